@@ -0,0 +1,23 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+
+// embed_migrations! reads the directory at compile time, so new migrations must trigger a rebuild
+fn main() {
+    println!("cargo:rerun-if-changed=migrations");
+    // The versions diesel records for the embedded migrations, for checking the database without running them
+    let mut versions: Vec<String> = fs::read_dir("migrations")
+        .expect("Could not read the migrations directory")
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().join("up.sql").is_file())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter_map(|name| {
+            name.split('_')
+                .next()
+                .map(|version| version.replace('-', ""))
+        })
+        .collect();
+    versions.sort();
+    let out_file = Path::new(&env::var("OUT_DIR").unwrap()).join("migration_versions.rs");
+    fs::write(out_file, format!("&{:?}", versions)).expect("Could not write migration_versions.rs");
+}
@@ -0,0 +1 @@
+ALTER TABLE users DROP COLUMN recovery_psswd_file;
\ No newline at end of file
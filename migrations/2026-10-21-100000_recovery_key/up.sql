@@ -0,0 +1 @@
+ALTER TABLE users ADD COLUMN recovery_psswd_file TEXT;
\ No newline at end of file
@@ -0,0 +1,25 @@
+CREATE TABLE audit_events (
+  id SERIAL PRIMARY KEY,
+  user_id INTEGER,
+  event_type VARCHAR NOT NULL,
+  locker_id VARCHAR,
+  ip VARCHAR,
+  user_agent VARCHAR,
+  outcome VARCHAR NOT NULL,
+  occurred_at BIGINT NOT NULL,
+  prev_hash VARCHAR NOT NULL,
+  hash VARCHAR NOT NULL,
+  inserted_at TIMESTAMP NOT NULL DEFAULT current_timestamp
+);
+
+CREATE INDEX audit_events_user_id ON audit_events (user_id);
+
+-- Append-only: existing entries can never be changed or removed
+CREATE OR REPLACE FUNCTION audit_events_append_only() RETURNS trigger AS $$
+BEGIN
+    RAISE EXCEPTION 'audit_events is append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER audit_events_append_only BEFORE UPDATE OR DELETE ON audit_events
+    FOR EACH ROW EXECUTE PROCEDURE audit_events_append_only();
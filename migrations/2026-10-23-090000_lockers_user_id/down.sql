@@ -0,0 +1,7 @@
+ALTER TABLE lockers ADD COLUMN email VARCHAR;
+
+UPDATE lockers SET email = users.email FROM users WHERE users.id = lockers.user_id;
+
+ALTER TABLE lockers ALTER COLUMN email SET NOT NULL;
+DROP INDEX lockers_user_id_locker_id;
+ALTER TABLE lockers DROP COLUMN user_id;
@@ -0,0 +1,26 @@
+ALTER TABLE lockers ADD COLUMN user_id INTEGER REFERENCES users (id) ON DELETE CASCADE;
+
+-- Older clients sent the email as typed, so case and surrounding spaces may differ from the user's
+DO $$
+DECLARE
+  ambiguous BIGINT;
+  unmatched BIGINT;
+BEGIN
+  SELECT count(*) INTO ambiguous FROM lockers
+    WHERE (SELECT count(*) FROM users WHERE lower(trim(users.email)) = lower(trim(lockers.email))) > 1;
+  IF ambiguous > 0 THEN
+    RAISE EXCEPTION '% locker(s) match several users by email, assign them by hand before migrating', ambiguous;
+  END IF;
+
+  UPDATE lockers SET user_id = users.id FROM users WHERE lower(trim(users.email)) = lower(trim(lockers.email));
+
+  SELECT count(*) INTO unmatched FROM lockers WHERE user_id IS NULL;
+  IF unmatched > 0 THEN
+    RAISE EXCEPTION '% locker(s) match no user by email, find them with SELECT id, email FROM lockers WHERE lower(trim(email)) NOT IN (SELECT lower(trim(email)) FROM users) and reassign or remove them before migrating', unmatched;
+  END IF;
+END $$;
+
+ALTER TABLE lockers ALTER COLUMN user_id SET NOT NULL;
+ALTER TABLE lockers DROP COLUMN email;
+
+CREATE INDEX lockers_user_id_locker_id ON lockers (user_id, locker_id);
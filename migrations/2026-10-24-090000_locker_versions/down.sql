@@ -0,0 +1,4 @@
+DROP INDEX lockers_user_id_locker_id;
+CREATE INDEX lockers_user_id_locker_id ON lockers (user_id, locker_id);
+
+ALTER TABLE lockers DROP COLUMN version;
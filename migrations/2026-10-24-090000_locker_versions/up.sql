@@ -0,0 +1,18 @@
+ALTER TABLE lockers ADD COLUMN version INTEGER NOT NULL DEFAULT 1;
+
+-- Lookups by id and user took whichever duplicate Postgres returned first, so none of them is known to be the stale
+-- one. Renaming is no way out either, the locker_id is the OPAQUE credential identifier the locker was registered with
+DO $$
+DECLARE
+  duplicates BIGINT;
+BEGIN
+  SELECT count(*) INTO duplicates FROM (
+    SELECT 1 FROM lockers GROUP BY user_id, locker_id HAVING count(*) > 1
+  ) AS duplicated;
+  IF duplicates > 0 THEN
+    RAISE EXCEPTION '% locker id(s) are registered more than once for the same user, find them with SELECT user_id, locker_id, array_agg(id) FROM lockers GROUP BY user_id, locker_id HAVING count(*) > 1 and keep one row of each before migrating', duplicates;
+  END IF;
+END $$;
+
+DROP INDEX lockers_user_id_locker_id;
+CREATE UNIQUE INDEX lockers_user_id_locker_id ON lockers (user_id, locker_id);
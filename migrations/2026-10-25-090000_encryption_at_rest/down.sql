@@ -0,0 +1,12 @@
+-- Encrypted rows can't be turned back into plaintext here, refuse instead of leaving them unreadable
+DO $$
+BEGIN
+    IF EXISTS (SELECT 1 FROM users WHERE data_key_id IS NOT NULL)
+        OR EXISTS (SELECT 1 FROM lockers WHERE data_key_id IS NOT NULL) THEN
+        RAISE EXCEPTION 'users or lockers still hold encrypted rows';
+    END IF;
+END $$;
+
+ALTER TABLE lockers DROP COLUMN data_key_id;
+ALTER TABLE users DROP COLUMN data_key_id;
+DROP TABLE data_keys;
@@ -0,0 +1,11 @@
+-- Data encryption keys, each wrapped by the master key named in master_key_id
+CREATE TABLE data_keys (
+  id SERIAL PRIMARY KEY,
+  master_key_id VARCHAR NOT NULL,
+  wrapped_key TEXT NOT NULL,
+  inserted_at TIMESTAMP NOT NULL DEFAULT current_timestamp
+);
+
+-- The data key a row's password files (and locker contents) are encrypted with, NULL until the app re-encrypts it
+ALTER TABLE users ADD COLUMN data_key_id INTEGER REFERENCES data_keys (id);
+ALTER TABLE lockers ADD COLUMN data_key_id INTEGER REFERENCES data_keys (id);
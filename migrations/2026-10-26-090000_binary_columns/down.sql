@@ -0,0 +1,6 @@
+-- encode() wraps base64 lines at 76 characters, the app expects a single line
+ALTER TABLE data_keys ALTER COLUMN wrapped_key TYPE TEXT USING translate(encode(wrapped_key, 'base64'), E'\n', '');
+ALTER TABLE lockers ALTER COLUMN ciphertext TYPE TEXT USING translate(encode(ciphertext, 'base64'), E'\n', '');
+ALTER TABLE lockers ALTER COLUMN psswd_file TYPE TEXT USING translate(encode(psswd_file, 'base64'), E'\n', '');
+ALTER TABLE users ALTER COLUMN recovery_psswd_file TYPE TEXT USING translate(encode(recovery_psswd_file, 'base64'), E'\n', '');
+ALTER TABLE users ALTER COLUMN psswd_file TYPE TEXT USING translate(encode(psswd_file, 'base64'), E'\n', '');
@@ -0,0 +1,6 @@
+-- Password files, locker contents and wrapped data keys are bytes, stored base64 encoded until now
+ALTER TABLE users ALTER COLUMN psswd_file TYPE BYTEA USING decode(psswd_file, 'base64');
+ALTER TABLE users ALTER COLUMN recovery_psswd_file TYPE BYTEA USING decode(recovery_psswd_file, 'base64');
+ALTER TABLE lockers ALTER COLUMN psswd_file TYPE BYTEA USING decode(psswd_file, 'base64');
+ALTER TABLE lockers ALTER COLUMN ciphertext TYPE BYTEA USING decode(ciphertext, 'base64');
+ALTER TABLE data_keys ALTER COLUMN wrapped_key TYPE BYTEA USING decode(wrapped_key, 'base64');
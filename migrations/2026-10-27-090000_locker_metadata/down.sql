@@ -0,0 +1,5 @@
+ALTER TABLE lockers DROP COLUMN icon_hash;
+ALTER TABLE lockers DROP COLUMN custom_fields;
+ALTER TABLE lockers DROP COLUMN tags;
+ALTER TABLE lockers DROP COLUMN label;
+ALTER TABLE lockers DROP COLUMN kind;
@@ -0,0 +1,11 @@
+-- What a locker holds. Existing lockers could be anything
+ALTER TABLE lockers ADD COLUMN kind VARCHAR NOT NULL DEFAULT 'other'
+  CHECK (kind IN ('password', 'ssh_key', 'note', 'api_key', 'card', 'other'));
+
+-- Encrypted by the client, the server only stores them
+ALTER TABLE lockers ADD COLUMN label BYTEA;
+ALTER TABLE lockers ADD COLUMN tags BYTEA;
+ALTER TABLE lockers ADD COLUMN custom_fields BYTEA;
+
+-- Hex SHA-256 of the icon URL, so clients can cache icons without the server learning the site
+ALTER TABLE lockers ADD COLUMN icon_hash VARCHAR(64);
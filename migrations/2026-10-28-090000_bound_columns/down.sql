@@ -0,0 +1,2 @@
+ALTER TABLE lockers DROP COLUMN columns_bound;
+ALTER TABLE users DROP COLUMN columns_bound;
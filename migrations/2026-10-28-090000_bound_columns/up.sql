@@ -0,0 +1,4 @@
+-- Whether the row's encrypted columns are bound to it, i.e. sealed with its table, id and user id as associated data.
+-- Existing rows are re-encrypted by the app
+ALTER TABLE users ADD COLUMN columns_bound BOOLEAN NOT NULL DEFAULT false;
+ALTER TABLE lockers ADD COLUMN columns_bound BOOLEAN NOT NULL DEFAULT false;
@@ -2,8 +2,5 @@
 
 set -e
 
-echo "Running migrations..."
-diesel migration run
-
 echo "Starting keypost-app..."
 ./keypost-app
\ No newline at end of file
@@ -0,0 +1,128 @@
+use diesel::result::{DatabaseErrorKind, Error};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use tracing::{error, info};
+
+use crate::api::ApiError;
+use crate::cache;
+use crate::config;
+use crate::crypto;
+use crate::logging;
+use crate::mail;
+use crate::persistence;
+use crate::recovery;
+use crate::user;
+
+/// Starts moving the account to `new_email`: mails a confirmation code to the new address and starts the OPAQUE
+/// registration of the password (and optionally a new recovery code) under the new credential identifier.
+/// Returns both registration responses.
+pub fn start_email_change(
+    email: &str,
+    session_id: &[u8],
+    new_email: &str,
+    registration_request_base64: &str,
+    recovery_registration_request_base64: Option<&str>,
+) -> Result<(String, Option<String>), ApiError> {
+    if !mail::is_enabled() {
+        return Err(ApiError::EmailChangeError(
+            "Email changes are not available on this server".to_string(),
+        ));
+    }
+    if !mail::is_valid_address(new_email) || new_email == email {
+        return Err(ApiError::EmailChangeError("Invalid new email".to_string()));
+    }
+    let registration =
+        crypto::server_side_registration_start(registration_request_base64, new_email)?;
+    let recovery_registration = match recovery_registration_request_base64 {
+        Some(r) => Some(crypto::server_side_registration_start(
+            r,
+            &recovery::recovery_identifier(new_email),
+        )?),
+        None => None,
+    };
+
+    let code = crypto::create_recovery_code();
+    cache::insert_email_change(
+        session_id.to_vec(),
+        new_email,
+        Sha256::digest(code.as_bytes()).to_vec(),
+    );
+    mail::send(
+        new_email,
+        "Confirm your new Keypost email",
+        &format!(
+            "Enter this code to finish changing your Keypost email to this address: {}\r\n\r\n\
+             It expires in {}. If you didn't ask for this, ignore this message.",
+            code,
+            expires_in(config::get().sessions.email_change_ttl_secs)
+        ),
+    )
+    .map_err(|err| {
+        error!(error = %err, "Could not send email change confirmation");
+        ApiError::ServerError
+    })?;
+    Ok((
+        base64::encode(registration.message.serialize()),
+        recovery_registration.map(|r| base64::encode(r.message.serialize())),
+    ))
+}
+
+/// Checks the mailed code and stores the new email with the records registered for it. Without a new recovery record
+/// the old one is dropped, since it is bound to the old email. Returns the new email.
+pub fn finish_email_change(
+    email: &str,
+    session_id: &[u8],
+    code: &str,
+    registration_upload_base64: &str,
+    recovery_upload_base64: Option<&str>,
+) -> Result<String, ApiError> {
+    // Malformed uploads are rejected before the pending change is used up, so the client can retry them
+    let password_file = crypto::server_side_registration_finish(registration_upload_base64)?;
+    let recovery_password_file = recovery_upload_base64
+        .map(crypto::server_side_registration_finish)
+        .transpose()?;
+    let change = cache::take_email_change(session_id).ok_or(ApiError::BadRequest)?;
+    let matches: bool = Sha256::digest(code.as_bytes())
+        .as_slice()
+        .ct_eq(&change.code_hash)
+        .into();
+    if !matches {
+        return Err(ApiError::EmailChangeError(
+            "Invalid confirmation code".to_string(),
+        ));
+    }
+    let user = user::get_user(email).map_err(ApiError::EmailChangeError)?;
+    match persistence::change_email(
+        user.id,
+        &change.new_email,
+        &password_file,
+        recovery_password_file.as_deref(),
+    ) {
+        Ok(1) => {}
+        Ok(_) => return Err(ApiError::EmailChangeError("User not found!".to_string())),
+        Err(Error::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
+            return Err(ApiError::EmailChangeError(
+                "That email is not available".to_string(),
+            ));
+        }
+        Err(err) => {
+            error!(error = ?err, "Error changing email");
+            return Err(ApiError::ServerError);
+        }
+    }
+    cache::rename_user_sessions(email, &change.new_email);
+    info!(
+        user_id = user.id,
+        new_email = %logging::email(&change.new_email),
+        "Email changed"
+    );
+    Ok(change.new_email)
+}
+
+fn expires_in(secs: u64) -> String {
+    match (secs % 60, secs / 60) {
+        (0, 1) => "1 minute".to_string(),
+        (0, minutes) => format!("{} minutes", minutes),
+        _ => format!("{} seconds", secs),
+    }
+}
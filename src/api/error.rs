@@ -6,6 +6,9 @@ use rocket::request::Request;
 use rocket::response::{self, Responder, Response};
 use std::io::Cursor;
 use thiserror::Error;
+use tracing::error;
+
+use crate::persistence;
 
 // These errors are expected to use throughout the entire app, not just for api so that no lib specific errors are leaked out.
 #[derive(Error, Debug)]
@@ -19,6 +22,27 @@ pub enum ApiError {
     #[error("User not Authenticated.")]
     NotAuthenticated,
 
+    #[error("A second factor is required.")]
+    SecondFactorRequired,
+
+    #[error("Invalid second factor.")]
+    InvalidSecondFactor,
+
+    #[error("A fresh password proof is required.")]
+    ReauthenticationRequired,
+
+    #[error("Two-factor error: `{0}`")]
+    TwoFactorError(String),
+
+    #[error("WebAuthn error: `{0}`")]
+    WebAuthnError(String),
+
+    #[error("Error during recovery: `{0}`")]
+    RecoveryError(String),
+
+    #[error("Error changing email: `{0}`")]
+    EmailChangeError(String),
+
     #[error("Confirmation key `{0}` is invalid.")]
     InvalidConfirmationKey(String),
 
@@ -38,15 +62,30 @@ pub enum ApiError {
     #[error("Bad API request, protocol error.")]
     BadRequestProtocol,
 
+    #[error("Bad nonce or code verifier.")]
+    BadNonceOrCodeVerifier,
+
     #[error("Bad confirmation key or wrong email given.")]
     BadConfirmationKeyOrWrongEmail,
 
+    #[error("Could not find session `{0}`")]
+    SessionNotFound(String),
+
     #[error("Could not find key `{0}`")]
     LockerNotFound(String),
 
+    #[error("Locker `{0}` was changed by another request.")]
+    LockerConflict(String),
+
     #[error("Unknown locker error: `{0}`")]
     UnknownLockerError(String),
 
+    #[error("Too many requests.")]
+    TooManyRequests,
+
+    #[error("A stored record is corrupt.")]
+    CorruptRecord,
+
     #[error("Server error.")]
     // #[response(status = 500)]
     ServerError,
@@ -56,6 +95,18 @@ pub enum ApiError {
     UnknownError,
 }
 
+// Lets persistence::transaction steps use `?`. The details are logged, never sent to the client.
+impl From<diesel::result::Error> for ApiError {
+    fn from(err: diesel::result::Error) -> Self {
+        if let Some(record) = persistence::CorruptRecord::find(&err) {
+            error!(table = record.table, id = record.id, reason = %record.reason, "Corrupt record");
+            return ApiError::CorruptRecord;
+        }
+        error!(error = ?err, "Database error");
+        ApiError::ServerError
+    }
+}
+
 impl<'r> Responder<'r> for ApiError {
     fn respond_to(self, _: &Request) -> response::Result<'r> {
         Response::build()
@@ -71,14 +122,26 @@ fn get_status(err: ApiError) -> Status {
         ApiError::LoginError(_) => Status::BadRequest,
         ApiError::LogoutError(_) => Status::Unauthorized,
         ApiError::NotAuthenticated => Status::Unauthorized,
+        ApiError::SecondFactorRequired => Status::Unauthorized,
+        ApiError::InvalidSecondFactor => Status::Unauthorized,
+        ApiError::ReauthenticationRequired => Status::Forbidden,
+        ApiError::TwoFactorError(_) => Status::BadRequest,
+        ApiError::WebAuthnError(_) => Status::BadRequest,
+        ApiError::RecoveryError(_) => Status::BadRequest,
+        ApiError::EmailChangeError(_) => Status::BadRequest,
         ApiError::InvalidConfirmationKey(_) => Status::BadRequest,
         ApiError::InvalidRequest { .. } => Status::BadRequest,
         ApiError::BadRequest => Status::BadRequest,
         ApiError::BadRequestDecode(_) => Status::BadRequest,
         ApiError::BadRequestProtocol => Status::BadRequest,
+        ApiError::BadNonceOrCodeVerifier => Status::BadRequest,
         ApiError::BadConfirmationKeyOrWrongEmail => Status::BadRequest,
+        ApiError::SessionNotFound(_) => Status::NotFound,
         ApiError::LockerNotFound(_) => Status::NotFound,
+        ApiError::LockerConflict(_) => Status::Conflict,
         ApiError::UnknownLockerError(_) => Status::InternalServerError,
+        ApiError::TooManyRequests => Status::TooManyRequests,
+        ApiError::CorruptRecord => Status::InternalServerError,
         ApiError::ServerError => Status::InternalServerError,
         ApiError::UnknownError => Status::InternalServerError,
     }
@@ -0,0 +1,338 @@
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{Header, Method, Status};
+use rocket::{Data, Request, Response};
+use std::time::Instant;
+use tracing::{debug, info};
+
+use crate::config::{CorsConfig, HeadersConfig};
+use crate::logging;
+use crate::metrics;
+
+const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+struct RequestId {
+    id: String,
+    started: Instant,
+}
+
+/// Wraps every request in a `request` span carrying its id, and echoes the id back to the client.
+pub struct RequestLogger;
+
+impl Fairing for RequestLogger {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request logger",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    fn on_request(&self, request: &mut Request, _: &Data) {
+        let request_id = request.local_cache(|| RequestId {
+            id: incoming_request_id(request).unwrap_or_else(new_request_id),
+            started: Instant::now(),
+        });
+        // Only the path, query strings are never logged
+        logging::enter_request_span(
+            &request_id.id,
+            request.method().as_str(),
+            request.uri().path(),
+        );
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        let request_id = request.local_cache(|| RequestId {
+            id: new_request_id(),
+            started: Instant::now(),
+        });
+        info!(
+            status = response.status().code,
+            elapsed_ms = request_id.started.elapsed().as_millis() as u64,
+            "Request completed"
+        );
+        response.set_header(Header::new(REQUEST_ID_HEADER, request_id.id.clone()));
+        logging::exit_request_span();
+    }
+}
+
+struct RequestStart(Instant);
+
+/// Counts every request and its latency under the name of the route that handled it.
+pub struct RequestMetrics;
+
+impl Fairing for RequestMetrics {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request metrics",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    fn on_request(&self, request: &mut Request, _: &Data) {
+        request.local_cache(|| RequestStart(Instant::now()));
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        let started = request.local_cache(|| RequestStart(Instant::now()));
+        let route = match request.route() {
+            Some(route) => route.name.unwrap_or("static"),
+            None => "unmatched",
+        };
+        metrics::observe_request(route, response.status().code, started.0.elapsed());
+    }
+}
+
+/// CORS for the web client when it is served from another origin. With no `cors.allowed_origins`
+/// configured nothing is allowed cross-origin, which is the default.
+pub struct Cors {
+    allowed_origins: Vec<String>,
+    allowed_methods: Vec<String>,
+    allowed_headers: Vec<String>,
+    max_age: String,
+}
+
+impl Cors {
+    pub fn from_config(cors: &CorsConfig) -> Cors {
+        Cors {
+            allowed_origins: cors.allowed_origins.clone(),
+            allowed_methods: cors.allowed_methods.clone(),
+            allowed_headers: cors.allowed_headers.clone(),
+            max_age: cors.max_age.to_string(),
+        }
+    }
+
+    fn allows_origin(&self, origin: &str) -> bool {
+        self.allowed_origins
+            .iter()
+            .any(|allowed| allowed == "*" || allowed == origin)
+    }
+
+    fn allows_preflight(&self, request: &Request) -> bool {
+        let method_allowed = request
+            .headers()
+            .get_one("Access-Control-Request-Method")
+            .map(|method| {
+                self.allowed_methods
+                    .iter()
+                    .any(|m| m.eq_ignore_ascii_case(method))
+            })
+            .unwrap_or(false);
+        let headers_allowed = request
+            .headers()
+            .get_one("Access-Control-Request-Headers")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|header| !header.is_empty())
+            .all(|header| {
+                self.allowed_headers
+                    .iter()
+                    .any(|h| h.eq_ignore_ascii_case(header))
+            });
+        method_allowed && headers_allowed
+    }
+}
+
+impl Fairing for Cors {
+    fn info(&self) -> Info {
+        Info {
+            name: "CORS",
+            kind: Kind::Response,
+        }
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        let origin = match request.headers().get_one("Origin") {
+            Some(origin) => origin,
+            None => return,
+        };
+        let is_preflight = request.method() == Method::Options
+            && request.headers().contains("Access-Control-Request-Method");
+        let allowed =
+            self.allows_origin(origin) && (!is_preflight || self.allows_preflight(request));
+
+        // The allowed origin varies with the request, so caches must key on it
+        response.set_raw_header("Vary", "Origin");
+        if is_preflight {
+            // There are no OPTIONS routes, so the preflight is answered here instead of by the 404 catcher
+            response.take_body();
+            response.remove_header("Content-Type");
+            response.set_status(match allowed {
+                true => Status::NoContent,
+                false => Status::Forbidden,
+            });
+        }
+        if !allowed {
+            debug!(origin, "Cross-origin request not allowed");
+            return;
+        }
+
+        response.set_header(Header::new(
+            "Access-Control-Allow-Origin",
+            origin.to_string(),
+        ));
+        response.set_header(Header::new(
+            "Access-Control-Expose-Headers",
+            REQUEST_ID_HEADER,
+        ));
+        if is_preflight {
+            response.set_header(Header::new(
+                "Access-Control-Allow-Methods",
+                self.allowed_methods.join(", "),
+            ));
+            response.set_header(Header::new(
+                "Access-Control-Allow-Headers",
+                self.allowed_headers.join(", "),
+            ));
+            response.set_header(Header::new("Access-Control-Max-Age", self.max_age.clone()));
+        }
+    }
+}
+
+/// Hardening headers on every response. API responses (JSON, never meant to be rendered or cached) get a
+/// deny-all CSP and `Cache-Control: no-store`, the static bundle gets a CSP that lets it run.
+pub struct SecurityHeaders {
+    api_csp: String,
+    static_csp: String,
+    hsts: String,
+    referrer_policy: String,
+    permissions_policy: String,
+}
+
+impl SecurityHeaders {
+    pub fn from_config(headers: &HeadersConfig) -> SecurityHeaders {
+        SecurityHeaders {
+            api_csp: headers.api_csp.clone(),
+            static_csp: headers.static_csp.clone(),
+            hsts: headers.hsts.clone(),
+            referrer_policy: headers.referrer_policy.clone(),
+            permissions_policy: headers.permissions_policy.clone(),
+        }
+    }
+}
+
+impl Fairing for SecurityHeaders {
+    fn info(&self) -> Info {
+        Info {
+            name: "Security headers",
+            kind: Kind::Response,
+        }
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        // StaticFiles is the only unnamed route
+        let is_static = request
+            .route()
+            .map(|route| route.name.is_none())
+            .unwrap_or(false);
+        let csp = match is_static {
+            true => &self.static_csp,
+            false => &self.api_csp,
+        };
+        response.set_header(Header::new("Content-Security-Policy", csp.clone()));
+        if !self.hsts.is_empty() {
+            response.set_header(Header::new("Strict-Transport-Security", self.hsts.clone()));
+        }
+        response.set_raw_header("X-Content-Type-Options", "nosniff");
+        response.set_header(Header::new("Referrer-Policy", self.referrer_policy.clone()));
+        response.set_header(Header::new(
+            "Permissions-Policy",
+            self.permissions_policy.clone(),
+        ));
+        if !is_static {
+            response.set_raw_header("Cache-Control", "no-store");
+        }
+    }
+}
+
+// Lets a fronting proxy correlate its own logs, as long as the id is short and harmless
+fn incoming_request_id(request: &Request) -> Option<String> {
+    request
+        .headers()
+        .get_one(REQUEST_ID_HEADER)
+        .filter(|id| {
+            !id.is_empty()
+                && id.len() <= 64
+                && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+        .map(String::from)
+}
+
+fn new_request_id() -> String {
+    format!("{:016x}", rand::random::<u64>())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::config::{Config, Environment};
+    use rocket::local::Client;
+
+    const ALLOWED_ORIGIN: &str = "http://localhost:8080";
+
+    fn client() -> Client {
+        let cors = Cors::from_config(&CorsConfig {
+            allowed_origins: vec![ALLOWED_ORIGIN.to_string()],
+            allowed_methods: vec!["GET".to_string(), "POST".to_string()],
+            allowed_headers: vec!["Authorization".to_string(), "Content-Type".to_string()],
+            max_age: 600,
+        });
+        let config = Config::build(Environment::Development).finalize().unwrap();
+        Client::new(rocket::custom(config).attach(cors)).unwrap()
+    }
+
+    fn preflight<'c>(
+        client: &'c Client,
+        origin: &str,
+        method: &str,
+        headers: &str,
+    ) -> rocket::local::LocalResponse<'c> {
+        client
+            .req(Method::Options, "/login/start")
+            .header(Header::new("Origin", origin.to_string()))
+            .header(Header::new(
+                "Access-Control-Request-Method",
+                method.to_string(),
+            ))
+            .header(Header::new(
+                "Access-Control-Request-Headers",
+                headers.to_string(),
+            ))
+            .dispatch()
+    }
+
+    #[test]
+    fn preflight_from_allowed_origin_succeeds() {
+        let client = client();
+        let response = preflight(&client, ALLOWED_ORIGIN, "POST", "content-type");
+        assert_eq!(response.status(), Status::NoContent);
+        let headers = response.headers();
+        assert_eq!(
+            headers.get_one("Access-Control-Allow-Origin"),
+            Some(ALLOWED_ORIGIN)
+        );
+        assert_eq!(
+            headers.get_one("Access-Control-Allow-Methods"),
+            Some("GET, POST")
+        );
+        assert_eq!(headers.get_one("Access-Control-Max-Age"), Some("600"));
+        assert_eq!(headers.get_one("Vary"), Some("Origin"));
+    }
+
+    #[test]
+    fn preflight_from_other_origin_is_forbidden() {
+        let client = client();
+        let response = preflight(&client, "https://evil.example", "POST", "content-type");
+        assert_eq!(response.status(), Status::Forbidden);
+        assert!(!response.headers().contains("Access-Control-Allow-Origin"));
+    }
+
+    #[test]
+    fn preflight_with_disallowed_method_or_header_is_forbidden() {
+        let client = client();
+        let response = preflight(&client, ALLOWED_ORIGIN, "DELETE", "");
+        assert_eq!(response.status(), Status::Forbidden);
+        let response = preflight(&client, ALLOWED_ORIGIN, "POST", "x-custom");
+        assert_eq!(response.status(), Status::Forbidden);
+        assert!(!response.headers().contains("Access-Control-Allow-Origin"));
+    }
+}
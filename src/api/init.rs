@@ -1,18 +1,49 @@
+use crate::api::fairings::{Cors, RequestLogger, RequestMetrics, SecurityHeaders};
 use crate::api::*;
-use crate::persistence;
+use crate::config::{self, Config, Profile};
+use crate::tls;
 
+use rocket::config::{ConfigError, Environment, LoggingLevel};
 use rocket_contrib::serve::StaticFiles;
 
-pub fn init() -> Result<(), std::io::Error> {
-    rocket().launch();
-    Ok(())
+pub fn init() -> Result<(), String> {
+    let config = config::get();
+    if config.server.tls.is_some() {
+        tls::init()?;
+    }
+    let rocket_config =
+        rocket_config(config).map_err(|err| format!("Invalid server configuration: {}", err))?;
+    // launch() only returns if the server could not start
+    let err = rocket(rocket_config, config).launch();
+    Err(format!("Could not launch server: {}", err))
 }
 
-/// https://github.com/SergioBenitez/Rocket/tree/v0.4.10/examples
-fn rocket() -> rocket::Rocket {
-    persistence::get_active_users().expect("Could not get users!");
+// Rocket.toml and ROCKET_* variables are not read, the server settings come from our own config
+fn rocket_config(config: &Config) -> Result<rocket::Config, ConfigError> {
+    let environment = match config.profile {
+        Profile::Dev => Environment::Development,
+        Profile::Staging => Environment::Staging,
+        Profile::Prod => Environment::Production,
+    };
+    // Rocket 0.4 can neither reload certificates nor check client certificates, so with TLS it sits behind tls.rs
+    let (address, port) = match config.server.tls {
+        Some(_) => ("127.0.0.1".to_string(), config.server.internal_port),
+        None => (config.server.address.clone(), config.server.port),
+    };
+    rocket::Config::build(environment)
+        .address(address)
+        .port(port)
+        .log_level(LoggingLevel::Normal)
+        .finalize()
+}
 
-    rocket::ignite()
+/// https://github.com/SergioBenitez/Rocket/tree/v0.4.10/examples
+fn rocket(rocket_config: rocket::Config, config: &Config) -> rocket::Rocket {
+    rocket::custom(rocket_config)
+        .attach(Cors::from_config(&config.cors))
+        .attach(SecurityHeaders::from_config(&config.headers))
+        .attach(RequestLogger)
+        .attach(RequestMetrics)
         .mount(
             "/",
             routes![
@@ -21,17 +52,39 @@ fn rocket() -> rocket::Rocket {
                 login_start,
                 login_finish,
                 login_verify,
+                login_webauthn_start,
+                recovery_start,
+                recovery_finish,
+                recovery_reset,
                 logout,
+                refresh_session,
+                list_sessions,
+                revoke_session,
+                revoke_other_sessions,
+                reauth_start,
+                reauth_finish,
+                enable_totp,
+                confirm_totp,
+                disable_totp,
+                webauthn_register_start,
+                webauthn_register_finish,
+                disable_webauthn,
+                register_recovery_start,
+                register_recovery_finish,
+                change_email_start,
+                change_email_finish,
+                audit_history,
+                scrape_metrics,
+                healthz,
+                readyz,
                 register_locker_start,
                 register_locker_finish,
                 open_locker_start,
                 open_locker_finish,
+                list_lockers,
+                update_locker_metadata,
                 delete_locker_start,
-                delete_locker_finish,
-                options_rs,
-                options_rf,
-                options_ls,
-                options_lf
+                delete_locker_finish
             ],
         )
         .mount("/", StaticFiles::from("static/dist").rank(-1))
@@ -1,4 +1,5 @@
 mod error;
+mod fairings;
 mod init;
 mod routes;
 mod structs;
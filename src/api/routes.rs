@@ -2,15 +2,28 @@ use diesel::result::DatabaseErrorKind;
 use rocket::http::Status;
 use rocket::outcome::Outcome::*;
 use rocket::request::{self, FromRequest, Request};
+use rocket::response::status;
 use rocket_contrib::json;
 use rocket_contrib::json::{Json, JsonValue};
 use sha2::{Digest, Sha256};
+use std::net::IpAddr;
+use subtle::ConstantTimeEq;
+use tracing::{debug, error, info, warn};
+use zeroize::Zeroizing;
 
+use crate::account;
 use crate::api::*;
+use crate::audit::{self, EventType};
 use crate::cache;
+use crate::config;
 use crate::crypto;
+use crate::health;
 use crate::locker;
+use crate::metrics;
 use crate::persistence;
+use crate::recovery;
+use crate::tls;
+use crate::two_factor;
 use crate::user;
 
 // https://github.com/SergioBenitez/Rocket/discussions/2041#discussioncomment-1885738
@@ -20,77 +33,193 @@ impl<'a> FromRequest<'a, '_> for Authenticated {
     fn from_request(request: &'a Request<'_>) -> request::Outcome<Self, Self::Error> {
         match request.headers().get("AUTHORIZATION").next() {
             Some(val) => match base64::decode(val) {
-                Ok(session_id) => match cache::get_bin(&session_id) {
-                    Some(session_key) => Success(Authenticated {
+                Ok(session_id) => match cache::touch_session(&session_id) {
+                    Some((session_key, email)) => Success(Authenticated {
+                        email,
                         session_id,
                         session_key,
                     }),
                     None => {
-                        println!("session_id not found in cache");
+                        debug!("session_id not found in cache or expired");
                         Failure((Status::Unauthorized, ApiError::NotAuthenticated))
                     }
                 },
                 Err(_) => {
-                    println!("Could not base64 decode");
+                    debug!("Could not base64 decode session_id");
                     Failure((Status::Unauthorized, ApiError::NotAuthenticated))
                 }
             },
             None => {
-                println!("AUTH header not found");
+                debug!("AUTH header not found");
                 Failure((Status::Unauthorized, ApiError::NotAuthenticated))
             }
         }
     }
 }
 
+// Rocket's client_ip() takes any X-Real-IP header at its word. Only the TLS terminator sets it reliably (it replaces
+// whatever the client sent), so it counts only with TLS configured and the request coming from the loopback interface.
+fn client_ip(request: &Request<'_>) -> Option<IpAddr> {
+    let peer = request.remote().map(|remote| remote.ip());
+    let from_terminator =
+        config::get().server.tls.is_some() && peer.map_or(false, |ip| ip.is_loopback());
+    match from_terminator {
+        true => request.real_ip().or(peer),
+        false => peer,
+    }
+}
+
+impl<'a> FromRequest<'a, '_> for ClientInfo {
+    type Error = ApiError;
+
+    fn from_request(request: &'a Request<'_>) -> request::Outcome<Self, Self::Error> {
+        Success(ClientInfo {
+            ip: client_ip(request),
+            user_agent: request.headers().get_one("User-Agent").map(String::from),
+        })
+    }
+}
+
+impl<'a> FromRequest<'a, '_> for AuthAttempt {
+    type Error = ApiError;
+
+    fn from_request(request: &'a Request<'_>) -> request::Outcome<Self, Self::Error> {
+        match client_ip(request) {
+            Some(ip) if !cache::allow_auth_attempt(ip) => {
+                warn!(%ip, "Auth rate limit exceeded");
+                Failure((Status::TooManyRequests, ApiError::TooManyRequests))
+            }
+            _ => Success(AuthAttempt),
+        }
+    }
+}
+
+impl<'a> FromRequest<'a, '_> for MetricsScraper {
+    type Error = ApiError;
+
+    fn from_request(request: &'a Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let expected = match &config::get().metrics.token {
+            Some(token) => token,
+            // Metrics are disabled unless a token is configured
+            None => return Failure((Status::NotFound, ApiError::NotAuthenticated)),
+        };
+        // With a client CA configured, the TLS terminator only sets this header for verified certificates
+        let requires_cert = config::get()
+            .server
+            .tls
+            .as_ref()
+            .map_or(false, |tls| tls.client_ca.is_some());
+        if requires_cert && request.headers().get_one(tls::CLIENT_CERT_HEADER).is_none() {
+            return Failure((Status::Forbidden, ApiError::NotAuthenticated));
+        }
+        let presented = request
+            .headers()
+            .get_one("AUTHORIZATION")
+            .and_then(|val| val.strip_prefix("Bearer "))
+            .unwrap_or_default();
+        // Compare digests so neither the content nor the length of the token leaks through timing
+        let matches: bool = Sha256::digest(presented.as_bytes())
+            .as_slice()
+            .ct_eq(Sha256::digest(expected.as_bytes()).as_slice())
+            .into();
+        match matches {
+            true => Success(MetricsScraper),
+            false => Failure((Status::Unauthorized, ApiError::NotAuthenticated)),
+        }
+    }
+}
+
 #[post("/register/start", format = "json", data = "<payload>")]
-pub fn register_start(payload: Json<RegisterStart>) -> Result<JsonValue, ApiError> {
+pub fn register_start(
+    payload: Json<RegisterStart>,
+    _attempt: AuthAttempt,
+) -> Result<JsonValue, ApiError> {
+    let method = match &payload.m {
+        Some(m) => m.parse::<crypto::ChallengeMethod>()?,
+        None => crypto::ChallengeMethod::default(),
+    };
     let server_registration_start = crypto::server_side_registration_start(&payload.i, &payload.e)?;
+    // Optional second OPAQUE record, registered with a high-entropy recovery code as its password
+    let recovery_response = match &payload.r {
+        Some(r) => {
+            let recovery_registration_start = crypto::server_side_registration_start(
+                r,
+                &recovery::recovery_identifier(&payload.e),
+            )?;
+            Some(base64::encode(
+                recovery_registration_start.message.serialize(),
+            ))
+        }
+        None => None,
+    };
     let nonce = crypto::create_nonce();
-    cache::insert_str(nonce, &payload.c);
+    cache::insert_str(nonce, &crypto::to_cache_entry(method, &payload.c));
     let response_bytes = server_registration_start.message.serialize();
     let response = base64::encode(response_bytes);
-    Ok(json!({ "id": &nonce, "o": &response }))
+    Ok(json!({ "id": &nonce, "o": &response, "r": recovery_response }))
 }
 
 #[post("/register/finish", format = "json", data = "<payload>")]
-pub fn register_finish(payload: Json<RegisterFinish>) -> Result<JsonValue, ApiError> {
-    match cache::get_str(&payload.id) {
-        Some(expected_challenge) => {
+pub fn register_finish(
+    payload: Json<RegisterFinish>,
+    client: ClientInfo,
+) -> Result<JsonValue, ApiError> {
+    // A challenge may only be attempted once
+    match cache::take_str(&payload.id) {
+        Some(entry) => {
+            let (method, expected_challenge) = crypto::from_cache_entry(&entry)?;
             let verifier = base64::decode(&payload.v).map_err(ApiError::BadRequestDecode)?;
-            let actual_challenge = pkce::code_challenge(&verifier);
-            if expected_challenge != actual_challenge {
-                return Ok(json!({ "id": &payload.id, "o": "bad_nonce_or_code_verifier" }));
-            }
+            crypto::verify_code_challenge(method, expected_challenge, &verifier)?;
         }
-        None => return Err(ApiError::BadRequest),
+        None => return Err(ApiError::BadNonceOrCodeVerifier),
     }
 
-    let password_file = crypto::server_side_registration_finish(&payload.i);
-    match persistence::add_user(&payload.e, base64::encode(password_file).as_str()) {
+    let password_file = crypto::server_side_registration_finish(&payload.i)?;
+    let recovery_password_file = payload
+        .r
+        .as_deref()
+        .map(crypto::server_side_registration_finish)
+        .transpose()?;
+    let result = match persistence::add_user(
+        &payload.e,
+        &password_file,
+        recovery_password_file.as_deref(),
+    ) {
         Ok(_user) => Ok(json!({ "id": &payload.id, "o": "ok" })),
         Err(diesel::result::Error::DatabaseError(DatabaseErrorKind::UniqueViolation, info)) => {
-            println!("UniqueViolation: {:?}", info);
+            info!(constraint = ?info.constraint_name(), "UniqueViolation");
             Err(ApiError::BadConfirmationKeyOrWrongEmail)
         }
         Err(err) => {
-            println!("Could not create new user! {}, {:?}", &payload.id, err);
+            error!(id = payload.id, error = ?err, "Could not create new user");
             Err(ApiError::UnknownError)
         }
-    }
+    };
+    audit::record(
+        EventType::Register,
+        &payload.e,
+        None,
+        &client,
+        result.is_ok(),
+    );
+    result
 }
 
 #[post("/login/start", format = "json", data = "<payload>")]
-pub fn login_start(payload: Json<LoginStart>) -> Result<JsonValue, ApiError> {
+pub fn login_start(
+    payload: Json<LoginStart>,
+    _attempt: AuthAttempt,
+) -> Result<JsonValue, ApiError> {
     let nonce = crypto::create_nonce(); // This is the payload.id to be used throughout entire /login flow and tied to the session_key
     match user::get_user(&payload.e) {
         Ok(user) => {
-            let password_file_bytes =
-                base64::decode(user.psswd_file).expect("Could not base64 decode in login_start!");
+            let password_file_bytes = Zeroizing::new(user.psswd_file);
             let server_login_start_result =
-                crypto::login_start(&payload.e, &password_file_bytes, &payload.i);
-            let server_login_bytes = server_login_start_result.state.serialize().to_vec();
+                crypto::login_start(&payload.e, &password_file_bytes, &payload.i)?;
+            let server_login_bytes =
+                Zeroizing::new(server_login_start_result.state.serialize().to_vec());
             cache::insert(nonce, server_login_bytes);
+            cache::insert_login_email(nonce.to_be_bytes().to_vec(), &payload.e);
             let response_bytes = server_login_start_result.message.serialize();
             let response = base64::encode(response_bytes);
             Ok(json!({ "id": &nonce, "o": &response }))
@@ -100,58 +229,422 @@ pub fn login_start(payload: Json<LoginStart>) -> Result<JsonValue, ApiError> {
 }
 
 #[post("/login/finish", format = "json", data = "<payload>")]
-pub fn login_finish(payload: Json<LoginFinish>) -> Result<JsonValue, ApiError> {
-    let server_login_bytes = cache::get(&payload.id).unwrap();
+pub fn login_finish(payload: Json<LoginFinish>, client: ClientInfo) -> Result<JsonValue, ApiError> {
+    // ServerLogin state is single use
+    let server_login_bytes = cache::take(&payload.id).ok_or(ApiError::BadRequest)?;
+    let email = cache::take_login_email(&payload.id.to_be_bytes()).ok_or(ApiError::BadRequest)?;
     match crypto::login_finish(&server_login_bytes, &payload.i) {
         Ok(session_key) => {
             let rand_bytes = crypto::rand_bytes();
             let ciphertext =
-                crypto::encrypt_bytes_with_u32_nonce(&payload.id, &session_key, &rand_bytes);
+                crypto::encrypt_once(&session_key, crypto::KeyPurpose::LoginVerify, &rand_bytes);
             let client_hash = Sha256::digest(&ciphertext).to_vec();
-            //TODO Need to expire this client_hash/session_key incase /login/verify never completes (i.e. failed login attempts will pile up!).
+            cache::insert_login_email(client_hash.clone(), &email);
             cache::insert_bin(client_hash, session_key);
             Ok(json!({ "id": &payload.id, "o": base64::encode(rand_bytes) }))
         }
         Err(err) => {
-            println!("Error during login: {:?}", err);
-            Err(ApiError::BadRequestProtocol)
+            error!(error = ?err, "Error during login");
+            audit::record(EventType::Login, &email, None, &client, false);
+            Err(err)
         }
     }
 }
 
 #[post("/login/verify", format = "json", data = "<payload>")]
-pub fn login_verify(payload: Json<LoginVerify>) -> Result<JsonValue, ApiError> {
+pub fn login_verify(payload: Json<LoginVerify>, client: ClientInfo) -> Result<JsonValue, ApiError> {
     let client_hash = base64::decode(&payload.i).map_err(ApiError::BadRequestDecode)?;
-    match cache::get_bin(&client_hash) {
-        Some(session_key) => {
-            let session_key_id = crypto::encrypt_bytes_with_u32_nonce(
-                &payload.id,
-                &session_key,
-                &[payload.id.to_be_bytes()].concat(),
+    match (
+        cache::get_bin(&client_hash),
+        cache::take_login_email(&client_hash),
+    ) {
+        (Some(session_key), Some(email)) => {
+            let second_factor = two_factor::verify_login(
+                &email,
+                &client_hash,
+                payload.c.as_deref(),
+                payload.w.as_ref(),
             );
-            //TODO Need to expire/delete this new session_key entry after x amount of minutes (i.e. user's session expired).
-            cache::insert_bin(session_key_id, session_key);
+            if let Err(err) = second_factor {
+                // The client may retry with a code, but a wrong code uses up this login so each guess costs a full OPAQUE login
+                match err {
+                    ApiError::SecondFactorRequired => {
+                        cache::insert_login_email(client_hash, &email)
+                    }
+                    _ => {
+                        cache::delete_bin(&client_hash);
+                        audit::record(EventType::Login, &email, None, &client, false);
+                    }
+                }
+                return Err(err);
+            }
             cache::delete_bin(&client_hash); // i.e. login verification complete!
+            let session_key_id = crypto::encrypt_once(
+                &session_key,
+                crypto::KeyPurpose::SessionId,
+                &payload.id.to_be_bytes(),
+            );
+            audit::record(EventType::Login, &email, None, &client, true);
+            cache::insert_session(
+                session_key_id,
+                cache::Session::new(email, session_key, client.ip, client.user_agent),
+            );
             Ok(json!({ "id": 0, "o": "Success" }))
         }
         _ => {
-            println!("login verification failed: {}", &payload.id);
+            warn!(id = payload.id, "Login verification failed");
             Err(ApiError::LoginError("Failed".to_string()))
         }
     }
 }
 
+// Only needed when using a WebAuthn second factor: issues the assertion challenge for a login pending /login/verify.
+#[post("/login/webauthn/start", format = "json", data = "<payload>")]
+pub fn login_webauthn_start(payload: Json<WebAuthnLoginStart>) -> Result<JsonValue, ApiError> {
+    let client_hash = base64::decode(&payload.i).map_err(ApiError::BadRequestDecode)?;
+    match (
+        cache::get_bin(&client_hash),
+        cache::get_login_email(&client_hash),
+    ) {
+        (Some(_session_key), Some(email)) => {
+            let options = two_factor::start_webauthn_login(&email, &client_hash)?;
+            Ok(json!({ "id": 0, "o": options }))
+        }
+        _ => Err(ApiError::LoginError("Failed".to_string())),
+    }
+}
+
+#[post("/recovery/start", format = "json", data = "<payload>")]
+pub fn recovery_start(
+    payload: Json<RecoveryStart>,
+    _attempt: AuthAttempt,
+) -> Result<JsonValue, ApiError> {
+    let (nonce, response) = recovery::start(&payload.e, &payload.i)?;
+    Ok(json!({ "id": &nonce, "o": &response }))
+}
+
+#[post("/recovery/finish", format = "json", data = "<payload>")]
+pub fn recovery_finish(payload: Json<RecoveryFinish>) -> Result<JsonValue, ApiError> {
+    let (reset_token, password_response, recovery_response) =
+        recovery::finish(payload.id, &payload.i, &payload.n, &payload.r)?;
+    Ok(json!({
+        "id": &payload.id,
+        "t": base64::encode(reset_token),
+        "o": &password_response,
+        "r": &recovery_response
+    }))
+}
+
+#[post("/recovery/reset", format = "json", data = "<payload>")]
+pub fn recovery_reset(
+    payload: Json<RecoveryReset>,
+    client: ClientInfo,
+) -> Result<JsonValue, ApiError> {
+    let reset_token = base64::decode(&payload.t).map_err(ApiError::BadRequestDecode)?;
+    let email = recovery::reset(&reset_token, &payload.n, &payload.r)?;
+    audit::record(EventType::Recovery, &email, None, &client, true);
+    Ok(json!({ "id": 0, "o": "Success" }))
+}
+
 #[post("/logout", format = "json")]
-pub fn logout(auth: Authenticated) -> Result<JsonValue, ApiError> {
-    match cache::delete_bin(&auth.session_id) {
+pub fn logout(auth: Authenticated, client: ClientInfo) -> Result<JsonValue, ApiError> {
+    let logged_out = cache::delete_session(&auth.session_id);
+    audit::record(EventType::Logout, &auth.email, None, &client, logged_out);
+    match logged_out {
         true => Ok(json!({ "id": 0, "o": "Success", "n": 0 })),
         false => {
-            println!("Logout failed!");
+            warn!("Logout failed");
             Err(ApiError::LogoutError("Session not found!".to_string()))
         }
     }
 }
 
+// Issues a new session id (which the client uses from now on) and invalidates the old one.
+#[post("/session/refresh", format = "json")]
+pub fn refresh_session(auth: Authenticated, client: ClientInfo) -> Result<JsonValue, ApiError> {
+    let new_session_id = crypto::create_session_id();
+    let refreshed = cache::refresh_session(
+        &auth.session_id,
+        new_session_id.clone(),
+        client.ip,
+        client.user_agent,
+    );
+    match refreshed {
+        true => Ok(json!({ "id": 0, "o": base64::encode(new_session_id) })),
+        false => {
+            warn!("Session refresh failed");
+            Err(ApiError::NotAuthenticated)
+        }
+    }
+}
+
+#[get("/sessions")]
+pub fn list_sessions(auth: Authenticated) -> Result<JsonValue, ApiError> {
+    let sessions = cache::list_sessions(&auth.email, &auth.session_id);
+    Ok(json!({ "id": 0, "o": sessions }))
+}
+
+#[post("/sessions/revoke", format = "json", data = "<payload>")]
+pub fn revoke_session(
+    payload: Json<RevokeSession>,
+    auth: Authenticated,
+    client: ClientInfo,
+) -> Result<JsonValue, ApiError> {
+    let revoked = cache::revoke_session(&auth.email, &payload.id);
+    audit::record(
+        EventType::SessionRevoke,
+        &auth.email,
+        None,
+        &client,
+        revoked,
+    );
+    match revoked {
+        true => Ok(json!({ "id": 0, "o": "Success" })),
+        false => Err(ApiError::SessionNotFound(payload.id.clone())),
+    }
+}
+
+#[post("/sessions/revoke_others", format = "json")]
+pub fn revoke_other_sessions(
+    auth: Authenticated,
+    client: ClientInfo,
+) -> Result<JsonValue, ApiError> {
+    let revoked = cache::revoke_other_sessions(&auth.email, &auth.session_id);
+    audit::record(EventType::SessionRevoke, &auth.email, None, &client, true);
+    Ok(json!({ "id": 0, "o": "Success", "n": revoked }))
+}
+
+// Re-runs the OPAQUE login for the already authenticated user, as a fresh password proof before sensitive account changes.
+#[post("/reauth/start", format = "json", data = "<payload>")]
+pub fn reauth_start(
+    payload: Json<ReauthStart>,
+    auth: Authenticated,
+) -> Result<JsonValue, ApiError> {
+    let nonce = crypto::create_nonce();
+    let user = user::get_user(&auth.email).map_err(ApiError::LoginError)?;
+    let password_file_bytes = Zeroizing::new(user.psswd_file);
+    let server_login_start_result =
+        crypto::login_start(&auth.email, &password_file_bytes, &payload.i)?;
+    cache::insert(
+        nonce,
+        Zeroizing::new(server_login_start_result.state.serialize().to_vec()),
+    );
+    cache::insert_login_email(nonce.to_be_bytes().to_vec(), &auth.email);
+    let response = base64::encode(server_login_start_result.message.serialize());
+    Ok(json!({ "id": &nonce, "o": &response }))
+}
+
+#[post("/reauth/finish", format = "json", data = "<payload>")]
+pub fn reauth_finish(
+    payload: Json<ReauthFinish>,
+    auth: Authenticated,
+) -> Result<JsonValue, ApiError> {
+    // ServerLogin state is single use
+    let server_login_bytes = cache::take(&payload.id).ok_or(ApiError::BadRequest)?;
+    // The login state must have been started for this session's user, not some other login
+    match cache::take_login_email(&payload.id.to_be_bytes()) {
+        Some(email) if email == auth.email => {}
+        _ => return Err(ApiError::BadRequest),
+    }
+    match crypto::login_finish(&server_login_bytes, &payload.i) {
+        Ok(_session_key) => {
+            cache::mark_reauthenticated(&auth.session_id);
+            Ok(json!({ "id": &payload.id, "o": "Success" }))
+        }
+        Err(err) => {
+            error!(error = ?err, "Error during reauth");
+            Err(err)
+        }
+    }
+}
+
+#[post("/2fa/totp/enable", format = "json")]
+pub fn enable_totp(auth: Authenticated) -> Result<JsonValue, ApiError> {
+    require_reauthenticated(&auth)?;
+    let enrollment = two_factor::enable_totp(&auth.email)?;
+    Ok(json!({
+        "id": 0,
+        "o": enrollment.provisioning_uri,
+        "r": enrollment.recovery_codes
+    }))
+}
+
+#[post("/2fa/totp/confirm", format = "json", data = "<payload>")]
+pub fn confirm_totp(
+    payload: Json<TotpCode>,
+    auth: Authenticated,
+    client: ClientInfo,
+) -> Result<JsonValue, ApiError> {
+    require_reauthenticated(&auth)?;
+    let result = two_factor::confirm_totp(&auth.email, &payload.c);
+    audit::record(
+        EventType::TwoFactorEnable,
+        &auth.email,
+        None,
+        &client,
+        result.is_ok(),
+    );
+    result?;
+    Ok(json!({ "id": 0, "o": "Success" }))
+}
+
+#[post("/2fa/totp/disable", format = "json")]
+pub fn disable_totp(auth: Authenticated, client: ClientInfo) -> Result<JsonValue, ApiError> {
+    require_reauthenticated(&auth)?;
+    let result = two_factor::disable_totp(&auth.email);
+    audit::record(
+        EventType::TwoFactorDisable,
+        &auth.email,
+        None,
+        &client,
+        result.is_ok(),
+    );
+    result?;
+    Ok(json!({ "id": 0, "o": "Success" }))
+}
+
+#[post("/2fa/webauthn/register/start", format = "json")]
+pub fn webauthn_register_start(auth: Authenticated) -> Result<JsonValue, ApiError> {
+    require_reauthenticated(&auth)?;
+    let nonce = crypto::create_nonce();
+    let options = two_factor::start_webauthn_registration(&auth.email, nonce)?;
+    Ok(json!({ "id": &nonce, "o": options }))
+}
+
+#[post("/2fa/webauthn/register/finish", format = "json", data = "<payload>")]
+pub fn webauthn_register_finish(
+    payload: Json<WebAuthnRegisterFinish>,
+    auth: Authenticated,
+    client: ClientInfo,
+) -> Result<JsonValue, ApiError> {
+    let result =
+        two_factor::finish_webauthn_registration(&auth.email, payload.id, &payload.a, &payload.d);
+    audit::record(
+        EventType::TwoFactorEnable,
+        &auth.email,
+        None,
+        &client,
+        result.is_ok(),
+    );
+    result?;
+    Ok(json!({ "id": &payload.id, "o": "Success" }))
+}
+
+#[post("/2fa/webauthn/disable", format = "json")]
+pub fn disable_webauthn(auth: Authenticated, client: ClientInfo) -> Result<JsonValue, ApiError> {
+    require_reauthenticated(&auth)?;
+    let result = two_factor::disable_webauthn(&auth.email);
+    audit::record(
+        EventType::TwoFactorDisable,
+        &auth.email,
+        None,
+        &client,
+        result.is_ok(),
+    );
+    result?;
+    Ok(json!({ "id": 0, "o": "Success" }))
+}
+
+#[get("/audit")]
+pub fn audit_history(auth: Authenticated) -> Result<JsonValue, ApiError> {
+    let entries = audit::history(&auth.email)?;
+    Ok(json!({ "id": 0, "o": entries }))
+}
+
+#[get("/metrics")]
+pub fn scrape_metrics(_scraper: MetricsScraper) -> Result<String, ApiError> {
+    metrics::render()
+}
+
+#[get("/healthz")]
+pub fn healthz() -> JsonValue {
+    json!({ "status": "ok" })
+}
+
+#[get("/readyz")]
+pub fn readyz() -> status::Custom<JsonValue> {
+    let readiness = health::readiness();
+    let code = match readiness.is_ready() {
+        true => Status::Ok,
+        false => Status::ServiceUnavailable,
+    };
+    status::Custom(code, json!(readiness))
+}
+
+#[post("/recovery/register/start", format = "json", data = "<payload>")]
+pub fn register_recovery_start(
+    payload: Json<RegisterRecovery>,
+    auth: Authenticated,
+) -> Result<JsonValue, ApiError> {
+    require_reauthenticated(&auth)?;
+    let server_registration_start = crypto::server_side_registration_start(
+        &payload.i,
+        &recovery::recovery_identifier(&auth.email),
+    )?;
+    let response = base64::encode(server_registration_start.message.serialize());
+    Ok(json!({ "id": 0, "o": &response }))
+}
+
+#[post("/recovery/register/finish", format = "json", data = "<payload>")]
+pub fn register_recovery_finish(
+    payload: Json<RegisterRecovery>,
+    auth: Authenticated,
+) -> Result<JsonValue, ApiError> {
+    require_reauthenticated(&auth)?;
+    recovery::register_finish(&auth.email, &payload.i)?;
+    Ok(json!({ "id": 0, "o": "Success" }))
+}
+
+// The new address must be confirmed with a mailed code, and the OPAQUE records are re-registered under it in the same step.
+#[post("/account/email/start", format = "json", data = "<payload>")]
+pub fn change_email_start(
+    payload: Json<ChangeEmailStart>,
+    auth: Authenticated,
+) -> Result<JsonValue, ApiError> {
+    require_reauthenticated(&auth)?;
+    let (response, recovery_response) = account::start_email_change(
+        &auth.email,
+        &auth.session_id,
+        &payload.e,
+        &payload.i,
+        payload.r.as_deref(),
+    )?;
+    Ok(json!({ "id": 0, "o": &response, "r": recovery_response }))
+}
+
+#[post("/account/email/finish", format = "json", data = "<payload>")]
+pub fn change_email_finish(
+    payload: Json<ChangeEmailFinish>,
+    auth: Authenticated,
+    client: ClientInfo,
+) -> Result<JsonValue, ApiError> {
+    require_reauthenticated(&auth)?;
+    let result = account::finish_email_change(
+        &auth.email,
+        &auth.session_id,
+        &payload.c,
+        &payload.i,
+        payload.r.as_deref(),
+    );
+    let recorded_email = result.as_deref().unwrap_or(&auth.email);
+    audit::record(
+        EventType::EmailChange,
+        recorded_email,
+        None,
+        &client,
+        result.is_ok(),
+    );
+    let new_email = result?;
+    Ok(json!({ "id": 0, "o": new_email }))
+}
+
+fn require_reauthenticated(auth: &Authenticated) -> Result<(), ApiError> {
+    match cache::is_reauthenticated(&auth.session_id) {
+        true => Ok(()),
+        false => Err(ApiError::ReauthenticationRequired),
+    }
+}
+
 #[post("/locker/register/start", format = "json", data = "<payload>")]
 pub fn register_locker_start(
     payload: Json<RegisterLockerStart>,
@@ -159,12 +652,11 @@ pub fn register_locker_start(
 ) -> Result<JsonValue, ApiError> {
     //TODO use _auth.session_key in order to decrypt payload and encrypt response
     let id = &payload.id;
-    let _email = &payload.e;
-    let input = base64::decode(&payload.i).expect("Could not base64 decode!");
+    let input = base64::decode(&payload.i).map_err(ApiError::BadRequestDecode)?;
     match locker::register_start(id, &input) {
         Ok(response) => Ok(json!({ "id": response.id, "o": response.output })),
         Err(err) => {
-            println!("Error in register_locker_start: {:?}", err);
+            error!(error = ?err, "Error in register_locker_start");
             Err(err)
         }
     }
@@ -173,17 +665,27 @@ pub fn register_locker_start(
 #[post("/locker/register/finish", format = "json", data = "<payload>")]
 pub fn register_locker_finish(
     payload: Json<RegisterLockerFinish>,
-    _auth: Authenticated,
+    auth: Authenticated,
+    client: ClientInfo,
 ) -> Result<JsonValue, ApiError> {
-    //TODO use _auth.session_key in order to decrypt payload and encrypt response
+    //TODO use auth.session_key in order to decrypt payload and encrypt response
     let id = &payload.id;
-    let email = &payload.e;
-    let input = base64::decode(&payload.i).expect("Could not base64 decode!");
-    let ciphertext = base64::decode(&payload.c).expect("Could not base64 decode!");
-    match locker::register_finish(id, email, &input, &ciphertext) {
-        Ok(response) => Ok(json!({ "id": response.id, "o": response.output })),
+    let input = base64::decode(&payload.i).map_err(ApiError::BadRequestDecode)?;
+    let ciphertext = base64::decode(&payload.c).map_err(ApiError::BadRequestDecode)?;
+    let result = locker::register_finish(id, &auth.email, &input, &ciphertext, payload.v);
+    audit::record(
+        EventType::LockerRegister,
+        &auth.email,
+        Some(id.as_str()),
+        &client,
+        result.is_ok(),
+    );
+    match result {
+        Ok(response) => {
+            Ok(json!({ "id": response.id, "o": response.output, "v": response.version }))
+        }
         Err(err) => {
-            println!("Error in register_locker_finish: {:?}", err);
+            error!(error = ?err, "Error in register_locker_finish");
             Err(err)
         }
     }
@@ -192,16 +694,17 @@ pub fn register_locker_finish(
 #[post("/locker/open/start", format = "json", data = "<payload>")]
 pub fn open_locker_start(
     payload: Json<OpenLockerStart>,
-    _auth: Authenticated,
+    auth: Authenticated,
 ) -> Result<JsonValue, ApiError> {
-    //TODO use _auth.session_key in order to decrypt payload and encrypt response
+    //TODO use auth.session_key in order to decrypt payload and encrypt response
     let locker_id = payload.id.as_str();
-    let email = payload.e.as_str();
-    let input = base64::decode(&payload.i).expect("Could not base64 decode!");
-    match locker::open_start(locker_id, email, &input) {
-        Ok(response) => Ok(json!({ "id": response.id, "o": response.output, "n": response.nonce })),
+    let input = base64::decode(&payload.i).map_err(ApiError::BadRequestDecode)?;
+    match locker::open_start(locker_id, &auth.email, &input) {
+        Ok(response) => Ok(
+            json!({ "id": response.id, "o": response.output, "n": response.nonce, "v": response.version }),
+        ),
         Err(err) => {
-            println!("Error in open_locker_start: {:?}", err);
+            error!(error = ?err, "Error in open_locker_start");
             Err(err)
         }
     }
@@ -210,17 +713,56 @@ pub fn open_locker_start(
 #[post("/locker/open/finish", format = "json", data = "<payload>")]
 pub fn open_locker_finish(
     payload: Json<OpenLockerFinish>,
-    _auth: Authenticated,
+    auth: Authenticated,
+    client: ClientInfo,
 ) -> Result<JsonValue, ApiError> {
-    //TODO use _auth.session_key in order to decrypt payload and encrypt response
+    //TODO use auth.session_key in order to decrypt payload and encrypt response
     let locker_id = &payload.id;
-    let email = &payload.e;
-    let input = base64::decode(&payload.i).expect("Could not base64 decode!");
+    let input = base64::decode(&payload.i).map_err(ApiError::BadRequestDecode)?;
     let nonce = payload.n;
-    match locker::open_finish(locker_id, email, &input, nonce) {
-        Ok(response) => Ok(json!({ "id": response.id, "o": response.output, "n": response.nonce })),
+    let result = locker::open_finish(locker_id, &auth.email, &input, nonce);
+    audit::record(
+        EventType::LockerOpen,
+        &auth.email,
+        Some(locker_id.as_str()),
+        &client,
+        result.is_ok(),
+    );
+    match result {
+        Ok(response) => Ok(
+            json!({ "id": response.id, "o": response.output, "n": response.nonce, "v": response.version }),
+        ),
         Err(err) => {
-            println!("Error in open_locker_finish: {:?}", err);
+            error!(error = ?err, "Error in open_locker_finish");
+            Err(err)
+        }
+    }
+}
+
+#[get("/lockers")]
+pub fn list_lockers(auth: Authenticated) -> Result<JsonValue, ApiError> {
+    let lockers = locker::list(&auth.email)?;
+    Ok(json!({ "id": 0, "o": lockers }))
+}
+
+#[post("/locker/metadata", format = "json", data = "<payload>")]
+pub fn update_locker_metadata(
+    payload: Json<UpdateLockerMetadata>,
+    auth: Authenticated,
+    client: ClientInfo,
+) -> Result<JsonValue, ApiError> {
+    let result = locker::update_metadata(&auth.email, &payload);
+    audit::record(
+        EventType::LockerMetadataUpdate,
+        &auth.email,
+        Some(payload.id.as_str()),
+        &client,
+        result.is_ok(),
+    );
+    match result {
+        Ok(()) => Ok(json!({ "id": 0, "o": "Success" })),
+        Err(err) => {
+            error!(error = ?err, "Error in update_locker_metadata");
             Err(err)
         }
     }
@@ -229,16 +771,17 @@ pub fn open_locker_finish(
 #[post("/locker/delete/start", format = "json", data = "<payload>")]
 pub fn delete_locker_start(
     payload: Json<DeleteLockerStart>,
-    _auth: Authenticated,
+    auth: Authenticated,
 ) -> Result<JsonValue, ApiError> {
-    //TODO use _auth.session_key in order to decrypt payload and encrypt response
+    //TODO use auth.session_key in order to decrypt payload and encrypt response
     let locker_id = payload.id.as_str();
-    let email = payload.e.as_str();
-    let input = base64::decode(&payload.i).expect("Could not base64 decode!");
-    match locker::delete_start(locker_id, email, &input) {
-        Ok(response) => Ok(json!({ "id": response.id, "o": response.output, "n": response.nonce })),
+    let input = base64::decode(&payload.i).map_err(ApiError::BadRequestDecode)?;
+    match locker::delete_start(locker_id, &auth.email, &input) {
+        Ok(response) => Ok(
+            json!({ "id": response.id, "o": response.output, "n": response.nonce, "v": response.version }),
+        ),
         Err(err) => {
-            println!("Error in delete_locker_start: {:?}", err);
+            error!(error = ?err, "Error in delete_locker_start");
             Err(err)
         }
     }
@@ -247,40 +790,28 @@ pub fn delete_locker_start(
 #[post("/locker/delete/finish", format = "json", data = "<payload>")]
 pub fn delete_locker_finish(
     payload: Json<DeleteLockerFinish>,
-    _auth: Authenticated,
+    auth: Authenticated,
+    client: ClientInfo,
 ) -> Result<JsonValue, ApiError> {
-    //TODO use _auth.session_key in order to decrypt payload and encrypt response
+    //TODO use auth.session_key in order to decrypt payload and encrypt response
     let locker_id = &payload.id;
-    let email = &payload.e;
-    let input = base64::decode(&payload.i).expect("Could not base64 decode!");
+    let input = base64::decode(&payload.i).map_err(ApiError::BadRequestDecode)?;
     let nonce = payload.n;
-    match locker::delete_finish(locker_id, email, &input, nonce) {
-        Ok(response) => Ok(json!({ "id": response.id, "o": response.output, "n": response.nonce })),
+    let result = locker::delete_finish(locker_id, &auth.email, &input, nonce);
+    audit::record(
+        EventType::LockerDelete,
+        &auth.email,
+        Some(locker_id.as_str()),
+        &client,
+        result.is_ok(),
+    );
+    match result {
+        Ok(response) => Ok(
+            json!({ "id": response.id, "o": response.output, "n": response.nonce, "v": response.version }),
+        ),
         Err(err) => {
-            println!("Error in delete_locker_finish: {:?}", err);
+            error!(error = ?err, "Error in delete_locker_finish");
             Err(err)
         }
     }
 }
-
-// To allow (also need a browser extension) CORS during development (-web requests to -app, localhost on different ports)
-// TODO Add a build cfg feature around this for local (i.e. non_production) builds only
-#[options("/register/start")]
-pub fn options_rs() -> JsonValue {
-    json!({})
-}
-
-#[options("/register/finish")]
-pub fn options_rf() -> JsonValue {
-    json!({})
-}
-
-#[options("/login/start")]
-pub fn options_ls() -> JsonValue {
-    json!({})
-}
-
-#[options("/login/finish")]
-pub fn options_lf() -> JsonValue {
-    json!({})
-}
@@ -1,3 +1,7 @@
+use std::fmt;
+use std::net::IpAddr;
+use zeroize::Zeroizing;
+
 /// Only non-database structs for API endpoints go here
 
 // To quiet if vscode: https://rust-analyzer.github.io/manual.html#unresolved-macro-call
@@ -8,6 +12,8 @@ pub struct RegisterStart {
     pub e: String,
     pub i: String,
     pub c: String,
+    pub m: Option<String>,
+    pub r: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -16,6 +22,7 @@ pub struct RegisterFinish {
     pub e: String,
     pub i: String,
     pub v: String,
+    pub r: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -35,34 +42,113 @@ pub struct LoginFinish {
 pub struct LoginVerify {
     pub id: u32,
     pub i: String,
+    pub c: Option<String>,
+    pub w: Option<WebAuthnAssertion>,
 }
 
+/// All fields base64url encoded, as produced by navigator.credentials.get()
 #[derive(Debug, Deserialize, Serialize)]
-pub struct RegisterLockerStart {
+pub struct WebAuthnAssertion {
     pub id: String,
+    pub a: String,
+    pub d: String,
+    pub s: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct WebAuthnLoginStart {
+    pub i: String,
+}
+
+/// Attestation object and client data JSON, base64url encoded, as produced by navigator.credentials.create()
+#[derive(Debug, Deserialize, Serialize)]
+pub struct WebAuthnRegisterFinish {
+    pub id: u32,
+    pub a: String,
+    pub d: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ReauthStart {
+    pub i: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ReauthFinish {
+    pub id: u32,
+    pub i: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RecoveryStart {
+    pub e: String,
+    pub i: String,
+}
+
+/// `n` and `r` are the registration requests for the new password and the new recovery code
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RecoveryFinish {
+    pub id: u32,
+    pub i: String,
+    pub n: String,
+    pub r: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RecoveryReset {
+    pub t: String,
+    pub n: String,
+    pub r: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RegisterRecovery {
+    pub i: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ChangeEmailStart {
     pub e: String,
     pub i: String,
+    pub r: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ChangeEmailFinish {
+    pub c: String,
+    pub i: String,
+    pub r: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TotpCode {
+    pub c: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RegisterLockerStart {
+    pub id: String,
+    pub i: String,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct RegisterLockerFinish {
     pub id: String,
-    pub e: String,
     pub i: String,
     pub c: String,
+    // Version being rotated, None to register a new locker
+    pub v: Option<i32>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct OpenLockerStart {
     pub id: String,
-    pub e: String,
     pub i: String,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct OpenLockerFinish {
     pub id: String,
-    pub e: String,
     pub i: String,
     pub n: u32,
 }
@@ -70,20 +156,60 @@ pub struct OpenLockerFinish {
 #[derive(Debug, Deserialize, Serialize)]
 pub struct DeleteLockerStart {
     pub id: String,
-    pub e: String,
     pub i: String,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct DeleteLockerFinish {
     pub id: String,
-    pub e: String,
     pub i: String,
     pub n: u32,
 }
 
+// Label, tags and custom fields are base64 of the client-encrypted values, h is the hex SHA-256 of the icon URL
+#[derive(Debug, Deserialize, Serialize)]
+pub struct UpdateLockerMetadata {
+    pub id: String,
+    pub t: String,
+    pub l: Option<String>,
+    pub g: Option<String>,
+    pub f: Option<String>,
+    pub h: Option<String>,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
+pub struct RevokeSession {
+    pub id: String,
+}
+
+/// Not Serialize/Deserialize and with a redacting Debug so the session key can never be printed or sent anywhere.
 pub struct Authenticated {
+    pub email: String,
     pub session_id: Vec<u8>,
-    pub session_key: Vec<u8>,
+    pub session_key: Zeroizing<Vec<u8>>,
+}
+
+impl fmt::Debug for Authenticated {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Authenticated")
+            .field("email", &self.email)
+            .field("session_id", &"<redacted>")
+            .field("session_key", &"<redacted>")
+            .finish()
+    }
 }
+
+/// Where a request came from, recorded on the session for display and auditing.
+#[derive(Debug)]
+pub struct ClientInfo {
+    pub ip: Option<IpAddr>,
+    pub user_agent: Option<String>,
+}
+
+/// An unauthenticated request that is still within its client's rate limit.
+#[derive(Debug)]
+pub struct AuthAttempt;
+
+/// A scraper presenting `KEYPOST_METRICS_TOKEN` as a bearer token.
+#[derive(Debug)]
+pub struct MetricsScraper;
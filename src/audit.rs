@@ -0,0 +1,282 @@
+use sha2::{Digest, Sha256};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::error;
+
+use crate::api::{ApiError, ClientInfo};
+use crate::models::{AuditEvent, NewAuditEvent};
+use crate::persistence;
+
+const HISTORY_LIMIT: i64 = 100;
+
+#[derive(Clone, Copy, Debug)]
+pub enum EventType {
+    Register,
+    Login,
+    Logout,
+    LockerRegister,
+    LockerOpen,
+    LockerDelete,
+    LockerMetadataUpdate,
+    SessionRevoke,
+    TwoFactorEnable,
+    TwoFactorDisable,
+    Recovery,
+    EmailChange,
+}
+
+impl EventType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventType::Register => "register",
+            EventType::Login => "login",
+            EventType::Logout => "logout",
+            EventType::LockerRegister => "locker_register",
+            EventType::LockerOpen => "locker_open",
+            EventType::LockerDelete => "locker_delete",
+            EventType::LockerMetadataUpdate => "locker_metadata_update",
+            EventType::SessionRevoke => "session_revoke",
+            EventType::TwoFactorEnable => "two_factor_enable",
+            EventType::TwoFactorDisable => "two_factor_disable",
+            EventType::Recovery => "recovery",
+            EventType::EmailChange => "email_change",
+        }
+    }
+}
+
+/// One entry of a user's own history, including the chain hashes so the client can check them.
+#[derive(Debug, Serialize)]
+pub struct AuditEntry {
+    pub event: String,
+    pub locker_id: Option<String>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub outcome: String,
+    pub at: i64,
+    pub prev_hash: String,
+    pub hash: String,
+    // The entry's own hash matches its contents
+    pub valid: bool,
+    // `prev_hash` is the hash of the entry before it in the chain, i.e. nothing was removed or inserted in between
+    pub linked: bool,
+}
+
+/// Records the outcome of a security-relevant action. Never fails the request, errors are only logged.
+pub fn record(
+    event_type: EventType,
+    email: &str,
+    locker_id: Option<&str>,
+    client: &ClientInfo,
+    success: bool,
+) {
+    let user_id = persistence::find_user(email)
+        .ok()
+        .flatten()
+        .map(|user| user.id);
+    let event = NewAuditEvent {
+        user_id,
+        event_type: event_type.as_str().to_string(),
+        locker_id: locker_id.map(String::from),
+        ip: client.ip.map(|ip| ip.to_string()),
+        user_agent: client.user_agent.clone(),
+        outcome: match success {
+            true => "success".to_string(),
+            false => "failure".to_string(),
+        },
+        occurred_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as i64)
+            .unwrap_or_default(),
+        prev_hash: String::new(),
+        hash: String::new(),
+    };
+    if let Err(err) = persistence::append_audit_event(event, chain_hash) {
+        error!(event = event_type.as_str(), error = ?err, "Error recording audit event");
+    }
+}
+
+pub fn history(email: &str) -> Result<Vec<AuditEntry>, ApiError> {
+    let user = persistence::find_user(email)
+        .map_err(|err| {
+            error!(error = ?err, "Error finding user");
+            ApiError::ServerError
+        })?
+        .ok_or(ApiError::NotAuthenticated)?;
+    let events = persistence::fetch_audit_events(user.id, HISTORY_LIMIT).map_err(|err| {
+        error!(error = ?err, "Error fetching audit events");
+        ApiError::ServerError
+    })?;
+    Ok(events
+        .into_iter()
+        .map(|(event, preceding_hash)| to_entry(event, preceding_hash))
+        .collect())
+}
+
+/// Walks the whole chain in order and describes every entry that doesn't match its contents or doesn't link to the
+/// entry before it. Empty if the chain is intact.
+pub fn verify_chain(events: &[AuditEvent]) -> Vec<String> {
+    let mut breaks = Vec::new();
+    let mut preceding_hash = "";
+    for event in events {
+        if event_hash(event) != event.hash {
+            breaks.push(format!(
+                "entry {}: hash does not match its contents",
+                event.id
+            ));
+        }
+        if event.prev_hash != preceding_hash {
+            breaks.push(format!(
+                "entry {}: prev_hash is not the hash of the entry before it",
+                event.id
+            ));
+        }
+        preceding_hash = event.hash.as_str();
+    }
+    breaks
+}
+
+// Every entry commits to the previous entry's hash, so changing, removing or reordering rows breaks the chain
+fn chain_hash(event: &NewAuditEvent) -> String {
+    let mut hasher = Sha256::new();
+    let user_id = event.user_id.map(|id| id.to_string()).unwrap_or_default();
+    let occurred_at = event.occurred_at.to_string();
+    for field in [
+        event.prev_hash.as_str(),
+        user_id.as_str(),
+        event.event_type.as_str(),
+        event.locker_id.as_deref().unwrap_or_default(),
+        event.ip.as_deref().unwrap_or_default(),
+        event.user_agent.as_deref().unwrap_or_default(),
+        event.outcome.as_str(),
+        occurred_at.as_str(),
+    ] {
+        // Length-prefixed so field boundaries can't be shifted
+        hasher.update((field.len() as u64).to_be_bytes());
+        hasher.update(field.as_bytes());
+    }
+    base64::encode(hasher.finalize())
+}
+
+// The hash a stored event should have given its contents
+fn event_hash(event: &AuditEvent) -> String {
+    chain_hash(&NewAuditEvent {
+        user_id: event.user_id,
+        event_type: event.event_type.clone(),
+        locker_id: event.locker_id.clone(),
+        ip: event.ip.clone(),
+        user_agent: event.user_agent.clone(),
+        outcome: event.outcome.clone(),
+        occurred_at: event.occurred_at,
+        prev_hash: event.prev_hash.clone(),
+        hash: String::new(),
+    })
+}
+
+fn to_entry(event: AuditEvent, preceding_hash: Option<String>) -> AuditEntry {
+    AuditEntry {
+        valid: event_hash(&event) == event.hash,
+        linked: event.prev_hash == preceding_hash.unwrap_or_default(),
+        event: event.event_type,
+        locker_id: event.locker_id,
+        ip: event.ip,
+        user_agent: event.user_agent,
+        outcome: event.outcome,
+        at: event.occurred_at,
+        prev_hash: event.prev_hash,
+        hash: event.hash,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use diesel::pg::data_types::PgTimestamp;
+
+    fn new_event(outcome: &str, prev_hash: &str) -> NewAuditEvent {
+        NewAuditEvent {
+            user_id: Some(1),
+            event_type: EventType::Login.as_str().to_string(),
+            locker_id: None,
+            ip: Some("192.0.2.1".to_string()),
+            user_agent: None,
+            outcome: outcome.to_string(),
+            occurred_at: 1_700_000_000_000,
+            prev_hash: prev_hash.to_string(),
+            hash: String::new(),
+        }
+    }
+
+    // What append_audit_event stores: each entry hashed over the previous entry's hash
+    fn chain(outcomes: &[&str]) -> Vec<AuditEvent> {
+        let mut events: Vec<AuditEvent> = Vec::new();
+        for (i, outcome) in outcomes.iter().enumerate() {
+            let prev_hash = events.last().map(|e| e.hash.clone()).unwrap_or_default();
+            let new = new_event(outcome, &prev_hash);
+            events.push(AuditEvent {
+                id: i as i32 + 1,
+                user_id: new.user_id,
+                event_type: new.event_type.clone(),
+                locker_id: new.locker_id.clone(),
+                ip: new.ip.clone(),
+                user_agent: new.user_agent.clone(),
+                outcome: new.outcome.clone(),
+                occurred_at: new.occurred_at,
+                prev_hash,
+                hash: chain_hash(&new),
+                inserted_at: PgTimestamp(0),
+            });
+        }
+        events
+    }
+
+    #[test]
+    fn chain_hash_commits_to_previous_hash_and_field_boundaries() {
+        let first = chain_hash(&new_event("success", ""));
+        assert_ne!(chain_hash(&new_event("success", &first)), first);
+        assert_ne!(
+            chain_hash(&new_event("success", &first)),
+            chain_hash(&new_event("success", "something else"))
+        );
+
+        let mut shifted = new_event("success", "");
+        shifted.locker_id = Some("ab".to_string());
+        shifted.ip = Some("c".to_string());
+        let mut original = new_event("success", "");
+        original.locker_id = Some("a".to_string());
+        original.ip = Some("bc".to_string());
+        assert_ne!(chain_hash(&shifted), chain_hash(&original));
+    }
+
+    #[test]
+    fn intact_chain_verifies() {
+        let events = chain(&["success", "failure", "success"]);
+        assert!(verify_chain(&events).is_empty());
+        let entry = to_entry(events[1].clone(), Some(events[0].hash.clone()));
+        assert!(entry.valid && entry.linked);
+    }
+
+    #[test]
+    fn altered_entry_breaks_its_hash() {
+        let mut events = chain(&["success", "failure", "success"]);
+        events[1].outcome = "success".to_string();
+        assert_eq!(
+            verify_chain(&events),
+            vec!["entry 2: hash does not match its contents"]
+        );
+        assert!(!to_entry(events[1].clone(), Some(events[0].hash.clone())).valid);
+    }
+
+    #[test]
+    fn removed_or_reordered_entries_break_the_links() {
+        let mut events = chain(&["success", "failure", "success"]);
+        let removed = events.remove(1);
+        assert_eq!(
+            verify_chain(&events),
+            vec!["entry 3: prev_hash is not the hash of the entry before it"]
+        );
+        let entry = to_entry(events[1].clone(), Some(events[0].hash.clone()));
+        assert!(entry.valid && !entry.linked);
+
+        events.insert(0, removed);
+        assert_eq!(verify_chain(&events).len(), 3);
+    }
+}
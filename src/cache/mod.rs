@@ -1,3 +1,7 @@
+mod rate_limit;
+mod session;
 mod store;
 
+pub use rate_limit::*;
+pub use session::*;
 pub use store::*;
@@ -0,0 +1,30 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config;
+
+const WINDOW: Duration = Duration::from_secs(60);
+
+lazy_static! {
+    // Start of the current window and the attempts counted in it, per client IP
+    static ref AUTH_ATTEMPTS: Mutex<HashMap<IpAddr, (Instant, u32)>> = {
+        let map = HashMap::new();
+        Mutex::new(map)
+    };
+}
+
+/// Counts an unauthenticated attempt (login, registration, recovery) against a fixed one-minute window.
+pub fn allow_auth_attempt(ip: IpAddr) -> bool {
+    let limit = config::get().rate_limits.auth_per_minute;
+    if limit == 0 {
+        return true;
+    }
+    let now = Instant::now();
+    let mut attempts = AUTH_ATTEMPTS.lock().unwrap();
+    attempts.retain(|_, (window_start, _)| now.duration_since(*window_start) < WINDOW);
+    let (_, count) = attempts.entry(ip).or_insert((now, 0));
+    *count += 1;
+    *count <= limit
+}
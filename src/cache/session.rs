@@ -0,0 +1,359 @@
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use zeroize::Zeroizing;
+
+use crate::config;
+
+// A session expires after the idle timeout without an authenticated request, or after its max lifetime regardless of activity.
+fn idle_timeout() -> Duration {
+    Duration::from_secs(config::get().sessions.idle_timeout_secs)
+}
+
+fn max_lifetime() -> Duration {
+    Duration::from_secs(config::get().sessions.max_lifetime_secs)
+}
+
+// How long the code mailed to a new address stays valid
+fn email_change_ttl() -> Duration {
+    Duration::from_secs(config::get().sessions.email_change_ttl_secs)
+}
+
+// How long a started login, registration or locker operation waits for its next step
+fn pending_ttl() -> Duration {
+    Duration::from_secs(config::get().sessions.pending_ttl_secs)
+}
+
+// How long a fresh password proof (i.e. /reauth) allows sensitive account changes for
+fn reauth_window() -> Duration {
+    Duration::from_secs(config::get().sessions.reauth_window_secs)
+}
+
+pub struct Session {
+    pub email: String,
+    pub session_key: Zeroizing<Vec<u8>>,
+    pub created_at: SystemTime,
+    pub last_seen: SystemTime,
+    pub ip: Option<IpAddr>,
+    pub user_agent: Option<String>,
+    pub reauthenticated_at: Option<SystemTime>,
+}
+
+impl Session {
+    pub fn new(
+        email: String,
+        session_key: Zeroizing<Vec<u8>>,
+        ip: Option<IpAddr>,
+        user_agent: Option<String>,
+    ) -> Session {
+        let now = SystemTime::now();
+        Session {
+            email,
+            session_key,
+            created_at: now,
+            last_seen: now,
+            ip,
+            user_agent,
+            reauthenticated_at: None,
+        }
+    }
+
+    fn is_expired(&self, now: SystemTime) -> bool {
+        let idle = now.duration_since(self.last_seen).unwrap_or_default();
+        let age = now.duration_since(self.created_at).unwrap_or_default();
+        idle > idle_timeout() || age > max_lifetime()
+    }
+}
+
+/// What a user gets to see about one of their sessions. The handle identifies the session for revocation without exposing the session id.
+#[derive(Debug, Serialize)]
+pub struct SessionInfo {
+    pub handle: String,
+    pub device: String,
+    pub created_at: u64,
+    pub last_seen: u64,
+    pub ip: Option<String>,
+    pub current: bool,
+}
+
+/// An email change waiting for the code sent to the new address. At most one per session.
+pub struct PendingEmailChange {
+    pub new_email: String,
+    pub code_hash: Vec<u8>,
+    created_at: SystemTime,
+}
+
+#[derive(Default)]
+struct SessionStore {
+    sessions: HashMap<Vec<u8>, Session>,
+    by_user: HashMap<String, HashSet<Vec<u8>>>,
+}
+
+impl SessionStore {
+    fn insert(&mut self, session_id: Vec<u8>, session: Session) {
+        self.by_user
+            .entry(session.email.clone())
+            .or_default()
+            .insert(session_id.clone());
+        self.sessions.insert(session_id, session);
+    }
+
+    fn remove(&mut self, session_id: &[u8]) -> Option<Session> {
+        let session = self.sessions.remove(session_id)?;
+        if let Some(ids) = self.by_user.get_mut(&session.email) {
+            ids.remove(session_id);
+            if ids.is_empty() {
+                self.by_user.remove(&session.email);
+            }
+        }
+        Some(session)
+    }
+
+    fn purge_expired(&mut self, now: SystemTime) {
+        let expired: Vec<Vec<u8>> = self
+            .sessions
+            .iter()
+            .filter(|(_, s)| s.is_expired(now))
+            .map(|(id, _)| id.clone())
+            .collect();
+        for session_id in expired {
+            self.remove(&session_id);
+        }
+    }
+
+    fn user_session_ids(&self, email: &str) -> Vec<Vec<u8>> {
+        self.by_user
+            .get(email)
+            .map(|ids| ids.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+lazy_static! {
+    static ref SESSIONS: Mutex<SessionStore> = Mutex::new(SessionStore::default());
+    // Email of each in-progress login, keyed by login id (start -> finish) and then by client_hash (finish -> verify).
+    // Expires like the other pending state in the cache.
+    static ref LOGIN_EMAILS: Mutex<HashMap<Vec<u8>, (String, SystemTime)>> = {
+        let map = HashMap::new();
+        Mutex::new(map)
+    };
+    static ref EMAIL_CHANGES: Mutex<HashMap<Vec<u8>, PendingEmailChange>> = {
+        let map = HashMap::new();
+        Mutex::new(map)
+    };
+}
+
+pub fn insert_login_email(k: Vec<u8>, email: &str) {
+    let mut login_emails = LOGIN_EMAILS.lock().unwrap();
+    let now = SystemTime::now();
+    login_emails.retain(|_, (_, created_at)| !is_expired_pending(*created_at, now));
+    login_emails.insert(k, (email.to_string(), now));
+}
+
+pub fn get_login_email(k: &[u8]) -> Option<String> {
+    let login_emails = LOGIN_EMAILS.lock().unwrap();
+    login_emails
+        .get(k)
+        .filter(|(_, created_at)| !is_expired_pending(*created_at, SystemTime::now()))
+        .map(|(email, _)| email.clone())
+}
+
+pub fn take_login_email(k: &[u8]) -> Option<String> {
+    let mut login_emails = LOGIN_EMAILS.lock().unwrap();
+    login_emails
+        .remove(k)
+        .filter(|(_, created_at)| !is_expired_pending(*created_at, SystemTime::now()))
+        .map(|(email, _)| email)
+}
+
+pub(super) fn is_expired_pending(created_at: SystemTime, now: SystemTime) -> bool {
+    now.duration_since(created_at).unwrap_or_default() > pending_ttl()
+}
+
+pub fn insert_session(session_id: Vec<u8>, session: Session) {
+    let mut store = SESSIONS.lock().unwrap();
+    store.purge_expired(SystemTime::now());
+    store.insert(session_id, session);
+}
+
+/// Returns the session key and email if the session exists and has not expired, refreshing its idle timeout.
+pub fn touch_session(session_id: &[u8]) -> Option<(Zeroizing<Vec<u8>>, String)> {
+    let mut store = SESSIONS.lock().unwrap();
+    let now = SystemTime::now();
+    let expired = store.sessions.get(session_id)?.is_expired(now);
+    if expired {
+        store.remove(session_id);
+        return None;
+    }
+    let session = store.sessions.get_mut(session_id)?;
+    session.last_seen = now;
+    Some((session.session_key.clone(), session.email.clone()))
+}
+
+/// Moves the session to `new_session_id`, invalidating `session_id`. The absolute lifetime is kept.
+pub fn refresh_session(
+    session_id: &[u8],
+    new_session_id: Vec<u8>,
+    ip: Option<IpAddr>,
+    user_agent: Option<String>,
+) -> bool {
+    let mut store = SESSIONS.lock().unwrap();
+    match store.remove(session_id) {
+        Some(mut session) if !session.is_expired(SystemTime::now()) => {
+            session.last_seen = SystemTime::now();
+            session.ip = ip;
+            session.user_agent = user_agent;
+            store.insert(new_session_id, session);
+            true
+        }
+        _ => false,
+    }
+}
+
+pub fn mark_reauthenticated(session_id: &[u8]) -> bool {
+    let mut store = SESSIONS.lock().unwrap();
+    match store.sessions.get_mut(session_id) {
+        Some(session) => {
+            session.reauthenticated_at = Some(SystemTime::now());
+            true
+        }
+        None => false,
+    }
+}
+
+pub fn is_reauthenticated(session_id: &[u8]) -> bool {
+    let store = SESSIONS.lock().unwrap();
+    store
+        .sessions
+        .get(session_id)
+        .and_then(|s| s.reauthenticated_at)
+        .and_then(|t| SystemTime::now().duration_since(t).ok())
+        .map(|elapsed| elapsed <= reauth_window())
+        .unwrap_or(false)
+}
+
+pub fn sessions_healthy() -> bool {
+    !SESSIONS.is_poisoned() && !LOGIN_EMAILS.is_poisoned() && !EMAIL_CHANGES.is_poisoned()
+}
+
+pub fn active_session_count() -> usize {
+    let mut store = SESSIONS.lock().unwrap();
+    store.purge_expired(SystemTime::now());
+    store.sessions.len()
+}
+
+pub fn delete_session(session_id: &[u8]) -> bool {
+    let mut store = SESSIONS.lock().unwrap();
+    store.remove(session_id).is_some()
+}
+
+pub fn list_sessions(email: &str, current_session_id: &[u8]) -> Vec<SessionInfo> {
+    let mut store = SESSIONS.lock().unwrap();
+    store.purge_expired(SystemTime::now());
+    store
+        .user_session_ids(email)
+        .iter()
+        .filter_map(|session_id| {
+            store.sessions.get(session_id).map(|s| SessionInfo {
+                handle: session_handle(session_id),
+                device: s
+                    .user_agent
+                    .clone()
+                    .unwrap_or_else(|| "Unknown device".to_string()),
+                created_at: unix_secs(s.created_at),
+                last_seen: unix_secs(s.last_seen),
+                ip: s.ip.map(|ip| ip.to_string()),
+                current: session_id.as_slice() == current_session_id,
+            })
+        })
+        .collect()
+}
+
+/// Revokes the user's session identified by `handle`. Sessions of other users are never matched.
+pub fn revoke_session(email: &str, handle: &str) -> bool {
+    let mut store = SESSIONS.lock().unwrap();
+    let session_id = store
+        .user_session_ids(email)
+        .into_iter()
+        .find(|session_id| session_handle(session_id) == handle);
+    match session_id {
+        Some(session_id) => store.remove(&session_id).is_some(),
+        None => false,
+    }
+}
+
+/// Revokes all of the user's sessions except `current_session_id`, returning how many were revoked.
+pub fn revoke_other_sessions(email: &str, current_session_id: &[u8]) -> usize {
+    let mut store = SESSIONS.lock().unwrap();
+    let others: Vec<Vec<u8>> = store
+        .user_session_ids(email)
+        .into_iter()
+        .filter(|session_id| session_id.as_slice() != current_session_id)
+        .collect();
+    others
+        .iter()
+        .filter(|session_id| store.remove(session_id).is_some())
+        .count()
+}
+
+/// Revokes every session of the user, e.g. after their password was reset. Returns how many were revoked.
+pub fn revoke_user_sessions(email: &str) -> usize {
+    let mut store = SESSIONS.lock().unwrap();
+    store
+        .user_session_ids(email)
+        .iter()
+        .filter(|session_id| store.remove(session_id).is_some())
+        .count()
+}
+
+/// Points the user's sessions at their new email, so an email change doesn't sign them out.
+pub fn rename_user_sessions(email: &str, new_email: &str) -> usize {
+    let mut store = SESSIONS.lock().unwrap();
+    let session_ids = store.user_session_ids(email);
+    for session_id in &session_ids {
+        if let Some(mut session) = store.remove(session_id) {
+            session.email = new_email.to_string();
+            store.insert(session_id.clone(), session);
+        }
+    }
+    session_ids.len()
+}
+
+/// Starts (or replaces) the session's pending email change.
+pub fn insert_email_change(session_id: Vec<u8>, new_email: &str, code_hash: Vec<u8>) {
+    let mut email_changes = EMAIL_CHANGES.lock().unwrap();
+    let now = SystemTime::now();
+    email_changes.retain(|_, change| !is_expired_email_change(change, now));
+    email_changes.insert(
+        session_id,
+        PendingEmailChange {
+            new_email: new_email.to_string(),
+            code_hash,
+            created_at: now,
+        },
+    );
+}
+
+/// Removes and returns the session's pending email change, unless it has expired. Each one can be attempted once.
+pub fn take_email_change(session_id: &[u8]) -> Option<PendingEmailChange> {
+    let mut email_changes = EMAIL_CHANGES.lock().unwrap();
+    email_changes
+        .remove(session_id)
+        .filter(|change| !is_expired_email_change(change, SystemTime::now()))
+}
+
+fn is_expired_email_change(change: &PendingEmailChange, now: SystemTime) -> bool {
+    now.duration_since(change.created_at).unwrap_or_default() > email_change_ttl()
+}
+
+fn session_handle(session_id: &[u8]) -> String {
+    base64::encode(Sha256::digest(session_id))
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
@@ -1,50 +1,109 @@
 use std::collections::HashMap;
+use std::hash::Hash;
 use std::sync::Mutex;
+use std::time::SystemTime;
+use zeroize::Zeroizing;
 
+use super::session::is_expired_pending;
+
+// Values are session keys and OPAQUE server login state, so they are wiped from memory when removed or overwritten.
+// They belong to a login, registration or locker operation that may never finish, so they also expire: expired
+// entries are never returned and are dropped (and wiped) on the next insert.
 // https://github.com/SergioBenitez/Rocket/blob/v0.4.10/examples/uuid/src/main.rs
 lazy_static! {
-    static ref CACHE: Mutex<HashMap<u32, Vec<u8>>> = {
+    static ref CACHE: Mutex<HashMap<u32, Entry>> = {
         let map = HashMap::new();
         Mutex::new(map)
     };
-    static ref BIN_CACHE: Mutex<HashMap<Vec<u8>, Vec<u8>>> = {
+    static ref BIN_CACHE: Mutex<HashMap<Vec<u8>, Entry>> = {
         let map = HashMap::new();
         Mutex::new(map)
     };
 }
 
-pub fn insert(k: u32, v: Vec<u8>) {
-    let mut cache = CACHE.lock().unwrap();
-    cache.insert(k, v);
+struct Entry {
+    value: Zeroizing<Vec<u8>>,
+    created_at: SystemTime,
 }
 
-pub fn insert_str(k: u32, s: &str) {
-    let v = s.as_bytes().to_vec();
-    let mut cache = CACHE.lock().unwrap();
-    cache.insert(k, v);
+impl Entry {
+    fn new(value: Zeroizing<Vec<u8>>) -> Entry {
+        Entry {
+            value,
+            created_at: SystemTime::now(),
+        }
+    }
+
+    fn is_live(&self, now: SystemTime) -> bool {
+        !is_expired_pending(self.created_at, now)
+    }
 }
 
-pub fn insert_bin(k: Vec<u8>, v: Vec<u8>) {
-    let mut cache = BIN_CACHE.lock().unwrap();
-    cache.insert(k, v);
+fn insert_entry<K: Eq + Hash>(cache: &Mutex<HashMap<K, Entry>>, k: K, v: Zeroizing<Vec<u8>>) {
+    let mut cache = cache.lock().unwrap();
+    let now = SystemTime::now();
+    cache.retain(|_, entry| entry.is_live(now));
+    cache.insert(k, Entry::new(v));
 }
 
-pub fn get(k: &u32) -> Option<Vec<u8>> {
-    let cache = CACHE.lock().unwrap();
-    cache.get(k).cloned()
+pub fn insert(k: u32, v: Zeroizing<Vec<u8>>) {
+    insert_entry(&CACHE, k, v);
 }
 
-pub fn get_str(k: &u32) -> Option<String> {
-    let cache = CACHE.lock().unwrap();
-    cache.get(k).map(|v| String::from_utf8_lossy(v).to_string())
+pub fn insert_str(k: u32, s: &str) {
+    insert_entry(&CACHE, k, Zeroizing::new(s.as_bytes().to_vec()));
+}
+
+pub fn insert_bin(k: Vec<u8>, v: Zeroizing<Vec<u8>>) {
+    insert_entry(&BIN_CACHE, k, v);
 }
 
-pub fn get_bin(k: &[u8]) -> Option<Vec<u8>> {
+pub fn get_bin(k: &[u8]) -> Option<Zeroizing<Vec<u8>>> {
     let cache = BIN_CACHE.lock().unwrap();
-    cache.get(k).cloned()
+    cache
+        .get(k)
+        .filter(|entry| entry.is_live(SystemTime::now()))
+        .map(|entry| entry.value.clone())
+}
+
+// Single-use values are taken under one lock, so two concurrent requests can never both get them
+pub fn take(k: &u32) -> Option<Zeroizing<Vec<u8>>> {
+    let mut cache = CACHE.lock().unwrap();
+    cache
+        .remove(k)
+        .filter(|entry| entry.is_live(SystemTime::now()))
+        .map(|entry| entry.value)
+}
+
+pub fn take_str(k: &u32) -> Option<String> {
+    take(k).map(|v| String::from_utf8_lossy(&v).to_string())
+}
+
+pub fn take_bin(k: &[u8]) -> Option<Zeroizing<Vec<u8>>> {
+    let mut cache = BIN_CACHE.lock().unwrap();
+    cache
+        .remove(k)
+        .filter(|entry| entry.is_live(SystemTime::now()))
+        .map(|entry| entry.value)
 }
 
 pub fn delete_bin(k: &[u8]) -> bool {
     let mut cache = BIN_CACHE.lock().unwrap();
     cache.remove(k).is_some()
 }
+
+pub fn is_healthy() -> bool {
+    !CACHE.is_poisoned() && !BIN_CACHE.is_poisoned()
+}
+
+pub fn len() -> usize {
+    let now = SystemTime::now();
+    let cache = CACHE.lock().unwrap();
+    cache.values().filter(|entry| entry.is_live(now)).count()
+}
+
+pub fn len_bin() -> usize {
+    let now = SystemTime::now();
+    let cache = BIN_CACHE.lock().unwrap();
+    cache.values().filter(|entry| entry.is_live(now)).count()
+}
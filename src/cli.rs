@@ -0,0 +1,320 @@
+use serde_json::{json, Value};
+use toml::Value as TomlValue;
+
+use crate::audit;
+use crate::config;
+use crate::crypto;
+use crate::persistence;
+use crate::util;
+
+pub const USAGE: &str = "Usage: keypost-app [COMMAND]
+
+Commands:
+  serve                   Start the server (default)
+  init                    Create the data directory, ServerSetup and server key
+  migrate                 Run the embedded database migrations
+  list-users              List all users, including disabled ones
+  disable-user <email>    Soft-delete a user so they can no longer log in
+  purge-deleted           Permanently remove disabled users and their lockers
+  rotate-server-key       Re-encrypt server-side secrets under a new server key (server must be stopped)
+  reencrypt               Re-encrypt password files and lockers under the current master key
+  export-backup <path>    Write server secrets and all tables to <path> (mode 0600)
+  verify-config           Validate the configuration and print it, secrets redacted
+  verify-audit            Check the audit log hash chain for altered, removed or inserted entries
+  help                    Print this message";
+
+pub enum Command {
+    Serve,
+    Init,
+    Migrate,
+    ListUsers,
+    DisableUser(String),
+    PurgeDeleted,
+    RotateServerKey,
+    Reencrypt,
+    ExportBackup(String),
+    VerifyConfig,
+    VerifyAudit,
+    Help,
+}
+
+/// Parses the arguments after the program name. No command means `serve`.
+pub fn parse(args: &[String]) -> Result<Command, String> {
+    let command = match args.get(0).map(|s| s.as_str()) {
+        None | Some("serve") => Command::Serve,
+        Some("init") => Command::Init,
+        Some("migrate") => Command::Migrate,
+        Some("list-users") => Command::ListUsers,
+        Some("disable-user") => Command::DisableUser(argument(args, "<email>")?),
+        Some("purge-deleted") => Command::PurgeDeleted,
+        Some("rotate-server-key") => Command::RotateServerKey,
+        Some("reencrypt") => Command::Reencrypt,
+        Some("export-backup") => Command::ExportBackup(argument(args, "<path>")?),
+        Some("verify-config") => Command::VerifyConfig,
+        Some("verify-audit") => Command::VerifyAudit,
+        Some("help") | Some("-h") | Some("--help") => Command::Help,
+        Some(other) => return Err(format!("Unknown command `{}`", other)),
+    };
+    let expected = match command {
+        Command::DisableUser(_) | Command::ExportBackup(_) => 2,
+        _ => 1,
+    };
+    if args.len() > expected {
+        return Err(format!("Unexpected argument `{}`", args[expected]));
+    }
+    Ok(command)
+}
+
+fn argument(args: &[String], name: &str) -> Result<String, String> {
+    args.get(1)
+        .cloned()
+        .ok_or_else(|| format!("`{}` needs an argument {}", args[0], name))
+}
+
+/// Runs an administrative command. Every command uses the same config and persistence layer as the server.
+pub fn run(command: Command) -> Result<(), String> {
+    match command {
+        Command::Serve => unreachable!("serve is handled by main"),
+        Command::Help => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        Command::Init => init(),
+        Command::Migrate => persistence::run_migrations(),
+        Command::ListUsers => list_users(),
+        Command::DisableUser(email) => disable_user(&email),
+        Command::PurgeDeleted => purge_deleted(),
+        Command::RotateServerKey => rotate_server_key(),
+        Command::Reencrypt => reencrypt(),
+        Command::ExportBackup(path) => export_backup(&path),
+        Command::VerifyConfig => verify_config(),
+        Command::VerifyAudit => verify_audit(),
+    }
+}
+
+fn init() -> Result<(), String> {
+    init_data_dir()?;
+    println!("Data directory {} is ready", util::default_dir());
+    Ok(())
+}
+
+fn init_data_dir() -> Result<(), String> {
+    crypto::init().map_err(|err| format!("Could not initialize the app directory: {}", err))
+}
+
+fn list_users() -> Result<(), String> {
+    let users = persistence::list_users().map_err(db_error)?;
+    for user in &users {
+        let status = if user.deleted { "disabled" } else { "active" };
+        println!("{}\t{}\t{}", user.id, user.email, status);
+    }
+    println!("{} user(s)", users.len());
+    Ok(())
+}
+
+fn disable_user(email: &str) -> Result<(), String> {
+    match persistence::disable_user(email).map_err(db_error)? {
+        0 => Err(format!("No active user {}", email)),
+        _ => {
+            println!("Disabled {}", email);
+            Ok(())
+        }
+    }
+}
+
+fn purge_deleted() -> Result<(), String> {
+    let purged = persistence::purge_deleted_users().map_err(db_error)?;
+    println!("Purged {} disabled user(s)", purged);
+    Ok(())
+}
+
+// The new key is staged in its own file first, so a failure before the switch leaves the current key in use.
+fn rotate_server_key() -> Result<(), String> {
+    // Before the current key is read, so no server starts with it halfway through
+    let _lock = persistence::lock_server_key()?;
+    init_data_dir()?;
+    let old_key = crypto::server_key();
+    let new_key = crypto::stage_server_key()
+        .map_err(|err| format!("Could not write the new server key: {}", err))?;
+    let resealed = persistence::reseal_totp_secrets(|sealed| {
+        let bytes = base64::decode(sealed).ok()?;
+        let secret = crypto::open(old_key, crypto::KeyPurpose::TotpSecret, &bytes).ok()?;
+        let sealed = crypto::seal(&new_key, crypto::KeyPurpose::TotpSecret, &secret);
+        Some(base64::encode(sealed))
+    })
+    .map_err(|err| {
+        format!(
+            "Could not re-encrypt TOTP secrets, nothing was changed: {}",
+            err
+        )
+    })?;
+    crypto::promote_server_key().map_err(|err| {
+        format!(
+            "TOTP secrets now use the key in {dir}/{file}.next but it could not be moved into place: {err}. \
+             Rename {file} to {file}.previous and {file}.next to {file} by hand before starting the server",
+            dir = util::default_dir(),
+            file = crypto::SERVER_KEY_FILE,
+            err = err
+        )
+    })?;
+    println!(
+        "Rotated the server key and re-encrypted {} TOTP secret(s). The old key is kept as {}.previous, \
+         delete it once a backup with the new key exists",
+        resealed,
+        crypto::SERVER_KEY_FILE
+    );
+    Ok(())
+}
+
+// Same pass the server runs in the background at startup, for operators who want to wait for it
+fn reencrypt() -> Result<(), String> {
+    persistence::init_encryption()?;
+    let done = persistence::reencrypt_all()?;
+    println!(
+        "Re-encrypted {} row(s) under master key `{}`",
+        done.rows,
+        config::get().encryption.master_key_id
+    );
+    match done.errors {
+        0 => Ok(()),
+        errors => Err(format!(
+            "{} row(s) could not be decrypted and were skipped, see the log",
+            errors
+        )),
+    }
+}
+
+fn export_backup(path: &str) -> Result<(), String> {
+    let dir = util::default_dir();
+    let read_secret = |file: &str| {
+        util::read_file(&format!("{}/{}", dir, file))
+            .map(base64::encode)
+            .map_err(|err| format!("Could not read {}: {}", file, err))
+    };
+    let users: Vec<Value> = persistence::list_users()
+        .map_err(db_error)?
+        .into_iter()
+        .map(|u| {
+            json!({"id": u.id, "email": u.email, "psswd_file": base64::encode(u.psswd_file),
+                "recovery_psswd_file": u.recovery_psswd_file.map(base64::encode), "deleted": u.deleted,
+                "data_key_id": u.data_key_id})
+        })
+        .collect();
+    let lockers: Vec<Value> = persistence::load_all_lockers()
+        .map_err(db_error)?
+        .into_iter()
+        .map(|l| {
+            json!({"id": l.id, "user_id": l.user_id, "locker_id": l.locker_id,
+                "psswd_file": base64::encode(l.psswd_file), "ciphertext": base64::encode(l.ciphertext),
+                "version": l.version, "data_key_id": l.data_key_id, "kind": l.kind,
+                "label": l.label.map(base64::encode), "tags": l.tags.map(base64::encode),
+                "custom_fields": l.custom_fields.map(base64::encode), "icon_hash": l.icon_hash})
+        })
+        .collect();
+    let data_keys: Vec<Value> = persistence::load_all_data_keys()
+        .map_err(db_error)?
+        .into_iter()
+        .map(|d| {
+            json!({"id": d.id, "master_key_id": d.master_key_id,
+                "wrapped_key": base64::encode(d.wrapped_key)})
+        })
+        .collect();
+    let totp_secrets: Vec<Value> = persistence::load_all_totp_secrets()
+        .map_err(db_error)?
+        .into_iter()
+        .map(|t| {
+            json!({"id": t.id, "user_id": t.user_id, "secret": t.secret, "enabled": t.enabled,
+                "last_used_step": t.last_used_step})
+        })
+        .collect();
+    let recovery_codes: Vec<Value> = persistence::load_all_recovery_codes()
+        .map_err(db_error)?
+        .into_iter()
+        .map(
+            |r| json!({"id": r.id, "user_id": r.user_id, "code_hash": r.code_hash, "used": r.used}),
+        )
+        .collect();
+    let webauthn_credentials: Vec<Value> = persistence::load_all_webauthn_credentials()
+        .map_err(db_error)?
+        .into_iter()
+        .map(|w| {
+            json!({"id": w.id, "user_id": w.user_id, "credential_id": w.credential_id,
+                "public_key": w.public_key, "sign_count": w.sign_count})
+        })
+        .collect();
+    let audit_events: Vec<Value> = persistence::load_all_audit_events()
+        .map_err(db_error)?
+        .into_iter()
+        .map(|a| {
+            json!({"id": a.id, "user_id": a.user_id, "event_type": a.event_type, "locker_id": a.locker_id,
+                "ip": a.ip, "user_agent": a.user_agent, "outcome": a.outcome, "occurred_at": a.occurred_at,
+                "prev_hash": a.prev_hash, "hash": a.hash})
+        })
+        .collect();
+    let backup = json!({
+        "server_setup": read_secret(crypto::SERVER_SETUP_FILE)?,
+        "server_key": read_secret(crypto::SERVER_KEY_FILE)?,
+        "users": users,
+        "lockers": lockers,
+        "data_keys": data_keys,
+        "totp_secrets": totp_secrets,
+        "recovery_codes": recovery_codes,
+        "webauthn_credentials": webauthn_credentials,
+        "audit_events": audit_events,
+    });
+    util::write_to_file(path, backup.to_string().as_bytes())
+        .map_err(|err| format!("Could not write backup to {}: {}", path, err))?;
+    println!(
+        "Wrote backup to {}. It contains the server secrets, store it encrypted and never next to the database. \
+         The master keys are not included, restoring needs them too",
+        path
+    );
+    Ok(())
+}
+
+// config::init() already validated it, printing shows what the sources resolved to
+fn verify_config() -> Result<(), String> {
+    let mut value = TomlValue::try_from(config::get())
+        .map_err(|err| format!("Could not serialize config: {}", err))?;
+    if let Some(database) = value.get_mut("database").and_then(TomlValue::as_table_mut) {
+        database.insert("url".into(), TomlValue::String("[redacted]".into()));
+    }
+    if let Some(encryption) = value
+        .get_mut("encryption")
+        .and_then(TomlValue::as_table_mut)
+    {
+        if encryption.contains_key("master_key") {
+            encryption.insert("master_key".into(), TomlValue::String("[redacted]".into()));
+        }
+    }
+    if let Some(metrics) = value.get_mut("metrics").and_then(TomlValue::as_table_mut) {
+        if metrics.contains_key("token") {
+            metrics.insert("token".into(), TomlValue::String("[redacted]".into()));
+        }
+    }
+    println!("Configuration is valid\n\n{}", value);
+    Ok(())
+}
+
+fn verify_audit() -> Result<(), String> {
+    let events = persistence::load_all_audit_events().map_err(db_error)?;
+    let breaks = audit::verify_chain(&events);
+    for chain_break in &breaks {
+        println!("{}", chain_break);
+    }
+    match breaks.len() {
+        0 => {
+            println!("Audit chain of {} entries is intact", events.len());
+            Ok(())
+        }
+        n => Err(format!(
+            "Audit chain is broken: {} problem(s) in {} entries",
+            n,
+            events.len()
+        )),
+    }
+}
+
+fn db_error(err: diesel::result::Error) -> String {
+    format!("Database error: {}", err)
+}
@@ -0,0 +1,708 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use toml::Value;
+
+use crate::mail;
+use crate::persistence;
+
+const DEFAULT_CONFIG_FILE: &str = "keypost.toml";
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Profile {
+    Dev,
+    Staging,
+    Prod,
+}
+
+impl Profile {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Profile::Dev => "dev",
+            Profile::Staging => "staging",
+            Profile::Prod => "prod",
+        }
+    }
+}
+
+impl FromStr for Profile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dev" => Ok(Profile::Dev),
+            "staging" => Ok(Profile::Staging),
+            "prod" => Ok(Profile::Prod),
+            other => Err(format!(
+                "Unknown profile `{}`, expected dev, staging or prod",
+                other
+            )),
+        }
+    }
+}
+
+/// Everything the app can be configured with. Built from the profile's defaults, then the `[default]` and
+/// `[<profile>]` tables of the config file, then environment variables, and validated before anything starts.
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub profile: Profile,
+    pub data_dir: String,
+    pub server: ServerConfig,
+    pub database: DatabaseConfig,
+    pub cache: CacheConfig,
+    pub sessions: SessionConfig,
+    pub rate_limits: RateLimitConfig,
+    pub log: LogConfig,
+    pub metrics: MetricsConfig,
+    pub cors: CorsConfig,
+    pub headers: HeadersConfig,
+    pub webauthn: WebAuthnConfig,
+    pub mail: MailConfig,
+    pub encryption: EncryptionConfig,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServerConfig {
+    pub address: String,
+    pub port: u16,
+    // With TLS, Rocket serves plain HTTP here on 127.0.0.1 and the app terminates TLS on `port` in front of it
+    pub internal_port: u16,
+    pub tls: Option<TlsConfig>,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    pub certs: String,
+    pub key: String,
+    // CA bundle for client certificates. When set, admin routes (metrics) require a client certificate signed by it
+    pub client_ca: Option<String>,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub url: String,
+    // `apply` runs pending migrations at startup, `check` refuses to start unless the schema is up to date
+    pub migrations: String,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct CacheConfig {
+    pub backend: String,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct SessionConfig {
+    pub idle_timeout_secs: u64,
+    pub max_lifetime_secs: u64,
+    pub reauth_window_secs: u64,
+    // How long the code mailed for an email change stays valid
+    pub email_change_ttl_secs: u64,
+    // How long a started login, registration or locker operation is kept in the cache waiting for its next step
+    pub pending_ttl_secs: u64,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitConfig {
+    // Per client IP, across the unauthenticated *start endpoints. 0 disables the limit.
+    pub auth_per_minute: u32,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct LogConfig {
+    pub filter: String,
+    pub format: String,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct MetricsConfig {
+    pub token: Option<String>,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct CorsConfig {
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    pub allowed_headers: Vec<String>,
+    pub max_age: u32,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct HeadersConfig {
+    pub api_csp: String,
+    pub static_csp: String,
+    pub hsts: String,
+    pub referrer_policy: String,
+    pub permissions_policy: String,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebAuthnConfig {
+    pub rp_id: String,
+    pub origin: String,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct MailConfig {
+    // A sendmail-compatible program, given the message on stdin with `-t`. Unset, nothing that needs mail is available.
+    pub sendmail: Option<String>,
+    pub from: String,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct EncryptionConfig {
+    // The master key that wraps new data keys. Retired master keys stay in the key file until re-encryption is done.
+    pub master_key_id: String,
+    // Stand-in for a KMS: a TOML file of `<master key id> = "<base64 of 32 random bytes>"`, readable by the owner only
+    pub master_key_file: Option<String>,
+    // The current master key itself (base64 of 32 random bytes), for deployments that only pass secrets as environment
+    // variables. Never the database password: whoever can read the connection config must not be able to decrypt
+    pub master_key: Option<String>,
+}
+
+/// Loads and validates the configuration. Must run once, before anything reads it.
+pub fn init() -> Result<&'static Config, String> {
+    let config = load()?;
+    config.validate()?;
+    CONFIG
+        .set(config)
+        .map_err(|_| "Configuration was already initialized".to_string())?;
+    Ok(get())
+}
+
+pub fn get() -> &'static Config {
+    CONFIG
+        .get()
+        .expect("config::init must run before the configuration is used")
+}
+
+fn load() -> Result<Config, String> {
+    // Keeps supporting a local .env file (e.g. DATABASE_URL shared with the diesel CLI)
+    dotenv::dotenv().ok();
+
+    let profile: Profile = env::var("KEYPOST_PROFILE")
+        .unwrap_or_else(|_| Profile::Prod.as_str().to_string())
+        .parse()?;
+
+    let path = env::var("KEYPOST_CONFIG").unwrap_or_else(|_| DEFAULT_CONFIG_FILE.to_string());
+    let file = if Path::new(&path).exists() {
+        let contents =
+            fs::read_to_string(&path).map_err(|err| format!("Could not read {}: {}", path, err))?;
+        let file: Value = contents
+            .parse()
+            .map_err(|err| format!("Could not parse {}: {}", path, err))?;
+        Some(file)
+    } else if env::var("KEYPOST_CONFIG").is_ok() {
+        return Err(format!("Config file {} does not exist", path));
+    } else {
+        None
+    };
+
+    let mut config = resolve(profile, file.as_ref())?;
+    config.apply_env()?;
+    Ok(config)
+}
+
+// The profile's defaults with the file's `[default]` and then `[<profile>]` tables merged over them
+fn resolve(profile: Profile, file: Option<&Value>) -> Result<Config, String> {
+    let mut merged = Value::try_from(Config::defaults(profile))
+        .map_err(|err| format!("Could not build default configuration: {}", err))?;
+    if let Some(file) = file {
+        for table in ["default", profile.as_str()] {
+            if let Some(overrides) = file.get(table) {
+                merge(&mut merged, overrides.clone());
+            }
+        }
+    }
+    let mut config: Config = merged
+        .try_into()
+        .map_err(|err| format!("Invalid configuration: {}", err))?;
+    // The file can't switch profiles, the defaults were already picked for this one
+    config.profile = profile;
+    Ok(config)
+}
+
+impl Config {
+    fn defaults(profile: Profile) -> Config {
+        let prod_like = profile != Profile::Dev;
+        Config {
+            profile,
+            data_dir: default_data_dir(),
+            server: ServerConfig {
+                address: match prod_like {
+                    true => "0.0.0.0".to_string(),
+                    false => "localhost".to_string(),
+                },
+                port: 8000,
+                internal_port: 8001,
+                tls: None,
+            },
+            database: DatabaseConfig {
+                url: String::new(),
+                migrations: "apply".to_string(),
+            },
+            cache: CacheConfig {
+                backend: "memory".to_string(),
+            },
+            sessions: SessionConfig {
+                idle_timeout_secs: 15 * 60,
+                max_lifetime_secs: 12 * 60 * 60,
+                reauth_window_secs: 5 * 60,
+                email_change_ttl_secs: 15 * 60,
+                pending_ttl_secs: 5 * 60,
+            },
+            rate_limits: RateLimitConfig {
+                auth_per_minute: match prod_like {
+                    true => 30,
+                    false => 0,
+                },
+            },
+            log: LogConfig {
+                filter: "info".to_string(),
+                format: match prod_like {
+                    true => "json".to_string(),
+                    false => "text".to_string(),
+                },
+            },
+            metrics: MetricsConfig { token: None },
+            cors: CorsConfig {
+                allowed_origins: vec![],
+                allowed_methods: vec!["GET".to_string(), "POST".to_string()],
+                allowed_headers: vec!["Authorization".to_string(), "Content-Type".to_string()],
+                max_age: 600,
+            },
+            headers: HeadersConfig {
+                api_csp: "default-src 'none'; frame-ancestors 'none'".to_string(),
+                // The webpack bundle's style-loader injects <style> tags, hence the inline styles
+                static_csp:
+                    "default-src 'self'; script-src 'self'; style-src 'self' 'unsafe-inline'; \
+                     img-src 'self' data:; connect-src 'self'; object-src 'none'; base-uri 'self'; \
+                     form-action 'self'; frame-ancestors 'none'"
+                        .to_string(),
+                hsts: match prod_like {
+                    true => "max-age=63072000; includeSubDomains".to_string(),
+                    false => String::new(),
+                },
+                referrer_policy: "no-referrer".to_string(),
+                permissions_policy: "camera=(), microphone=(), geolocation=(), payment=()"
+                    .to_string(),
+            },
+            webauthn: WebAuthnConfig {
+                rp_id: "localhost".to_string(),
+                origin: "http://localhost:8000".to_string(),
+            },
+            mail: MailConfig {
+                sendmail: None,
+                from: "keypost@localhost".to_string(),
+            },
+            encryption: EncryptionConfig {
+                master_key_id: "default".to_string(),
+                master_key_file: None,
+                master_key: None,
+            },
+        }
+    }
+
+    // Variable names predate the config file and are kept as they are
+    fn apply_env(&mut self) -> Result<(), String> {
+        override_string("KEYPOST_DATA_DIR", &mut self.data_dir);
+        override_string("KEYPOST_ADDRESS", &mut self.server.address);
+        override_parsed("KEYPOST_PORT", &mut self.server.port)?;
+        override_parsed("KEYPOST_INTERNAL_PORT", &mut self.server.internal_port)?;
+        if let (Ok(certs), Ok(key)) = (env::var("KEYPOST_TLS_CERTS"), env::var("KEYPOST_TLS_KEY")) {
+            let client_ca = self.server.tls.take().and_then(|tls| tls.client_ca);
+            self.server.tls = Some(TlsConfig {
+                certs,
+                key,
+                client_ca,
+            });
+        }
+        if let (Some(tls), Ok(client_ca)) =
+            (&mut self.server.tls, env::var("KEYPOST_TLS_CLIENT_CA"))
+        {
+            tls.client_ca = Some(client_ca).filter(|client_ca| !client_ca.is_empty());
+        }
+        override_string("DATABASE_URL", &mut self.database.url);
+        override_string("KEYPOST_DB_MIGRATIONS", &mut self.database.migrations);
+        override_string("KEYPOST_CACHE_BACKEND", &mut self.cache.backend);
+        override_parsed(
+            "KEYPOST_SESSION_IDLE_TIMEOUT_SECS",
+            &mut self.sessions.idle_timeout_secs,
+        )?;
+        override_parsed(
+            "KEYPOST_SESSION_MAX_LIFETIME_SECS",
+            &mut self.sessions.max_lifetime_secs,
+        )?;
+        override_parsed(
+            "KEYPOST_REAUTH_WINDOW_SECS",
+            &mut self.sessions.reauth_window_secs,
+        )?;
+        override_parsed(
+            "KEYPOST_EMAIL_CHANGE_TTL_SECS",
+            &mut self.sessions.email_change_ttl_secs,
+        )?;
+        override_parsed(
+            "KEYPOST_PENDING_TTL_SECS",
+            &mut self.sessions.pending_ttl_secs,
+        )?;
+        override_parsed(
+            "KEYPOST_RATE_LIMIT_AUTH_PER_MINUTE",
+            &mut self.rate_limits.auth_per_minute,
+        )?;
+        override_string("KEYPOST_LOG", &mut self.log.filter);
+        override_string("KEYPOST_LOG_FORMAT", &mut self.log.format);
+        if let Ok(token) = env::var("KEYPOST_METRICS_TOKEN") {
+            self.metrics.token = Some(token).filter(|token| !token.is_empty());
+        }
+        override_list(
+            "KEYPOST_CORS_ALLOWED_ORIGINS",
+            &mut self.cors.allowed_origins,
+        );
+        override_list(
+            "KEYPOST_CORS_ALLOWED_METHODS",
+            &mut self.cors.allowed_methods,
+        );
+        override_list(
+            "KEYPOST_CORS_ALLOWED_HEADERS",
+            &mut self.cors.allowed_headers,
+        );
+        override_parsed("KEYPOST_CORS_MAX_AGE", &mut self.cors.max_age)?;
+        override_string("KEYPOST_API_CSP", &mut self.headers.api_csp);
+        override_string("KEYPOST_STATIC_CSP", &mut self.headers.static_csp);
+        override_string("KEYPOST_HSTS", &mut self.headers.hsts);
+        override_string("KEYPOST_REFERRER_POLICY", &mut self.headers.referrer_policy);
+        override_string(
+            "KEYPOST_PERMISSIONS_POLICY",
+            &mut self.headers.permissions_policy,
+        );
+        override_string("KEYPOST_WEBAUTHN_RP_ID", &mut self.webauthn.rp_id);
+        override_string("KEYPOST_WEBAUTHN_ORIGIN", &mut self.webauthn.origin);
+        if let Ok(sendmail) = env::var("KEYPOST_SENDMAIL") {
+            self.mail.sendmail = Some(sendmail).filter(|sendmail| !sendmail.is_empty());
+        }
+        override_string("KEYPOST_MAIL_FROM", &mut self.mail.from);
+        override_string("KEYPOST_MASTER_KEY_ID", &mut self.encryption.master_key_id);
+        if let Ok(file) = env::var("KEYPOST_MASTER_KEY_FILE") {
+            self.encryption.master_key_file = Some(file).filter(|file| !file.is_empty());
+        }
+        if let Ok(key) = env::var("KEYPOST_MASTER_KEY") {
+            self.encryption.master_key = Some(key).filter(|key| !key.is_empty());
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let prod_like = self.profile != Profile::Dev;
+        if !Path::new(&self.data_dir).is_absolute() {
+            return Err(format!(
+                "data_dir must be an absolute path, got `{}` (set it in the config file or KEYPOST_DATA_DIR)",
+                self.data_dir
+            ));
+        }
+        if !(self.database.url.starts_with("postgres://")
+            || self.database.url.starts_with("postgresql://"))
+        {
+            return Err("database.url (or DATABASE_URL) must be a postgres:// URL".to_string());
+        }
+        if !["apply", "check"].contains(&self.database.migrations.as_str()) {
+            return Err(format!(
+                "database.migrations must be `apply` or `check`, got `{}`",
+                self.database.migrations
+            ));
+        }
+        if self.cache.backend != "memory" {
+            return Err(format!(
+                "Unsupported cache.backend `{}`, only `memory` is available",
+                self.cache.backend
+            ));
+        }
+        let sessions = &self.sessions;
+        if sessions.idle_timeout_secs == 0
+            || sessions.reauth_window_secs == 0
+            || sessions.email_change_ttl_secs == 0
+            || sessions.pending_ttl_secs == 0
+            || sessions.max_lifetime_secs < sessions.idle_timeout_secs
+        {
+            return Err(
+                "sessions: timeouts must be positive and max_lifetime_secs at least idle_timeout_secs"
+                    .to_string(),
+            );
+        }
+        if !["json", "text"].contains(&self.log.format.as_str()) {
+            return Err(format!(
+                "Unknown log.format `{}`, expected json or text",
+                self.log.format
+            ));
+        }
+        if let Some(token) = &self.metrics.token {
+            if token.len() < 16 {
+                return Err("metrics.token must be at least 16 characters".to_string());
+            }
+        }
+        for origin in &self.cors.allowed_origins {
+            if origin == "*" && prod_like {
+                return Err(format!(
+                    "cors.allowed_origins can't be `*` in the {} profile",
+                    self.profile.as_str()
+                ));
+            }
+            if origin != "*" && !is_origin(origin) {
+                return Err(format!(
+                    "cors.allowed_origins entry `{}` is not an origin (scheme://host[:port])",
+                    origin
+                ));
+            }
+        }
+        if !is_origin(&self.webauthn.origin) {
+            return Err(format!(
+                "webauthn.origin `{}` is not an origin",
+                self.webauthn.origin
+            ));
+        }
+        let host = origin_host(&self.webauthn.origin);
+        if host != self.webauthn.rp_id && !host.ends_with(&format!(".{}", self.webauthn.rp_id)) {
+            return Err(format!(
+                "webauthn.rp_id `{}` must be the origin's host or a parent domain of it",
+                self.webauthn.rp_id
+            ));
+        }
+        // Browsers only allow WebAuthn on plain http for localhost
+        if prod_like && !self.webauthn.origin.starts_with("https://") && host != "localhost" {
+            return Err("webauthn.origin must be https".to_string());
+        }
+        if let Some(sendmail) = &self.mail.sendmail {
+            if !Path::new(sendmail).is_absolute() {
+                return Err(format!(
+                    "mail.sendmail must be an absolute path, got `{}`",
+                    sendmail
+                ));
+            }
+        }
+        if !mail::is_valid_address(&self.mail.from) {
+            return Err(format!(
+                "mail.from `{}` is not an email address",
+                self.mail.from
+            ));
+        }
+        let encryption = &self.encryption;
+        if encryption.master_key_file.is_none() && encryption.master_key.is_none() {
+            return Err(
+                "No master key: set encryption.master_key_file (KEYPOST_MASTER_KEY_FILE) or KEYPOST_MASTER_KEY"
+                    .to_string(),
+            );
+        }
+        if let Some(key) = &encryption.master_key {
+            persistence::decode_master_key(&encryption.master_key_id, key)?;
+        }
+        if let Some(file) = &encryption.master_key_file {
+            if !Path::new(file).is_file() {
+                return Err(format!("Master key file {} does not exist", file));
+            }
+        }
+        if let Some(tls) = &self.server.tls {
+            for file in [Some(&tls.certs), Some(&tls.key), tls.client_ca.as_ref()]
+                .iter()
+                .flatten()
+            {
+                if !Path::new(file).is_file() {
+                    return Err(format!("TLS file {} does not exist", file));
+                }
+            }
+            if self.server.internal_port == self.server.port {
+                return Err("server.internal_port must differ from server.port".to_string());
+            }
+        }
+        Ok(())
+    }
+}
+
+// Existing installs keep $HOME/.keypost-app, new ones follow the XDG base directory convention.
+// Empty if neither HOME nor XDG_DATA_HOME is set, so validation asks for an explicit data_dir.
+fn default_data_dir() -> String {
+    let home = env::var("HOME").ok().filter(|home| !home.is_empty());
+    if let Some(home) = &home {
+        let legacy = format!("{}/.keypost-app", home);
+        if Path::new(&legacy).is_dir() {
+            return legacy;
+        }
+    }
+    match env::var("XDG_DATA_HOME").ok().filter(|dir| !dir.is_empty()) {
+        Some(data_home) => format!("{}/keypost", data_home),
+        None => home
+            .map(|home| format!("{}/.local/share/keypost", home))
+            .unwrap_or_default(),
+    }
+}
+
+// Tables are merged key by key, anything else is replaced
+fn merge(base: &mut Value, overrides: Value) {
+    match (base, overrides) {
+        (Value::Table(base), Value::Table(overrides)) => {
+            for (key, value) in overrides {
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, value) => *base = value,
+    }
+}
+
+fn override_string(var: &str, field: &mut String) {
+    if let Ok(value) = env::var(var) {
+        *field = value;
+    }
+}
+
+fn override_parsed<T: FromStr>(var: &str, field: &mut T) -> Result<(), String> {
+    if let Ok(value) = env::var(var) {
+        *field = value
+            .parse()
+            .map_err(|_| format!("{} has an invalid value `{}`", var, value))?;
+    }
+    Ok(())
+}
+
+fn override_list(var: &str, field: &mut Vec<String>) {
+    if let Ok(value) = env::var(var) {
+        *field = value
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(String::from)
+            .collect();
+    }
+}
+
+fn is_origin(value: &str) -> bool {
+    let rest = match value
+        .strip_prefix("https://")
+        .or_else(|| value.strip_prefix("http://"))
+    {
+        Some(rest) => rest,
+        None => return false,
+    };
+    !rest.is_empty() && !rest.contains('/')
+}
+
+fn origin_host(origin: &str) -> &str {
+    let rest = origin.splitn(2, "://").nth(1).unwrap_or_default();
+    rest.split(':').next().unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILE: &str = r#"
+        [default.server]
+        port = 9000
+
+        [default.log]
+        filter = "debug"
+
+        [dev.server]
+        port = 9100
+
+        [prod.log]
+        format = "text"
+    "#;
+
+    fn valid(profile: Profile) -> Config {
+        let mut config = Config::defaults(profile);
+        config.data_dir = "/var/lib/keypost".to_string();
+        config.database.url = "postgres://keypost@localhost/keypost".to_string();
+        config.encryption.master_key = Some(base64::encode([1u8; 32]));
+        config
+    }
+
+    #[test]
+    fn profile_table_overrides_default_table() {
+        let file: Value = FILE.parse().unwrap();
+        let dev = resolve(Profile::Dev, Some(&file)).unwrap();
+        assert_eq!(dev.server.port, 9100);
+        assert_eq!(dev.server.address, "localhost");
+        assert_eq!(dev.log.filter, "debug");
+        assert_eq!(dev.log.format, "text");
+
+        let prod = resolve(Profile::Prod, Some(&file)).unwrap();
+        assert_eq!(prod.server.port, 9000);
+        assert_eq!(prod.server.address, "0.0.0.0");
+        assert_eq!(prod.log.format, "text");
+        // Untouched tables keep the profile's defaults
+        assert_eq!(prod.rate_limits.auth_per_minute, 30);
+        assert_eq!(resolve(Profile::Staging, None).unwrap().log.format, "json");
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        let file: Value = "[default.server]\nprot = 9000".parse().unwrap();
+        assert!(resolve(Profile::Prod, Some(&file)).is_err());
+    }
+
+    #[test]
+    fn validate_accepts_complete_config() {
+        for profile in [Profile::Dev, Profile::Staging, Profile::Prod] {
+            assert_eq!(valid(profile).validate(), Ok(()));
+        }
+    }
+
+    #[test]
+    fn validate_rejects_invalid_values() {
+        let invalid: Vec<fn(&mut Config)> = vec![
+            |config| config.data_dir = "relative/dir".to_string(),
+            |config| config.database.url = "mysql://localhost/keypost".to_string(),
+            |config| config.database.migrations = "skip".to_string(),
+            |config| config.sessions.max_lifetime_secs = config.sessions.idle_timeout_secs - 1,
+            |config| config.log.format = "xml".to_string(),
+            |config| config.metrics.token = Some("short".to_string()),
+            |config| config.cors.allowed_origins = vec!["https://app.example/path".to_string()],
+            |config| config.webauthn.rp_id = "example.com".to_string(),
+            |config| config.encryption.master_key = Some(base64::encode([1u8; 16])),
+            |config| config.encryption.master_key = None,
+        ];
+        for (i, make_invalid) in invalid.iter().enumerate() {
+            let mut config = valid(Profile::Prod);
+            make_invalid(&mut config);
+            assert!(config.validate().is_err(), "case {}", i);
+        }
+    }
+
+    #[test]
+    fn validate_is_stricter_outside_dev() {
+        let mut dev = valid(Profile::Dev);
+        dev.cors.allowed_origins = vec!["*".to_string()];
+        dev.webauthn.rp_id = "keypost.example".to_string();
+        dev.webauthn.origin = "http://keypost.example".to_string();
+        assert_eq!(dev.validate(), Ok(()));
+
+        let mut prod = valid(Profile::Prod);
+        prod.cors.allowed_origins = vec!["*".to_string()];
+        assert!(prod.validate().is_err());
+        let mut prod = valid(Profile::Prod);
+        prod.webauthn.rp_id = "keypost.example".to_string();
+        prod.webauthn.origin = "http://keypost.example".to_string();
+        assert!(prod.validate().is_err());
+    }
+}
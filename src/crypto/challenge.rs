@@ -0,0 +1,128 @@
+use std::str::FromStr;
+use subtle::ConstantTimeEq;
+
+use crate::api::ApiError;
+
+// https://datatracker.ietf.org/doc/html/rfc7636#section-4.1
+const MIN_VERIFIER_LEN: usize = 43;
+const MAX_VERIFIER_LEN: usize = 128;
+
+/// PKCE code challenge methods. Only S256 is accepted, "plain" is deliberately not supported.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChallengeMethod {
+    S256,
+}
+
+impl ChallengeMethod {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChallengeMethod::S256 => "S256",
+        }
+    }
+
+    fn challenge(&self, verifier: &[u8]) -> String {
+        match self {
+            ChallengeMethod::S256 => pkce::code_challenge(verifier),
+        }
+    }
+}
+
+impl Default for ChallengeMethod {
+    fn default() -> Self {
+        ChallengeMethod::S256
+    }
+}
+
+impl FromStr for ChallengeMethod {
+    type Err = ApiError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "S256" => Ok(ChallengeMethod::S256),
+            _ => Err(ApiError::InvalidRequest {
+                expected: "code challenge method S256".to_string(),
+            }),
+        }
+    }
+}
+
+// The method is cached alongside the challenge between /register/start and /register/finish
+pub fn to_cache_entry(method: ChallengeMethod, challenge: &str) -> String {
+    format!("{}:{}", method.as_str(), challenge)
+}
+
+pub fn from_cache_entry(entry: &str) -> Result<(ChallengeMethod, &str), ApiError> {
+    let (method, challenge) = entry.split_once(':').ok_or(ApiError::ServerError)?;
+    Ok((method.parse()?, challenge))
+}
+
+pub fn validate_code_verifier(verifier: &[u8]) -> Result<(), ApiError> {
+    let valid_length = (MIN_VERIFIER_LEN..=MAX_VERIFIER_LEN).contains(&verifier.len());
+    let valid_charset = verifier
+        .iter()
+        .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_' | b'~'));
+    if valid_length && valid_charset {
+        Ok(())
+    } else {
+        Err(ApiError::InvalidRequest {
+            expected: "code verifier of 43 to 128 unreserved characters".to_string(),
+        })
+    }
+}
+
+pub fn verify_code_challenge(
+    method: ChallengeMethod,
+    expected_challenge: &str,
+    verifier: &[u8],
+) -> Result<(), ApiError> {
+    validate_code_verifier(verifier)?;
+    let actual_challenge = method.challenge(verifier);
+    match bool::from(
+        expected_challenge
+            .as_bytes()
+            .ct_eq(actual_challenge.as_bytes()),
+    ) {
+        true => Ok(()),
+        false => Err(ApiError::BadNonceOrCodeVerifier),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // https://datatracker.ietf.org/doc/html/rfc7636#appendix-B
+    const RFC_VERIFIER: &[u8] = b"dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk";
+    const RFC_CHALLENGE: &str = "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM";
+
+    #[test]
+    fn verifier_length_bounds() {
+        assert!(validate_code_verifier(&[b'a'; 43]).is_ok());
+        assert!(validate_code_verifier(&[b'a'; 128]).is_ok());
+        assert!(validate_code_verifier(&[b'a'; 42]).is_err());
+        assert!(validate_code_verifier(&[b'a'; 129]).is_err());
+    }
+
+    #[test]
+    fn verifier_charset() {
+        let mut verifier = [b'a'; 43].to_vec();
+        verifier[..4].copy_from_slice(b"-._~");
+        assert!(validate_code_verifier(&verifier).is_ok());
+        for invalid in [b'+', b'/', b'=', b' ', 0xc3] {
+            verifier[0] = invalid;
+            assert!(validate_code_verifier(&verifier).is_err());
+        }
+    }
+
+    #[test]
+    fn s256_matches_rfc_vector() {
+        assert_eq!(ChallengeMethod::S256.challenge(RFC_VERIFIER), RFC_CHALLENGE);
+        assert!(verify_code_challenge(ChallengeMethod::S256, RFC_CHALLENGE, RFC_VERIFIER).is_ok());
+        let mut wrong_verifier = RFC_VERIFIER.to_vec();
+        wrong_verifier[0] = b'e';
+        assert!(matches!(
+            verify_code_challenge(ChallengeMethod::S256, RFC_CHALLENGE, &wrong_verifier),
+            Err(ApiError::BadNonceOrCodeVerifier)
+        ));
+    }
+}
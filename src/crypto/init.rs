@@ -1,6 +1,19 @@
+use std::path::Path;
+
+use crate::crypto;
 use crate::util;
 
+/// Prepares the data directory and refuses to continue if it or the key files in it are readable by others.
 pub fn init() -> Result<(), std::io::Error> {
     let app_dir = util::default_dir();
-    util::create_directory(&app_dir)
+    util::create_directory(&app_dir)?;
+    for file in [crypto::SERVER_SETUP_FILE, crypto::SERVER_KEY_FILE] {
+        let path = format!("{}/{}", app_dir, file);
+        if Path::new(&path).exists() {
+            util::check_private_permissions(&path)?;
+        }
+    }
+    crypto::load_server_setup();
+    crypto::load_server_key();
+    Ok(())
 }
@@ -0,0 +1,41 @@
+use hkdf::Hkdf;
+use sha2::Sha256;
+use zeroize::Zeroizing;
+
+/// Every use of a session key gets its own HKDF sub-key so that no two purposes ever share a key (and therefore a key+nonce pair).
+#[derive(Clone, Copy, Debug)]
+pub enum KeyPurpose {
+    LoginVerify,
+    SessionId,
+    LockerTransport,
+    TotpSecret,
+    DataKeyWrap,
+    UserPasswordFile,
+    UserRecoveryPasswordFile,
+    LockerPasswordFile,
+    LockerCiphertext,
+}
+
+impl KeyPurpose {
+    fn info(&self) -> &'static [u8] {
+        match self {
+            KeyPurpose::LoginVerify => b"keypost login verify v1",
+            KeyPurpose::SessionId => b"keypost session id v1",
+            KeyPurpose::LockerTransport => b"keypost locker transport v1",
+            KeyPurpose::TotpSecret => b"keypost totp secret v1",
+            KeyPurpose::DataKeyWrap => b"keypost data key wrap v1",
+            KeyPurpose::UserPasswordFile => b"keypost user password file v1",
+            KeyPurpose::UserRecoveryPasswordFile => b"keypost user recovery password file v1",
+            KeyPurpose::LockerPasswordFile => b"keypost locker password file v1",
+            KeyPurpose::LockerCiphertext => b"keypost locker ciphertext v1",
+        }
+    }
+}
+
+pub fn derive_subkey(key: &[u8], purpose: KeyPurpose) -> Zeroizing<[u8; 32]> {
+    let hkdf = Hkdf::<Sha256>::new(None, key);
+    let mut subkey = Zeroizing::new([0u8; 32]);
+    hkdf.expand(purpose.info(), &mut subkey[..])
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    subkey
+}
@@ -1,44 +1,105 @@
+mod challenge;
 mod init;
+mod kdf;
 mod opaque;
+mod server_key;
+mod totp;
+mod webauthn;
 
+pub use challenge::*;
 pub use init::*;
+pub use kdf::*;
 pub use opaque::*;
+pub use server_key::*;
+pub use totp::*;
+pub use webauthn::*;
 
-use chacha20poly1305::aead::{Aead, NewAead};
-use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce, XChaCha20Poly1305, XNonce};
 use opaque_ke::rand::rngs::OsRng;
 use opaque_ke::rand::RngCore;
+use zeroize::Zeroizing;
 
-pub fn encrypt_bytes(nonce: &[u8], key: &[u8], plaintext: &[u8]) -> Vec<u8> {
-    encrypt(&nonce[..12], key, plaintext).expect("Could not encrypt bytes!")
+// The sub-key for `purpose` must only ever encrypt a single message (e.g. one login_finish or login_verify per session key),
+// which is what makes the fixed zero nonce safe. The client derives the same sub-key to reproduce the ciphertext.
+pub fn encrypt_once(key: &[u8], purpose: KeyPurpose, plaintext: &[u8]) -> Vec<u8> {
+    let subkey = derive_subkey(key, purpose);
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(&subkey[..]));
+    cipher
+        .encrypt(Nonce::from_slice(&[0u8; 12]), plaintext)
+        .expect("Could not encrypt bytes!")
 }
 
-pub fn encrypt_bytes_with_u32_nonce(u32_nonce: &u32, key: &[u8], plaintext: &[u8]) -> Vec<u8> {
-    let nonce = expand_u32_nonce(u32_nonce);
-    encrypt_bytes(&nonce, key, plaintext)
+// Given a key and plaintext, produce an AEAD ciphertext under the `purpose` sub-key, prefixed with a random 192-bit nonce
+pub fn seal(key: &[u8], purpose: KeyPurpose, plaintext: &[u8]) -> Vec<u8> {
+    seal_with_aad(key, purpose, plaintext, &[])
 }
 
-// Given a key and plaintext, produce an AEAD ciphertext along with a nonce
-pub fn encrypt_locker(key: &[u8], plaintext: &[u8]) -> Vec<u8> {
+// Like seal(), additionally binding the ciphertext to `aad`: open_with_aad() only succeeds with the same bytes
+pub fn seal_with_aad(key: &[u8], purpose: KeyPurpose, plaintext: &[u8], aad: &[u8]) -> Vec<u8> {
     let mut rng = OsRng;
-    let mut nonce_bytes = [0u8; 12];
+    let mut nonce_bytes = [0u8; 24];
     rng.fill_bytes(&mut nonce_bytes);
 
-    let ciphertext = encrypt(&nonce_bytes, key, plaintext).expect("Could not encrypt locker!");
+    let subkey = derive_subkey(key, purpose);
+    let cipher = XChaCha20Poly1305::new(Key::from_slice(&subkey[..]));
+    let ciphertext = cipher
+        .encrypt(
+            XNonce::from_slice(&nonce_bytes),
+            Payload {
+                msg: plaintext,
+                aad,
+            },
+        )
+        .expect("Could not seal bytes!");
     [nonce_bytes.to_vec(), ciphertext].concat()
 }
 
+// Reverses seal(), i.e. splits off the 192-bit nonce and decrypts under the `purpose` sub-key
+pub fn open(
+    key: &[u8],
+    purpose: KeyPurpose,
+    sealed: &[u8],
+) -> Result<Zeroizing<Vec<u8>>, chacha20poly1305::aead::Error> {
+    open_with_aad(key, purpose, sealed, &[])
+}
+
+pub fn open_with_aad(
+    key: &[u8],
+    purpose: KeyPurpose,
+    sealed: &[u8],
+    aad: &[u8],
+) -> Result<Zeroizing<Vec<u8>>, chacha20poly1305::aead::Error> {
+    if sealed.len() < 24 {
+        return Err(chacha20poly1305::aead::Error);
+    }
+    let (nonce_bytes, ciphertext) = sealed.split_at(24);
+    let subkey = derive_subkey(key, purpose);
+    let cipher = XChaCha20Poly1305::new(Key::from_slice(&subkey[..]));
+    cipher
+        .decrypt(
+            XNonce::from_slice(nonce_bytes),
+            Payload {
+                msg: ciphertext,
+                aad,
+            },
+        )
+        .map(Zeroizing::new)
+}
+
+pub fn encrypt_locker(key: &[u8], plaintext: &[u8]) -> Vec<u8> {
+    seal(key, KeyPurpose::LockerTransport, plaintext)
+}
+
 pub fn create_nonce() -> u32 {
     rand::random::<u32>()
 }
 
-pub fn expand_u32_nonce(u32_nonce: &u32) -> Vec<u8> {
-    [
-        u32_nonce.to_be_bytes(),
-        u32_nonce.to_be_bytes(),
-        u32_nonce.to_be_bytes(),
-    ]
-    .concat()
+pub fn create_session_id() -> Vec<u8> {
+    let mut rng = OsRng;
+    let mut bytes = [0u8; 32];
+    rng.fill_bytes(&mut bytes);
+    bytes.to_vec()
 }
 
 pub fn rand_bytes() -> Vec<u8> {
@@ -47,13 +108,3 @@ pub fn rand_bytes() -> Vec<u8> {
     rng.fill_bytes(&mut bytes);
     bytes.to_vec()
 }
-
-fn encrypt(
-    nonce_bytes: &[u8],
-    key: &[u8],
-    plaintext: &[u8],
-) -> Result<Vec<u8>, chacha20poly1305::aead::Error> {
-    let cipher = ChaCha20Poly1305::new(Key::from_slice(&key[..32]));
-    let nonce = Nonce::from_slice(nonce_bytes);
-    cipher.encrypt(nonce, plaintext.as_ref())
-}
@@ -1,3 +1,4 @@
+use std::io::ErrorKind;
 use std::sync::Mutex;
 
 use opaque_ke::errors::ProtocolError;
@@ -6,34 +7,42 @@ use opaque_ke::{
     Identifiers, RegistrationRequest, RegistrationUpload, ServerLogin, ServerLoginStartParameters,
     ServerLoginStartResult, ServerRegistration, ServerRegistrationStartResult, ServerSetup,
 };
+use tracing::{debug, error};
+use zeroize::Zeroizing;
 
 use crate::api::ApiError;
 use crate::cache;
 use crate::crypto;
-use crate::persistence;
+use crate::metrics;
 use crate::util;
 
+pub const SERVER_SETUP_FILE: &str = "server_setup.private";
+
 lazy_static! {
     static ref SERVER_SETUP: Mutex<ServerSetup<DefaultCipherSuite>> = {
-        let server_setup_location = util::default_dir() + "/server_setup.private";
+        let server_setup_location = format!("{}/{}", util::default_dir(), SERVER_SETUP_FILE);
         let server_setup = match util::read_file(&server_setup_location) {
             Ok(bytes) => {
-                println!("DEBUG: Found server_setup file");
+                debug!("Found server_setup file");
                 ServerSetup::<DefaultCipherSuite>::deserialize(&bytes).unwrap_or_else(|err| {
-                    println!("ERROR: {:?}", err);
+                    error!(error = ?err, "Could not deserialize server_setup");
                     panic!(
                         "Could not deserialize bytes from file {}",
                         &server_setup_location
                     )
                 })
             }
+            // Any other error must not lead to a new ServerSetup, that would lock every user out
+            Err(err) if err.kind() != ErrorKind::NotFound => {
+                panic!("Could not read server_setup file {}: {}", &server_setup_location, err)
+            }
             Err(err) => {
-                println!("DEBUG: Could not find server_setup file - error: {:?}", err);
+                debug!(error = ?err, "Could not find server_setup file");
                 let mut server_rng = OsRng;
                 let server_setup = ServerSetup::<DefaultCipherSuite>::new(&mut server_rng);
                 util::write_to_file(&server_setup_location, &server_setup.serialize())
                     .unwrap_or_else(|err| {
-                        println!("ERROR: {:?}", err);
+                        error!(error = ?err, "Could not write server_setup");
                         panic!(
                             "Could not write server_setup file to {}",
                             &server_setup_location
@@ -46,6 +55,15 @@ lazy_static! {
     };
 }
 
+/// Loads (or creates) the ServerSetup now instead of on the first request, so a bad file stops startup.
+pub fn load_server_setup() {
+    lazy_static::initialize(&SERVER_SETUP);
+}
+
+pub fn server_setup_ready() -> bool {
+    !SERVER_SETUP.is_poisoned()
+}
+
 // The CipherSuite trait allows to specify the underlying primitives
 // that will be used in the OPAQUE protocol
 pub struct DefaultCipherSuite;
@@ -69,47 +87,50 @@ pub fn server_side_registration_start(
     registration_request_base64: &str,
     email: &str,
 ) -> Result<ServerRegistrationStartResult<DefaultCipherSuite>, ApiError> {
-    let registration_request_bytes =
-        base64::decode(registration_request_base64).map_err(ApiError::BadRequestDecode)?;
+    let registration_request_bytes = decode("registration_start", registration_request_base64)?;
 
     let server_setup = SERVER_SETUP.lock().map_err(|_e| ApiError::ServerError)?;
     // FIXME https://doc.rust-lang.org/stable/std/sync/struct.Mutex.html#poisoning
 
     let registration_request = RegistrationRequest::deserialize(&registration_request_bytes[..])
-        .map_err(|_e| ApiError::BadRequestProtocol)?;
+        .map_err(bad_request("registration_start"))?;
     ServerRegistration::<DefaultCipherSuite>::start(
         &server_setup,
         registration_request,
         email.as_bytes(),
     )
-    .map_err(|_e| ApiError::UnknownError)
+    .map_err(|err| {
+        failure("registration_start", err);
+        ApiError::UnknownError
+    })
 }
 
-pub fn server_side_registration_finish(client_message_base64: &str) -> Vec<u8> {
-    let client_message_bytes =
-        base64::decode(client_message_base64).expect("Could not perform base64 decode");
-    let password_file = ServerRegistration::finish(
-        RegistrationUpload::<DefaultCipherSuite>::deserialize(&client_message_bytes[..]).unwrap(),
-    );
-    password_file.serialize().to_vec()
+pub fn server_side_registration_finish(client_message_base64: &str) -> Result<Vec<u8>, ApiError> {
+    let client_message_bytes = decode("registration_finish", client_message_base64)?;
+    let registration_upload =
+        RegistrationUpload::<DefaultCipherSuite>::deserialize(&client_message_bytes[..])
+            .map_err(bad_request("registration_finish"))?;
+    let password_file = ServerRegistration::finish(registration_upload);
+    Ok(password_file.serialize().to_vec())
 }
 
 pub fn login_start(
     email: &str,
     password_file_bytes: &[u8],
     credential_request_base64: &str,
-) -> ServerLoginStartResult<DefaultCipherSuite> {
-    let credential_request_bytes =
-        base64::decode(credential_request_base64).expect("Could not perform base64 decode");
-    let password_file =
-        ServerRegistration::<DefaultCipherSuite>::deserialize(password_file_bytes).unwrap();
+) -> Result<ServerLoginStartResult<DefaultCipherSuite>, ApiError> {
+    let credential_request_bytes = decode("login_start", credential_request_base64)?;
+    let credential_request = CredentialRequest::deserialize(&credential_request_bytes[..])
+        .map_err(bad_request("login_start"))?;
+    let password_file = ServerRegistration::<DefaultCipherSuite>::deserialize(password_file_bytes)
+        .map_err(server_error("login_start"))?;
     let mut server_rng = OsRng;
-    let server_setup = SERVER_SETUP.lock().unwrap();
+    let server_setup = SERVER_SETUP.lock().map_err(|_e| ApiError::ServerError)?;
     ServerLogin::start(
         &mut server_rng,
         &server_setup,
         Some(password_file),
-        CredentialRequest::deserialize(&credential_request_bytes[..]).unwrap(),
+        credential_request,
         email.as_bytes(),
         ServerLoginStartParameters {
             context: None,
@@ -119,33 +140,38 @@ pub fn login_start(
             },
         },
     )
-    .unwrap()
+    .map_err(bad_request("login_start"))
 }
 
 pub fn login_finish(
     server_login_bytes: &[u8],
     credential_finalization_base64: &str,
-) -> Result<Vec<u8>, ProtocolError> {
-    let credential_finalization_bytes =
-        base64::decode(credential_finalization_base64).expect("Could not perform base64 deocde");
-    let server_login = ServerLogin::<DefaultCipherSuite>::deserialize(server_login_bytes).unwrap();
-    let r = server_login.finish(CredentialFinalization::deserialize(
-        &credential_finalization_bytes[..],
-    )?)?;
-    Ok(r.session_key.to_vec())
+) -> Result<Zeroizing<Vec<u8>>, ApiError> {
+    let credential_finalization_bytes = decode("login_finish", credential_finalization_base64)?;
+    let server_login = ServerLogin::<DefaultCipherSuite>::deserialize(server_login_bytes)
+        .map_err(server_error("login_finish"))?;
+    let credential_finalization =
+        CredentialFinalization::deserialize(&credential_finalization_bytes[..])
+            .map_err(bad_request("login_finish"))?;
+    let r = server_login
+        .finish(credential_finalization)
+        .map_err(bad_request("login_finish"))?;
+    Ok(Zeroizing::new(r.session_key.to_vec()))
 }
 
 pub fn register_locker_start(
     locker_id: &str,
     registration_request_bytes: &[u8],
-) -> Result<String, ProtocolError> {
-    let server_setup = SERVER_SETUP.lock().unwrap();
+) -> Result<String, ApiError> {
+    let registration_request = RegistrationRequest::deserialize(registration_request_bytes)
+        .map_err(bad_request("register_locker_start"))?;
+    let server_setup = SERVER_SETUP.lock().map_err(|_e| ApiError::ServerError)?;
     let server_registration_start_result = ServerRegistration::<DefaultCipherSuite>::start(
         &server_setup,
-        RegistrationRequest::deserialize(registration_request_bytes)?,
+        registration_request,
         locker_id.as_bytes(),
     )
-    .unwrap();
+    .map_err(bad_request("register_locker_start"))?;
     let registration_response_bytes = server_registration_start_result
         .message
         .serialize()
@@ -153,19 +179,13 @@ pub fn register_locker_start(
     Ok(base64::encode(registration_response_bytes))
 }
 
-pub fn register_locker_finish(
-    locker_id: &str,
-    email: &str,
-    message: &[u8],
-    ciphertext: &[u8],
-) -> Result<(), ProtocolError> {
+/// Returns the locker's password file, for the caller to store along with its contents.
+pub fn register_locker_finish(message: &[u8]) -> Result<Vec<u8>, ProtocolError> {
     let server_registration: ServerRegistration<DefaultCipherSuite> = ServerRegistration::finish(
-        RegistrationUpload::<DefaultCipherSuite>::deserialize(message)?,
+        RegistrationUpload::<DefaultCipherSuite>::deserialize(message)
+            .map_err(|err| failure("register_locker_finish", err))?,
     );
-    let password_file = server_registration.serialize().to_vec();
-    persistence::store_locker_contents(email, locker_id, &password_file, ciphertext)
-        .unwrap_or_else(|err| panic!("Could not store locker {} contents: {:?}", locker_id, err));
-    Ok(())
+    Ok(server_registration.serialize().to_vec())
 }
 
 //Also used for delete
@@ -174,27 +194,27 @@ pub fn open_locker_start(
     credential_request_bytes: &[u8],
     locker_password_file: &[u8],
     nonce: u32,
-) -> Result<String, ProtocolError> {
-    let server_setup = SERVER_SETUP.lock().unwrap();
-    let password_file =
-        ServerRegistration::<DefaultCipherSuite>::deserialize(locker_password_file).unwrap();
+) -> Result<String, ApiError> {
+    let credential_request = CredentialRequest::deserialize(credential_request_bytes)
+        .map_err(bad_request("open_locker_start"))?;
+    let password_file = ServerRegistration::<DefaultCipherSuite>::deserialize(locker_password_file)
+        .map_err(server_error("open_locker_start"))?;
     let mut server_rng = OsRng;
+    let server_setup = SERVER_SETUP.lock().map_err(|_e| ApiError::ServerError)?;
     let server_login_start_result: ServerLoginStartResult<DefaultCipherSuite> = ServerLogin::start(
         &mut server_rng,
         &server_setup,
         Some(password_file),
-        CredentialRequest::deserialize(credential_request_bytes).unwrap(),
+        credential_request,
         locker_id.as_bytes(),
         ServerLoginStartParameters::default(),
     )
-    .unwrap_or_else(|_| {
-        panic!(
-            "Could not execute ServerLogin::start: {:?}, {:?}",
-            locker_id, nonce
-        )
-    });
+    .map_err(bad_request("open_locker_start"))?;
     let credential_response_bytes = server_login_start_result.message.serialize().to_vec();
-    cache::insert(nonce, server_login_start_result.state.serialize().to_vec());
+    cache::insert(
+        nonce,
+        Zeroizing::new(server_login_start_result.state.serialize().to_vec()),
+    );
     Ok(base64::encode(credential_response_bytes))
 }
 
@@ -204,10 +224,14 @@ pub fn open_locker_finish(
     credential_finalization_bytes: &[u8],
     server_login_bytes: &[u8],
 ) -> Result<Vec<u8>, ProtocolError> {
-    let server_login_state = ServerLogin::<DefaultCipherSuite>::deserialize(server_login_bytes)?;
-    let server_login_finish_result = server_login_state.finish(
-        CredentialFinalization::deserialize(credential_finalization_bytes)?,
-    )?;
+    let server_login_state = ServerLogin::<DefaultCipherSuite>::deserialize(server_login_bytes)
+        .map_err(|err| failure("open_locker_finish", err))?;
+    let credential_finalization =
+        CredentialFinalization::deserialize(credential_finalization_bytes)
+            .map_err(|err| failure("open_locker_finish", err))?;
+    let server_login_finish_result = server_login_state
+        .finish(credential_finalization)
+        .map_err(|err| failure("open_locker_finish", err))?;
 
     // Server sends locker contents, encrypted under the session key, to the client
     let encrypted_locker_contents =
@@ -215,3 +239,42 @@ pub fn open_locker_finish(
 
     Ok(encrypted_locker_contents)
 }
+
+// Counts the failure by operation and reason, then hands the error back unchanged
+fn failure(operation: &str, err: ProtocolError) -> ProtocolError {
+    let reason = match err {
+        ProtocolError::InvalidLoginError => "invalid_login",
+        ProtocolError::SerializationError => "serialization",
+        ProtocolError::ReflectedValueError => "reflected_value",
+        ProtocolError::IdentityGroupElementError => "identity_group_element",
+        ProtocolError::LibraryError(_) => "library",
+        _ => "other",
+    };
+    metrics::opaque_failure(operation, reason);
+    err
+}
+
+// What the client sent doesn't decode, also counted as a failure of the operation
+fn decode(operation: &str, input_base64: &str) -> Result<Vec<u8>, ApiError> {
+    base64::decode(input_base64).map_err(|err| {
+        metrics::opaque_failure(operation, "base64");
+        ApiError::BadRequestDecode(err)
+    })
+}
+
+// For OPAQUE messages from the client
+fn bad_request(operation: &str) -> impl Fn(ProtocolError) -> ApiError + '_ {
+    move |err| {
+        failure(operation, err);
+        ApiError::BadRequestProtocol
+    }
+}
+
+// For the stored password files and the cached login state, which the client never supplies
+fn server_error(operation: &str) -> impl Fn(ProtocolError) -> ApiError + '_ {
+    move |err| {
+        error!(operation, error = ?err, "Could not deserialize stored OPAQUE state");
+        failure(operation, err);
+        ApiError::ServerError
+    }
+}
@@ -0,0 +1,70 @@
+use opaque_ke::rand::rngs::OsRng;
+use opaque_ke::rand::RngCore;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use tracing::{debug, error};
+use zeroize::Zeroizing;
+
+use crate::util;
+
+pub const SERVER_KEY_FILE: &str = "server_key.private";
+
+// Symmetric server key for secrets the server itself must be able to read back (e.g. TOTP secrets). Never leaves the server.
+lazy_static! {
+    static ref SERVER_KEY: Zeroizing<Vec<u8>> = {
+        let server_key_location = format!("{}/{}", util::default_dir(), SERVER_KEY_FILE);
+        match util::read_file(&server_key_location) {
+            Ok(bytes) if bytes.len() == 32 => {
+                debug!("Found server_key file");
+                Zeroizing::new(bytes)
+            }
+            Ok(_) => panic!("Invalid server_key file {}", &server_key_location),
+            Err(err) if err.kind() != ErrorKind::NotFound => {
+                panic!(
+                    "Could not read server_key file {}: {}",
+                    &server_key_location, err
+                )
+            }
+            Err(err) => {
+                debug!(error = ?err, "Could not find server_key file");
+                let mut key = Zeroizing::new(vec![0u8; 32]);
+                OsRng.fill_bytes(&mut key);
+                util::write_to_file(&server_key_location, &key).unwrap_or_else(|err| {
+                    error!(error = ?err, "Could not write server_key");
+                    panic!(
+                        "Could not write server_key file to {}",
+                        &server_key_location
+                    )
+                });
+                key
+            }
+        }
+    };
+}
+
+pub fn load_server_key() {
+    lazy_static::initialize(&SERVER_KEY);
+}
+
+pub fn server_key() -> &'static [u8] {
+    &SERVER_KEY
+}
+
+/// Writes a fresh key next to the current one (`<file>.next`) without switching to it yet.
+pub fn stage_server_key() -> Result<Zeroizing<Vec<u8>>, Error> {
+    let mut key = Zeroizing::new(vec![0u8; 32]);
+    OsRng.fill_bytes(&mut key);
+    util::write_to_file(&server_key_path(".next"), &key)?;
+    Ok(key)
+}
+
+/// Keeps the current key as `<file>.previous` and makes the staged key the current one.
+pub fn promote_server_key() -> Result<(), Error> {
+    let current = server_key_path("");
+    fs::rename(&current, server_key_path(".previous"))?;
+    fs::rename(server_key_path(".next"), &current)
+}
+
+fn server_key_path(suffix: &str) -> String {
+    format!("{}/{}{}", util::default_dir(), SERVER_KEY_FILE, suffix)
+}
@@ -0,0 +1,161 @@
+use hmac::{Hmac, Mac};
+use opaque_ke::rand::rngs::OsRng;
+use opaque_ke::rand::RngCore;
+use sha1::Sha1;
+use std::time::{SystemTime, UNIX_EPOCH};
+use subtle::ConstantTimeEq;
+use zeroize::Zeroizing;
+
+// RFC 6238 defaults, which is what authenticator apps expect: HMAC-SHA1, 30 second steps, 6 digits
+const ISSUER: &str = "keypost";
+const STEP_SECS: u64 = 30;
+const DIGITS: u32 = 6;
+const SECRET_LEN: usize = 20;
+// Number of steps before and after the current one that are still accepted, to allow for clock drift
+const SKEW_STEPS: i64 = 1;
+
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+pub fn create_totp_secret() -> Zeroizing<Vec<u8>> {
+    let mut secret = Zeroizing::new(vec![0u8; SECRET_LEN]);
+    OsRng.fill_bytes(&mut secret);
+    secret
+}
+
+/// The otpauth:// URI that authenticator apps import, usually rendered by the client as a QR code.
+pub fn totp_provisioning_uri(email: &str, secret: &[u8]) -> String {
+    format!(
+        "otpauth://totp/{issuer}:{email}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={digits}&period={period}",
+        issuer = ISSUER,
+        email = percent_encode(email),
+        secret = base32_encode(secret),
+        digits = DIGITS,
+        period = STEP_SECS,
+    )
+}
+
+/// Returns the time step the code matched, which must be greater than `last_used_step` so a code can't be replayed.
+pub fn verify_totp(secret: &[u8], code: &str, last_used_step: i64) -> Option<i64> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let current_step = (now / STEP_SECS) as i64;
+    (current_step - SKEW_STEPS..=current_step + SKEW_STEPS)
+        .filter(|step| *step > last_used_step)
+        .find(|step| {
+            let expected = format!(
+                "{:0width$}",
+                hotp(secret, *step as u64),
+                width = DIGITS as usize
+            );
+            bool::from(expected.as_bytes().ct_eq(code.as_bytes()))
+        })
+}
+
+// https://datatracker.ietf.org/doc/html/rfc4226#section-5.3
+fn hotp(secret: &[u8], counter: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+    binary % 10u32.pow(DIGITS)
+}
+
+// https://datatracker.ietf.org/doc/html/rfc4648#section-6 (without padding, as authenticator apps expect)
+pub fn base32_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::new();
+    for chunk in bytes.chunks(5) {
+        let mut buffer = [0u8; 5];
+        buffer[..chunk.len()].copy_from_slice(chunk);
+        let bits = u64::from_be_bytes([
+            0, 0, 0, buffer[0], buffer[1], buffer[2], buffer[3], buffer[4],
+        ]);
+        let chars = (chunk.len() * 8 + 4) / 5;
+        for i in 0..chars {
+            let index = (bits >> (35 - i * 5)) & 0x1f;
+            encoded.push(BASE32_ALPHABET[index as usize] as char);
+        }
+    }
+    encoded
+}
+
+fn percent_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// A one-time recovery code, 80 bits of entropy encoded as 16 base32 characters.
+pub fn create_recovery_code() -> String {
+    let mut bytes = Zeroizing::new([0u8; 10]);
+    OsRng.fill_bytes(&mut bytes[..]);
+    base32_encode(&bytes[..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // https://datatracker.ietf.org/doc/html/rfc6238#appendix-B (SHA-1), truncated to our 6 digits
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+    const RFC_VECTORS: [(u64, u32); 6] = [
+        (59, 287082),
+        (1111111109, 81804),
+        (1111111111, 50471),
+        (1234567890, 5924),
+        (2000000000, 279037),
+        (20000000000, 353130),
+    ];
+
+    #[test]
+    fn hotp_matches_rfc_6238_vectors() {
+        for (time, code) in RFC_VECTORS.iter() {
+            assert_eq!(hotp(RFC_SECRET, time / STEP_SECS), *code, "time {}", time);
+        }
+    }
+
+    #[test]
+    fn verify_totp_accepts_current_code_once() {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let step = (now / STEP_SECS) as i64;
+        // Skew allows the neighbouring steps, pick the current one even if the clock ticked over meanwhile
+        let code = format!("{:06}", hotp(RFC_SECRET, step as u64));
+        let matched = verify_totp(RFC_SECRET, &code, 0).expect("current code is accepted");
+        assert!((step..=step + SKEW_STEPS).contains(&matched));
+        assert_eq!(verify_totp(RFC_SECRET, &code, matched), None);
+        assert_eq!(verify_totp(RFC_SECRET, "000000x", 0), None);
+    }
+
+    // https://datatracker.ietf.org/doc/html/rfc4648#section-10
+    #[test]
+    fn base32_matches_rfc_4648_vectors() {
+        let vectors = [
+            ("", ""),
+            ("f", "MY"),
+            ("fo", "MZXQ"),
+            ("foo", "MZXW6"),
+            ("foob", "MZXW6YQ"),
+            ("fooba", "MZXW6YTB"),
+            ("foobar", "MZXW6YTBOI"),
+        ];
+        for (plain, encoded) in vectors.iter() {
+            assert_eq!(base32_encode(plain.as_bytes()), *encoded);
+        }
+        assert_eq!(create_recovery_code().len(), 16);
+    }
+}
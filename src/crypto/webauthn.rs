@@ -0,0 +1,225 @@
+use opaque_ke::rand::rngs::OsRng;
+use opaque_ke::rand::RngCore;
+use p256::ecdsa::signature::Verifier;
+use p256::ecdsa::{Signature, VerifyingKey};
+use serde::Deserialize;
+use serde_cbor::Value;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+use crate::api::ApiError;
+
+// https://www.w3.org/TR/webauthn-2/#sctn-authenticator-data
+const FLAG_USER_PRESENT: u8 = 0x01;
+const FLAG_ATTESTED_CREDENTIAL_DATA: u8 = 0x40;
+// https://www.iana.org/assignments/cose/cose.xhtml
+const COSE_KTY_EC2: i128 = 2;
+const COSE_ALG_ES256: i128 = -7;
+const COSE_CRV_P256: i128 = 1;
+
+/// A credential from a successful registration ceremony. `public_key` is an uncompressed SEC1 P-256 point.
+pub struct VerifiedCredential {
+    pub credential_id: Vec<u8>,
+    pub public_key: Vec<u8>,
+    pub sign_count: u32,
+}
+
+#[derive(Deserialize)]
+struct ClientData {
+    #[serde(rename = "type")]
+    type_: String,
+    challenge: String,
+    origin: String,
+}
+
+struct AuthenticatorData {
+    rp_id_hash: Vec<u8>,
+    flags: u8,
+    sign_count: u32,
+    attested_credential: Option<(Vec<u8>, Value)>,
+}
+
+pub fn create_webauthn_challenge() -> Vec<u8> {
+    let mut challenge = [0u8; 32];
+    OsRng.fill_bytes(&mut challenge);
+    challenge.to_vec()
+}
+
+pub fn base64url_encode(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+pub fn base64url_decode(s: &str) -> Result<Vec<u8>, ApiError> {
+    base64::decode_config(s, base64::URL_SAFE_NO_PAD).map_err(ApiError::BadRequestDecode)
+}
+
+/// Registration ceremony, only accepting the "none" attestation format and ES256 credentials.
+/// https://www.w3.org/TR/webauthn-2/#sctn-registering-a-new-credential
+pub fn verify_webauthn_registration(
+    rp_id: &str,
+    origin: &str,
+    challenge: &[u8],
+    attestation_object: &[u8],
+    client_data_json: &[u8],
+) -> Result<VerifiedCredential, ApiError> {
+    verify_client_data(client_data_json, "webauthn.create", challenge, origin)?;
+
+    let attestation: Value = serde_cbor::from_slice(attestation_object)
+        .map_err(|_| webauthn_error("Invalid attestation object"))?;
+    match map_get(&attestation, Value::Text("fmt".to_string())) {
+        Some(Value::Text(fmt)) if fmt == "none" => {}
+        _ => return Err(webauthn_error("Only \"none\" attestation is supported")),
+    }
+    let auth_data = match map_get(&attestation, Value::Text("authData".to_string())) {
+        Some(Value::Bytes(bytes)) => parse_authenticator_data(bytes)?,
+        _ => return Err(webauthn_error("Missing authenticator data")),
+    };
+    verify_authenticator_data(&auth_data, rp_id)?;
+
+    let (credential_id, cose_key) = auth_data
+        .attested_credential
+        .ok_or_else(|| webauthn_error("Missing attested credential data"))?;
+    Ok(VerifiedCredential {
+        credential_id,
+        public_key: parse_cose_key(&cose_key)?,
+        sign_count: auth_data.sign_count,
+    })
+}
+
+/// Assertion ceremony, returning the authenticator's new sign count to be stored.
+/// https://www.w3.org/TR/webauthn-2/#sctn-verifying-assertion
+#[allow(clippy::too_many_arguments)]
+pub fn verify_webauthn_assertion(
+    rp_id: &str,
+    origin: &str,
+    challenge: &[u8],
+    public_key: &[u8],
+    stored_sign_count: u32,
+    authenticator_data: &[u8],
+    client_data_json: &[u8],
+    signature: &[u8],
+) -> Result<u32, ApiError> {
+    verify_client_data(client_data_json, "webauthn.get", challenge, origin)?;
+    let auth_data = parse_authenticator_data(authenticator_data)?;
+    verify_authenticator_data(&auth_data, rp_id)?;
+
+    let verifying_key = VerifyingKey::from_sec1_bytes(public_key)
+        .map_err(|_| webauthn_error("Invalid stored public key"))?;
+    let signature =
+        Signature::from_der(signature).map_err(|_| webauthn_error("Invalid signature"))?;
+    let client_data_hash = Sha256::digest(client_data_json);
+    let signed_data = [authenticator_data, client_data_hash.as_slice()].concat();
+    verifying_key
+        .verify(&signed_data, &signature)
+        .map_err(|_| webauthn_error("Signature verification failed"))?;
+
+    // Authenticators without a counter always report 0, otherwise it must increase or the authenticator may be cloned
+    if (auth_data.sign_count != 0 || stored_sign_count != 0)
+        && auth_data.sign_count <= stored_sign_count
+    {
+        return Err(webauthn_error("Sign count did not increase"));
+    }
+    Ok(auth_data.sign_count)
+}
+
+fn verify_client_data(
+    client_data_json: &[u8],
+    expected_type: &str,
+    challenge: &[u8],
+    origin: &str,
+) -> Result<(), ApiError> {
+    let client_data: ClientData = serde_json::from_slice(client_data_json)
+        .map_err(|_| webauthn_error("Invalid client data"))?;
+    if client_data.type_ != expected_type {
+        return Err(webauthn_error("Unexpected client data type"));
+    }
+    let client_challenge = base64url_decode(&client_data.challenge)?;
+    if !bool::from(client_challenge.ct_eq(challenge)) {
+        return Err(webauthn_error("Challenge mismatch"));
+    }
+    if client_data.origin != origin {
+        return Err(webauthn_error("Origin mismatch"));
+    }
+    Ok(())
+}
+
+fn verify_authenticator_data(auth_data: &AuthenticatorData, rp_id: &str) -> Result<(), ApiError> {
+    if auth_data.rp_id_hash != Sha256::digest(rp_id.as_bytes()).as_slice() {
+        return Err(webauthn_error("RP ID mismatch"));
+    }
+    if auth_data.flags & FLAG_USER_PRESENT == 0 {
+        return Err(webauthn_error("User not present"));
+    }
+    Ok(())
+}
+
+fn parse_authenticator_data(bytes: &[u8]) -> Result<AuthenticatorData, ApiError> {
+    if bytes.len() < 37 {
+        return Err(webauthn_error("Authenticator data too short"));
+    }
+    let flags = bytes[32];
+    let sign_count = u32::from_be_bytes([bytes[33], bytes[34], bytes[35], bytes[36]]);
+    let attested_credential = match flags & FLAG_ATTESTED_CREDENTIAL_DATA {
+        0 => None,
+        _ => {
+            // aaguid (16 bytes) | credential id length (2 bytes) | credential id | COSE public key | extensions
+            let rest = bytes
+                .get(37 + 16..)
+                .ok_or_else(|| webauthn_error("Truncated"))?;
+            if rest.len() < 2 {
+                return Err(webauthn_error("Truncated attested credential data"));
+            }
+            let id_len = u16::from_be_bytes([rest[0], rest[1]]) as usize;
+            let credential_id = rest
+                .get(2..2 + id_len)
+                .ok_or_else(|| webauthn_error("Truncated credential id"))?
+                .to_vec();
+            let mut deserializer = serde_cbor::Deserializer::from_slice(&rest[2 + id_len..]);
+            let cose_key = Value::deserialize(&mut deserializer)
+                .map_err(|_| webauthn_error("Invalid credential public key"))?;
+            Some((credential_id, cose_key))
+        }
+    };
+    Ok(AuthenticatorData {
+        rp_id_hash: bytes[..32].to_vec(),
+        flags,
+        sign_count,
+        attested_credential,
+    })
+}
+
+fn parse_cose_key(cose_key: &Value) -> Result<Vec<u8>, ApiError> {
+    let int = |key: i128| map_get(cose_key, Value::Integer(key));
+    match (int(1), int(3), int(-1)) {
+        (
+            Some(Value::Integer(COSE_KTY_EC2)),
+            Some(Value::Integer(COSE_ALG_ES256)),
+            Some(Value::Integer(COSE_CRV_P256)),
+        ) => {}
+        _ => {
+            return Err(webauthn_error(
+                "Only ES256 (P-256) credentials are supported",
+            ))
+        }
+    }
+    match (int(-2), int(-3)) {
+        (Some(Value::Bytes(x)), Some(Value::Bytes(y))) if x.len() == 32 && y.len() == 32 => {
+            let public_key = [&[0x04u8][..], x.as_slice(), y.as_slice()].concat();
+            VerifyingKey::from_sec1_bytes(&public_key)
+                .map_err(|_| webauthn_error("Invalid credential public key"))?;
+            Ok(public_key)
+        }
+        _ => Err(webauthn_error("Invalid credential public key")),
+    }
+}
+
+fn map_get(value: &Value, key: Value) -> Option<&Value> {
+    match value {
+        Value::Map(map) => map.get(&key),
+        _ => None,
+    }
+}
+
+fn webauthn_error(msg: &str) -> ApiError {
+    ApiError::WebAuthnError(msg.to_string())
+}
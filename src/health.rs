@@ -0,0 +1,49 @@
+use tracing::warn;
+
+use crate::cache;
+use crate::crypto;
+use crate::persistence;
+
+const OK: &str = "ok";
+const UNAVAILABLE: &str = "unavailable";
+
+/// Readiness of each dependency. Failure details are logged, never returned to the (unauthenticated) caller.
+#[derive(Debug, Serialize)]
+pub struct Readiness {
+    pub status: &'static str,
+    pub database: &'static str,
+    pub server_setup: &'static str,
+    pub cache: &'static str,
+}
+
+impl Readiness {
+    pub fn is_ready(&self) -> bool {
+        self.status == OK
+    }
+}
+
+pub fn readiness() -> Readiness {
+    let database = match persistence::ping() {
+        Ok(()) => OK,
+        Err(err) => {
+            warn!(error = %err, "Readiness check: database unavailable");
+            UNAVAILABLE
+        }
+    };
+    let server_setup = status(crypto::server_setup_ready());
+    let cache = status(cache::is_healthy() && cache::sessions_healthy());
+    let ready = [database, server_setup, cache].iter().all(|s| *s == OK);
+    Readiness {
+        status: status(ready),
+        database,
+        server_setup,
+        cache,
+    }
+}
+
+fn status(ok: bool) -> &'static str {
+    match ok {
+        true => OK,
+        false => UNAVAILABLE,
+    }
+}
@@ -1,16 +1,28 @@
-use diesel::result::Error::NotFound;
+use diesel::pg::PgConnection;
+use diesel::result::DatabaseErrorKind;
+use diesel::result::Error::{DatabaseError, NotFound};
+use tracing::error;
+use zeroize::Zeroizing;
 
-use crate::api::ApiError;
+use crate::api::{ApiError, UpdateLockerMetadata};
 use crate::cache;
 use crate::crypto;
 use crate::locker::ApiError::*;
+use crate::models::{Locker, LockerMetadata, LockerMetadataChanges};
 use crate::persistence;
 
+// Limits on the decoded, client-encrypted metadata
+const MAX_LABEL_BYTES: usize = 1024;
+const MAX_TAGS_BYTES: usize = 4096;
+const MAX_CUSTOM_FIELDS_BYTES: usize = 65536;
+
 #[derive(Debug)]
 pub struct LockerResponse {
     pub id: u32,
     pub output: String,
     pub nonce: u32,
+    // Current version of the locker, which rotating it has to name
+    pub version: Option<i32>,
 }
 
 pub fn register_start(id: &str, input: &[u8]) -> Result<LockerResponse, ApiError> {
@@ -19,64 +31,82 @@ pub fn register_start(id: &str, input: &[u8]) -> Result<LockerResponse, ApiError
             id: 0,
             output,
             nonce: 0,
+            version: None,
         }),
         Err(err) => {
-            println!("Error in locker::register_start: {:?}", err);
-            Err(UnknownLockerError(
-                "There was an error during register_locker_start".to_string(),
-            ))
+            error!(error = ?err, "Error in locker::register_start");
+            Err(err)
         }
     }
 }
 
+/// Stores a new locker, or with `version` rotates the existing one if nobody else changed it since that version.
 pub fn register_finish(
     locker_id: &str,
     email: &str,
     input: &[u8],
     ciphertext: &[u8],
+    version: Option<i32>,
 ) -> Result<LockerResponse, ApiError> {
-    match crypto::register_locker_finish(locker_id, email, input, ciphertext) {
-        Ok(()) => Ok(LockerResponse {
-            id: 0,
-            output: "Success".to_string(),
-            nonce: 0,
-        }),
-        Err(err) => {
-            println!("Error in locker::register_finish: {:?}", err);
-            Err(UnknownLockerError(
-                "There was an error during register_locker_finish".to_string(),
-            ))
+    let password_file = crypto::register_locker_finish(input).map_err(|err| {
+        error!(error = ?err, "Error in locker::register_finish");
+        UnknownLockerError("There was an error during register_locker_finish".to_string())
+    })?;
+    let new_version = match version {
+        None => match persistence::transaction(|connection| {
+            persistence::insert_locker(connection, email, locker_id, &password_file, ciphertext)
+        }) {
+            Ok(locker) => locker.version,
+            Err(DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
+                return Err(LockerConflict(locker_id.to_string()));
+            }
+            Err(err) => return Err(err.into()),
+        },
+        Some(version) => {
+            let rotated = persistence::transaction(|connection| {
+                persistence::rotate_locker(
+                    connection,
+                    email,
+                    locker_id,
+                    version,
+                    &password_file,
+                    ciphertext,
+                )
+            })?;
+            if rotated == 0 {
+                return Err(LockerConflict(locker_id.to_string()));
+            }
+            version + 1
         }
-    }
+    };
+    Ok(LockerResponse {
+        id: 0,
+        output: "Success".to_string(),
+        nonce: 0,
+        version: Some(new_version),
+    })
 }
 
 pub fn open_start(locker_id: &str, email: &str, input: &[u8]) -> Result<LockerResponse, ApiError> {
     let nonce: u32 = crypto::create_nonce();
-    let (locker_psswd_file, _ciphertext) = {
-        match persistence::fetch_locker_contents(email, locker_id) {
-            Ok((l, c)) => (l, c),
-            Err(NotFound) => {
-                return Err(LockerNotFound(locker_id.to_string()));
-            }
-            Err(err) => {
-                println!("Error fetching locker contents: {:?}", err);
-                return Err(UnknownLockerError(
-                    "There was an error during open_locker_start".to_string(),
-                ));
-            }
+    let locker = persistence::transaction(|connection| find_locker(connection, email, locker_id))?;
+    match crypto::open_locker_start(locker_id, input, &locker.psswd_file, nonce) {
+        Ok(output) => {
+            // The open (or delete) finishes against this version of the locker, or not at all
+            cache::insert_bin(
+                version_cache_key(nonce),
+                Zeroizing::new(locker.version.to_be_bytes().to_vec()),
+            );
+            Ok(LockerResponse {
+                id: 0,
+                output,
+                nonce,
+                version: Some(locker.version),
+            })
         }
-    };
-    match crypto::open_locker_start(locker_id, input, &locker_psswd_file, nonce) {
-        Ok(output) => Ok(LockerResponse {
-            id: 0,
-            output,
-            nonce,
-        }),
         Err(err) => {
-            println!("Error in open_locker_start: {:?}", err);
-            Err(UnknownLockerError(
-                "There was an error during open_locker_start".to_string(),
-            ))
+            error!(error = ?err, "Error in open_locker_start");
+            Err(err)
         }
     }
 }
@@ -87,23 +117,10 @@ pub fn open_finish(
     input: &[u8],
     nonce: u32,
 ) -> Result<LockerResponse, ApiError> {
-    let (_locker_psswd_file, ciphertext) = persistence::fetch_locker_contents(email, locker_id)
-        .expect("Could not get locker_contents");
-    let server_login_bytes: Vec<u8> = cache::get(&nonce)
-        .unwrap_or_else(|| panic!("Could not find cached server_login_bytes: {:?}", nonce));
-    match crypto::open_locker_finish(&ciphertext, input, &server_login_bytes) {
-        Ok(encrypted_ciphertext) => Ok(LockerResponse {
-            id: 0,
-            output: base64::encode(encrypted_ciphertext),
-            nonce,
-        }),
-        Err(err) => {
-            println!("Error in locker::open_finish: {:?}", err);
-            Err(UnknownLockerError(
-                "There was an error during open_locker_finish".to_string(),
-            ))
-        }
-    }
+    persistence::transaction(|connection| {
+        let (locker, server_login_bytes) = opened_locker(connection, email, locker_id, nonce)?;
+        finish_open(&locker, input, nonce, &server_login_bytes)
+    })
 }
 
 pub fn delete_start(
@@ -122,29 +139,287 @@ pub fn delete_finish(
     nonce: u32,
 ) -> Result<LockerResponse, ApiError> {
     //Finish the open-locker opaque protocol, but instead of returning the encrypted key, delete locker contents (i.e. key).
-    match open_finish(locker_id, email, input, nonce) {
-        Ok(_) => delete_contents(email, locker_id, nonce),
-        Err(err) => {
-            println!("Error in locker::delete_finish: {:?}", err);
-            Err(UnknownLockerError(
-                "There was an error during locker::delete_finish".to_string(),
-            ))
+    persistence::transaction(|connection| {
+        let (locker, server_login_bytes) = opened_locker(connection, email, locker_id, nonce)?;
+        finish_open(&locker, input, nonce, &server_login_bytes)?;
+        match persistence::delete_locker(connection, email, locker_id, locker.version)? {
+            0 => Err(LockerConflict(locker_id.to_string())),
+            _ => Ok(LockerResponse {
+                id: 0,
+                output: "Key deleted!".to_string(),
+                nonce,
+                version: None,
+            }),
         }
+    })
+}
+
+fn find_locker(
+    connection: &PgConnection,
+    email: &str,
+    locker_id: &str,
+) -> Result<Locker, ApiError> {
+    match persistence::find_locker(connection, email, locker_id) {
+        Ok(locker) => Ok(locker),
+        Err(NotFound) => Err(LockerNotFound(locker_id.to_string())),
+        Err(err) => Err(err.into()),
     }
 }
 
-fn delete_contents(email: &str, locker_id: &str, nonce: u32) -> Result<LockerResponse, ApiError> {
-    match persistence::delete_locker_contents(email, locker_id) {
-        Ok(_) => Ok(LockerResponse {
+// The locker as of open_start with its ServerLogin state, or a conflict if it was rotated or deleted in between. Both
+// cache entries are single use and taken before anything can fail, so no outcome leaves one behind for a retry
+fn opened_locker(
+    connection: &PgConnection,
+    email: &str,
+    locker_id: &str,
+    nonce: u32,
+) -> Result<(Locker, Zeroizing<Vec<u8>>), ApiError> {
+    let opened_version = cache::take_bin(&version_cache_key(nonce));
+    let server_login_bytes = cache::take(&nonce);
+    let (opened_version, server_login_bytes) =
+        opened_version.zip(server_login_bytes).ok_or(BadRequest)?;
+    let locker = find_locker(connection, email, locker_id)?;
+    check_opened_version(locker_id, &opened_version, locker.version)?;
+    Ok((locker, server_login_bytes))
+}
+
+fn check_opened_version(
+    locker_id: &str,
+    opened_version: &[u8],
+    version: i32,
+) -> Result<(), ApiError> {
+    match opened_version == version.to_be_bytes() {
+        true => Ok(()),
+        false => Err(LockerConflict(locker_id.to_string())),
+    }
+}
+
+fn finish_open(
+    locker: &Locker,
+    input: &[u8],
+    nonce: u32,
+    server_login_bytes: &[u8],
+) -> Result<LockerResponse, ApiError> {
+    match crypto::open_locker_finish(&locker.ciphertext, input, server_login_bytes) {
+        Ok(encrypted_ciphertext) => Ok(LockerResponse {
             id: 0,
-            output: "Key deleted!".to_string(),
+            output: base64::encode(encrypted_ciphertext),
             nonce,
+            version: Some(locker.version),
         }),
         Err(err) => {
-            println!("Error in locker::delete_contents: {:?}", err);
+            error!(error = ?err, "Error in locker::open_finish");
             Err(UnknownLockerError(
-                "There was an error during locker::delete_contents".to_string(),
+                "There was an error during open_locker_finish".to_string(),
             ))
         }
     }
 }
+
+/// What a locker holds, so clients can tell lockers apart without opening them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LockerKind {
+    Password,
+    SshKey,
+    Note,
+    ApiKey,
+    Card,
+    Other,
+}
+
+impl LockerKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LockerKind::Password => "password",
+            LockerKind::SshKey => "ssh_key",
+            LockerKind::Note => "note",
+            LockerKind::ApiKey => "api_key",
+            LockerKind::Card => "card",
+            LockerKind::Other => "other",
+        }
+    }
+
+    pub fn parse(kind: &str) -> Option<LockerKind> {
+        match kind {
+            "password" => Some(LockerKind::Password),
+            "ssh_key" => Some(LockerKind::SshKey),
+            "note" => Some(LockerKind::Note),
+            "api_key" => Some(LockerKind::ApiKey),
+            "card" => Some(LockerKind::Card),
+            "other" => Some(LockerKind::Other),
+            _ => None,
+        }
+    }
+}
+
+/// One locker of the user's list: its metadata and current version, never its contents.
+#[derive(Debug, Serialize)]
+pub struct LockerSummary {
+    pub id: String,
+    pub v: i32,
+    pub t: String,
+    pub l: Option<String>,
+    pub g: Option<String>,
+    pub f: Option<String>,
+    pub h: Option<String>,
+}
+
+pub fn list(email: &str) -> Result<Vec<LockerSummary>, ApiError> {
+    let lockers = persistence::fetch_locker_metadata(email)?;
+    Ok(lockers.into_iter().map(to_summary).collect())
+}
+
+fn to_summary(metadata: LockerMetadata) -> LockerSummary {
+    LockerSummary {
+        id: metadata.locker_id,
+        v: metadata.version,
+        t: metadata.kind,
+        l: metadata.label.map(base64::encode),
+        g: metadata.tags.map(base64::encode),
+        f: metadata.custom_fields.map(base64::encode),
+        h: metadata.icon_hash,
+    }
+}
+
+/// Replaces the locker's metadata. Owning the session is enough, the metadata is encrypted by the client anyway.
+pub fn update_metadata(email: &str, update: &UpdateLockerMetadata) -> Result<(), ApiError> {
+    let kind = LockerKind::parse(&update.t).ok_or_else(|| InvalidRequest {
+        expected: "t to be one of password, ssh_key, note, api_key, card, other".to_string(),
+    })?;
+    let label = decode_metadata(&update.l, MAX_LABEL_BYTES, "l")?;
+    let tags = decode_metadata(&update.g, MAX_TAGS_BYTES, "g")?;
+    let custom_fields = decode_metadata(&update.f, MAX_CUSTOM_FIELDS_BYTES, "f")?;
+    if let Some(icon_hash) = &update.h {
+        let is_sha256_hex = icon_hash.len() == 64
+            && icon_hash
+                .chars()
+                .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c));
+        if !is_sha256_hex {
+            return Err(InvalidRequest {
+                expected: "h to be a lowercase hex SHA-256".to_string(),
+            });
+        }
+    }
+    let changes = LockerMetadataChanges {
+        kind: kind.as_str(),
+        label: label.as_deref(),
+        tags: tags.as_deref(),
+        custom_fields: custom_fields.as_deref(),
+        icon_hash: update.h.as_deref(),
+    };
+    match persistence::update_locker_metadata(email, &update.id, &changes) {
+        Ok(0) | Err(NotFound) => Err(LockerNotFound(update.id.clone())),
+        Ok(_) => Ok(()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn decode_metadata(
+    value: &Option<String>,
+    max_bytes: usize,
+    field: &str,
+) -> Result<Option<Vec<u8>>, ApiError> {
+    let bytes = match value {
+        Some(value) => base64::decode(value)?,
+        None => return Ok(None),
+    };
+    match bytes.len() <= max_bytes {
+        true => Ok(Some(bytes)),
+        false => Err(InvalidRequest {
+            expected: format!("{} to be at most {} bytes", field, max_bytes),
+        }),
+    }
+}
+
+fn version_cache_key(nonce: u32) -> Vec<u8> {
+    [b"locker:".as_ref(), &nonce.to_be_bytes()].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opened_version_must_still_be_current() {
+        assert!(check_opened_version("a", &3i32.to_be_bytes(), 3).is_ok());
+        assert!(matches!(
+            check_opened_version("a", &3i32.to_be_bytes(), 4),
+            Err(LockerConflict(id)) if id == "a"
+        ));
+        assert!(matches!(
+            check_opened_version("a", &[], 3),
+            Err(LockerConflict(_))
+        ));
+    }
+
+    fn update(t: &str) -> UpdateLockerMetadata {
+        UpdateLockerMetadata {
+            id: "a".to_string(),
+            t: t.to_string(),
+            l: None,
+            g: None,
+            f: None,
+            h: None,
+        }
+    }
+
+    // Each of these fails validation, before the database is involved
+    fn rejected(update: &UpdateLockerMetadata) -> String {
+        match update_metadata("user@example.com", update) {
+            Err(InvalidRequest { expected }) => expected,
+            other => panic!("expected InvalidRequest, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn kinds_round_trip_and_others_are_rejected() {
+        for kind in &["password", "ssh_key", "note", "api_key", "card", "other"] {
+            assert_eq!(LockerKind::parse(kind).map(|k| k.as_str()), Some(*kind));
+        }
+        for kind in &["", "Password", "ssh-key", "secret"] {
+            assert!(rejected(&update(kind)).starts_with("t to be one of"));
+        }
+    }
+
+    #[test]
+    fn oversized_metadata_is_rejected() {
+        let mut label = update("note");
+        label.l = Some(base64::encode(vec![0u8; MAX_LABEL_BYTES + 1]));
+        assert_eq!(rejected(&label), "l to be at most 1024 bytes");
+        let mut tags = update("note");
+        tags.g = Some(base64::encode(vec![0u8; MAX_TAGS_BYTES + 1]));
+        assert_eq!(rejected(&tags), "g to be at most 4096 bytes");
+        let mut custom_fields = update("note");
+        custom_fields.f = Some(base64::encode(vec![0u8; MAX_CUSTOM_FIELDS_BYTES + 1]));
+        assert_eq!(rejected(&custom_fields), "f to be at most 65536 bytes");
+    }
+
+    #[test]
+    fn icon_hash_must_be_lowercase_hex_sha256() {
+        let valid = "ab".repeat(32);
+        for h in &[
+            "ab".repeat(31),
+            "AB".repeat(32),
+            format!("{}g", &valid[1..]),
+            format!("{} ", valid),
+        ] {
+            let mut update = update("password");
+            update.h = Some(h.clone());
+            assert_eq!(rejected(&update), "h to be a lowercase hex SHA-256");
+        }
+    }
+
+    #[test]
+    fn metadata_is_decoded_up_to_its_limit() {
+        let at_limit = base64::encode(vec![7u8; MAX_LABEL_BYTES]);
+        assert_eq!(
+            decode_metadata(&Some(at_limit), MAX_LABEL_BYTES, "l").unwrap(),
+            Some(vec![7u8; MAX_LABEL_BYTES])
+        );
+        assert_eq!(decode_metadata(&None, MAX_LABEL_BYTES, "l").unwrap(), None);
+        assert!(matches!(
+            decode_metadata(&Some("not base64!".to_string()), MAX_LABEL_BYTES, "l"),
+            Err(BadRequestDecode(_))
+        ));
+    }
+}
@@ -0,0 +1,197 @@
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::fmt;
+use std::io::{self, Write};
+
+use hmac::{Hmac, Mac};
+use opaque_ke::rand::rngs::OsRng;
+use opaque_ke::rand::RngCore;
+use sha2::Sha256;
+use tracing::span::EnteredSpan;
+use tracing_subscriber::EnvFilter;
+
+use crate::config::LogConfig;
+
+// Base64/hex runs at least this long are session ids, keys or protocol messages, never something worth logging
+const MIN_ELIDED_TOKEN_LEN: usize = 40;
+
+lazy_static! {
+    // Per-process so logged email tags can't be reversed with a dictionary, but still correlate within a run
+    static ref REDACTION_KEY: [u8; 32] = {
+        let mut key = [0u8; 32];
+        OsRng.fill_bytes(&mut key);
+        key
+    };
+}
+
+thread_local! {
+    // Rocket 0.4 handles a request on one worker thread, from the request fairing to the response fairing
+    static REQUEST_SPAN: RefCell<Option<EnteredSpan>> = RefCell::new(None);
+}
+
+/// Installs the global subscriber. `filter` takes `EnvFilter` directives (e.g. `info,keypost_app=debug`),
+/// `format` is `json` or `text`. Rocket's own `log` records are forwarded to it as well.
+pub fn init(log_config: &LogConfig) -> Result<(), String> {
+    let filter = EnvFilter::try_new(&log_config.filter)
+        .map_err(|err| format!("Invalid log.filter: {}", err))?;
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(redacting_stdout);
+    let result = match log_config.format.as_str() {
+        "text" => builder.try_init(),
+        _ => builder.json().with_current_span(true).try_init(),
+    };
+    result.map_err(|err| err.to_string())?;
+
+    // Panic messages otherwise bypass the redacting writer
+    std::panic::set_hook(Box::new(
+        |info| tracing::error!(panic = %info, "Thread panicked"),
+    ));
+    Ok(())
+}
+
+pub fn enter_request_span(request_id: &str, method: &str, path: &str) {
+    let span = tracing::info_span!("request", id = %request_id, method = %method, path = %path);
+    REQUEST_SPAN.with(|current| *current.borrow_mut() = Some(span.entered()));
+}
+
+pub fn exit_request_span() {
+    REQUEST_SPAN.with(|current| current.borrow_mut().take());
+}
+
+/// Logs an email as a stable keyed tag instead of the address itself.
+pub struct Email<'a>(&'a str);
+
+pub fn email(value: &str) -> Email {
+    Email(value)
+}
+
+impl fmt::Display for Email<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&REDACTION_KEY[..])
+            .expect("HMAC accepts keys of any length");
+        mac.update(self.0.to_lowercase().as_bytes());
+        write!(f, "<email:")?;
+        for byte in &mac.finalize().into_bytes()[..6] {
+            write!(f, "{:02x}", byte)?;
+        }
+        write!(f, ">")
+    }
+}
+
+fn redacting_stdout() -> RedactingWriter<io::Stdout> {
+    RedactingWriter(io::stdout())
+}
+
+/// Last line of defence: whatever a call site (or a dependency, e.g. a Postgres error detail) formats,
+/// emails are replaced by their tag and long key-like tokens are elided before anything is written.
+pub struct RedactingWriter<W: Write>(W);
+
+impl<W: Write> Write for RedactingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let text = String::from_utf8_lossy(buf);
+        self.0.write_all(redact(&text).as_bytes())?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+fn redact(text: &str) -> Cow<str> {
+    if !text.split(|c| !is_token_char(c)).any(needs_redaction) {
+        return Cow::Borrowed(text);
+    }
+    let mut redacted = String::with_capacity(text.len());
+    let mut token_start = None;
+    for (i, c) in text
+        .char_indices()
+        .chain(std::iter::once((text.len(), ' ')))
+    {
+        match (is_token_char(c), token_start) {
+            (true, None) => token_start = Some(i),
+            (false, Some(start)) => {
+                redacted.push_str(&redact_token(&text[start..i]));
+                token_start = None;
+                if i < text.len() {
+                    redacted.push(c);
+                }
+            }
+            (false, None) if i < text.len() => redacted.push(c),
+            _ => {}
+        }
+    }
+    Cow::Owned(redacted)
+}
+
+fn is_token_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "+/=_-.%@".contains(c)
+}
+
+fn needs_redaction(token: &str) -> bool {
+    is_email(token) || is_key_like(token)
+}
+
+fn redact_token(token: &str) -> Cow<str> {
+    if is_email(token) {
+        Cow::Owned(email(token).to_string())
+    } else if is_key_like(token) {
+        Cow::Borrowed("[elided]")
+    } else {
+        Cow::Borrowed(token)
+    }
+}
+
+fn is_email(token: &str) -> bool {
+    match token.rsplit_once('@') {
+        Some((local, domain)) => {
+            !local.is_empty() && domain.trim_end_matches('.').contains('.') && !domain.contains('/')
+        }
+        None => false,
+    }
+}
+
+fn is_key_like(token: &str) -> bool {
+    token.len() >= MIN_ELIDED_TOKEN_LEN
+        && token.chars().any(|c| c.is_ascii_digit())
+        && token.chars().any(|c| c.is_ascii_alphabetic())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redact_leaves_plain_text_borrowed() {
+        let line = "INFO request: GET /healthz status=200 elapsed_ms=3";
+        assert!(matches!(redact(line), Cow::Borrowed(text) if text == line));
+    }
+
+    #[test]
+    fn redact_tags_emails() {
+        let tag = email("Alice@Example.com").to_string();
+        assert!(tag.starts_with("<email:") && tag.ends_with('>'));
+        // Case-insensitive, so the same user correlates however the address was typed
+        assert_eq!(tag, email("alice@example.com").to_string());
+        assert_eq!(
+            redact("login failed for Alice@Example.com, retrying"),
+            format!("login failed for {}, retrying", tag)
+        );
+        assert_eq!(redact("see user@localhost"), "see user@localhost");
+    }
+
+    #[test]
+    fn redact_elides_key_like_tokens() {
+        let session_id = base64::encode((0u8..32).collect::<Vec<_>>());
+        assert_eq!(
+            redact(&format!("session=\"{}\" ok", session_id)),
+            "session=\"[elided]\" ok"
+        );
+        let hex = "0123456789abcdef0123456789abcdef01234567";
+        assert_eq!(redact(&format!("key {}", hex)), "key [elided]");
+        // Long but without digits, e.g. an identifier, is kept
+        let identifier = "reencrypt_lockers_in_batches_under_the_active_key";
+        assert_eq!(redact(identifier), identifier);
+    }
+}
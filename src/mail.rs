@@ -0,0 +1,61 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+use tracing::{error, info};
+
+use crate::config;
+use crate::logging;
+
+const MAX_ADDRESS_LEN: usize = 254;
+
+/// A deliberately loose check: one @ with something on both sides, and nothing that could break out of a mail header.
+pub fn is_valid_address(address: &str) -> bool {
+    let valid_chars = address
+        .chars()
+        .all(|c| !c.is_whitespace() && !c.is_control() && !"<>,;\"".contains(c));
+    match address.split_once('@') {
+        Some((local, domain)) => {
+            valid_chars
+                && address.len() <= MAX_ADDRESS_LEN
+                && !local.is_empty()
+                && !domain.is_empty()
+                && !domain.contains('@')
+        }
+        None => false,
+    }
+}
+
+pub fn is_enabled() -> bool {
+    config::get().mail.sendmail.is_some()
+}
+
+/// Hands a plain text message to the configured sendmail program.
+pub fn send(to: &str, subject: &str, body: &str) -> Result<(), String> {
+    let mail = &config::get().mail;
+    let sendmail = mail.sendmail.as_ref().ok_or("Mail is not configured")?;
+    if !is_valid_address(to) {
+        return Err("Invalid recipient address".to_string());
+    }
+    let message = format!(
+        "From: {}\r\nTo: {}\r\nSubject: {}\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n{}\r\n",
+        mail.from, to, subject, body
+    );
+    let mut child = Command::new(sendmail)
+        .arg("-t")
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(|err| format!("Could not run {}: {}", sendmail, err))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin
+            .write_all(message.as_bytes())
+            .map_err(|err| format!("Could not write to {}: {}", sendmail, err))?;
+    }
+    let status = child
+        .wait()
+        .map_err(|err| format!("Could not wait for {}: {}", sendmail, err))?;
+    if !status.success() {
+        error!(%status, "sendmail failed");
+        return Err(format!("{} exited with {}", sendmail, status));
+    }
+    info!(to = %logging::email(to), "Mail sent");
+    Ok(())
+}
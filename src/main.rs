@@ -2,7 +2,8 @@
 
 #[macro_use]
 extern crate diesel;
-extern crate dotenv;
+#[macro_use]
+extern crate diesel_migrations;
 #[macro_use]
 extern crate lazy_static;
 #[macro_use]
@@ -10,23 +11,77 @@ extern crate rocket;
 #[macro_use]
 extern crate serde_derive;
 
+mod account;
 mod api;
+mod audit;
 mod cache;
+mod cli;
+mod config;
 mod crypto;
+mod health;
 mod locker;
+mod logging;
+mod mail;
+mod metrics;
 mod persistence;
+mod recovery;
+mod tls;
+mod two_factor;
 mod user;
 mod util;
 
 pub mod models;
 pub mod schema;
 
-fn main() -> Result<(), &'static str> {
-    init().map_err(|err| panic!("Error pre-initializing app: {:?}", err))?;
-    api::init().map_err(|err| panic!("Error initializing api: {:?}", err))
+use std::env;
+use std::process;
+use tracing::{error, info};
+
+use cli::Command;
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let command = cli::parse(&args).unwrap_or_else(|err| {
+        eprintln!("{}\n\n{}", err, cli::USAGE);
+        process::exit(2);
+    });
+    let config = config::init().unwrap_or_else(|err| {
+        eprintln!("Invalid configuration: {}", err);
+        process::exit(1);
+    });
+    if let Err(err) = logging::init(&config.log) {
+        eprintln!("Error initializing logging: {}", err);
+        process::exit(1);
+    }
+    info!(profile = config.profile.as_str(), "Configuration loaded");
+    if !matches!(command, Command::Serve) {
+        if let Err(err) = cli::run(command) {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+        return;
+    }
+    if let Err(err) = init() {
+        error!(error = %err, "Startup failed");
+        process::exit(1);
+    }
+    if let Err(err) = api::init() {
+        error!(error = %err, "Error initializing api");
+        process::exit(1);
+    }
 }
 
-fn init() -> Result<(), std::io::Error> {
-    crypto::init()?;
+// Everything a request may need is checked before the server starts accepting connections
+fn init() -> Result<(), String> {
+    persistence::ping()?;
+    // Before the server key is read, rotate-server-key refuses to run while it is held
+    persistence::share_server_key()?;
+    crypto::init().map_err(|err| format!("Could not initialize the app directory: {}", err))?;
+    match config::get().database.migrations.as_str() {
+        "check" => persistence::check_migrations()?,
+        _ => persistence::run_migrations()?,
+    }
+    persistence::init_encryption()?;
+    persistence::spawn_reencryption();
     Ok(())
 }
@@ -0,0 +1,85 @@
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, register_int_gauge, register_int_gauge_vec,
+    Encoder, HistogramTimer, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, TextEncoder,
+};
+use std::time::Duration;
+use tracing::error;
+
+use crate::api::ApiError;
+use crate::cache;
+
+// Labels are only ever route/query/operation names known at compile time, never user input, so cardinality stays bounded
+lazy_static! {
+    static ref HTTP_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "keypost_http_requests_total",
+        "HTTP requests by route and response status.",
+        &["route", "status"]
+    )
+    .unwrap();
+    static ref HTTP_REQUEST_DURATION: HistogramVec = register_histogram_vec!(
+        "keypost_http_request_duration_seconds",
+        "HTTP request latency by route.",
+        &["route"]
+    )
+    .unwrap();
+    static ref OPAQUE_FAILURES: IntCounterVec = register_int_counter_vec!(
+        "keypost_opaque_failures_total",
+        "Failed OPAQUE protocol steps by operation and reason.",
+        &["operation", "reason"]
+    )
+    .unwrap();
+    static ref CACHE_ENTRIES: IntGaugeVec = register_int_gauge_vec!(
+        "keypost_cache_entries",
+        "Entries currently held in the in-memory caches.",
+        &["cache"]
+    )
+    .unwrap();
+    static ref ACTIVE_SESSIONS: IntGauge =
+        register_int_gauge!("keypost_active_sessions", "Sessions that have not expired.").unwrap();
+    static ref DB_QUERY_DURATION: HistogramVec = register_histogram_vec!(
+        "keypost_db_query_duration_seconds",
+        "Database call latency, including establishing the connection.",
+        &["query"]
+    )
+    .unwrap();
+}
+
+pub fn observe_request(route: &str, status: u16, elapsed: Duration) {
+    HTTP_REQUESTS
+        .with_label_values(&[route, &status.to_string()])
+        .inc();
+    HTTP_REQUEST_DURATION
+        .with_label_values(&[route])
+        .observe(elapsed.as_secs_f64());
+}
+
+pub fn opaque_failure(operation: &str, reason: &str) {
+    OPAQUE_FAILURES
+        .with_label_values(&[operation, reason])
+        .inc();
+}
+
+/// Observes the query latency when the returned timer is dropped.
+pub fn db_query_timer(query: &str) -> HistogramTimer {
+    DB_QUERY_DURATION.with_label_values(&[query]).start_timer()
+}
+
+/// Renders every registered metric in the Prometheus text format. Gauges are sampled at scrape time.
+pub fn render() -> Result<String, ApiError> {
+    CACHE_ENTRIES
+        .with_label_values(&["cache"])
+        .set(cache::len() as i64);
+    CACHE_ENTRIES
+        .with_label_values(&["bin_cache"])
+        .set(cache::len_bin() as i64);
+    ACTIVE_SESSIONS.set(cache::active_session_count() as i64);
+
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+        .encode(&prometheus::gather(), &mut buffer)
+        .map_err(|err| {
+            error!(error = ?err, "Error encoding metrics");
+            ApiError::ServerError
+        })?;
+    String::from_utf8(buffer).map_err(|_| ApiError::ServerError)
+}
@@ -1,6 +1,11 @@
 /// Database models (i.e. tables) only!
+use super::schema::audit_events;
+use super::schema::data_keys;
 use super::schema::lockers;
+use super::schema::recovery_codes;
+use super::schema::totp_secrets;
 use super::schema::users;
+use super::schema::webauthn_credentials;
 use diesel::pg::data_types::PgTimestamp;
 
 // Fields must be in the same order as in schema.rs https://diesel.rs/guides/getting-started
@@ -8,35 +13,178 @@ use diesel::pg::data_types::PgTimestamp;
 pub struct User {
     pub id: i32,
     pub email: String,
-    pub psswd_file: String,
+    pub psswd_file: Vec<u8>,
     pub deleted: bool,
     pub inserted_at: PgTimestamp,
     pub updated_at: PgTimestamp,
+    pub recovery_psswd_file: Option<Vec<u8>>,
+    pub data_key_id: Option<i32>,
+    pub columns_bound: bool,
 }
 
 #[derive(Clone, Insertable)]
 #[table_name = "users"]
 pub struct NewUser<'a> {
+    // Taken from the sequence up front, since the encrypted columns are bound to it
+    pub id: i32,
     pub email: &'a str,
-    pub psswd_file: &'a str,
+    pub psswd_file: &'a [u8],
+    pub recovery_psswd_file: Option<&'a [u8]>,
+    pub data_key_id: Option<i32>,
+    pub columns_bound: bool,
 }
 
 #[derive(Clone, Queryable)]
 pub struct Locker {
     pub id: i32,
-    pub email: String,
     pub locker_id: String,
-    pub psswd_file: String,
-    pub ciphertext: String,
+    pub psswd_file: Vec<u8>,
+    pub ciphertext: Vec<u8>,
     pub inserted_at: PgTimestamp,
     pub updated_at: PgTimestamp,
+    pub user_id: i32,
+    pub version: i32,
+    pub data_key_id: Option<i32>,
+    pub kind: String,
+    pub label: Option<Vec<u8>>,
+    pub tags: Option<Vec<u8>>,
+    pub custom_fields: Option<Vec<u8>>,
+    pub icon_hash: Option<String>,
+    pub columns_bound: bool,
 }
 
 #[derive(Clone, Insertable)]
 #[table_name = "lockers"]
 pub struct NewLocker<'a> {
-    pub email: &'a str,
+    // Like NewUser's, taken from the sequence up front
+    pub id: i32,
+    pub user_id: i32,
     pub locker_id: &'a str,
-    pub psswd_file: &'a str,
-    pub ciphertext: &'a str,
+    pub psswd_file: &'a [u8],
+    pub ciphertext: &'a [u8],
+    pub data_key_id: Option<i32>,
+    pub columns_bound: bool,
+}
+
+#[derive(Clone, Queryable)]
+pub struct LockerMetadata {
+    pub locker_id: String,
+    pub version: i32,
+    pub kind: String,
+    pub label: Option<Vec<u8>>,
+    pub tags: Option<Vec<u8>>,
+    pub custom_fields: Option<Vec<u8>>,
+    pub icon_hash: Option<String>,
+}
+
+// Replaces all of a locker's metadata, a missing field clears it
+#[derive(Clone, AsChangeset)]
+#[table_name = "lockers"]
+#[changeset_options(treat_none_as_null = "true")]
+pub struct LockerMetadataChanges<'a> {
+    pub kind: &'a str,
+    pub label: Option<&'a [u8]>,
+    pub tags: Option<&'a [u8]>,
+    pub custom_fields: Option<&'a [u8]>,
+    pub icon_hash: Option<&'a str>,
+}
+
+#[derive(Clone, Queryable)]
+pub struct TotpSecret {
+    pub id: i32,
+    pub user_id: i32,
+    pub secret: String,
+    pub enabled: bool,
+    pub last_used_step: i64,
+    pub inserted_at: PgTimestamp,
+    pub updated_at: PgTimestamp,
+}
+
+#[derive(Clone, Insertable)]
+#[table_name = "totp_secrets"]
+pub struct NewTotpSecret<'a> {
+    pub user_id: i32,
+    pub secret: &'a str,
+}
+
+#[derive(Clone, Queryable)]
+pub struct RecoveryCode {
+    pub id: i32,
+    pub user_id: i32,
+    pub code_hash: String,
+    pub used: bool,
+    pub inserted_at: PgTimestamp,
+    pub updated_at: PgTimestamp,
+}
+
+#[derive(Clone, Insertable)]
+#[table_name = "recovery_codes"]
+pub struct NewRecoveryCode<'a> {
+    pub user_id: i32,
+    pub code_hash: &'a str,
+}
+
+#[derive(Clone, Queryable)]
+pub struct WebAuthnCredential {
+    pub id: i32,
+    pub user_id: i32,
+    pub credential_id: String,
+    pub public_key: String,
+    pub sign_count: i64,
+    pub inserted_at: PgTimestamp,
+    pub updated_at: PgTimestamp,
+}
+
+#[derive(Clone, Insertable)]
+#[table_name = "webauthn_credentials"]
+pub struct NewWebAuthnCredential<'a> {
+    pub user_id: i32,
+    pub credential_id: &'a str,
+    pub public_key: &'a str,
+    pub sign_count: i64,
+}
+
+#[derive(Clone, Queryable, QueryableByName)]
+#[table_name = "audit_events"]
+pub struct AuditEvent {
+    pub id: i32,
+    pub user_id: Option<i32>,
+    pub event_type: String,
+    pub locker_id: Option<String>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub outcome: String,
+    pub occurred_at: i64,
+    pub prev_hash: String,
+    pub hash: String,
+    pub inserted_at: PgTimestamp,
+}
+
+#[derive(Clone, Insertable)]
+#[table_name = "audit_events"]
+pub struct NewAuditEvent {
+    pub user_id: Option<i32>,
+    pub event_type: String,
+    pub locker_id: Option<String>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub outcome: String,
+    pub occurred_at: i64,
+    pub prev_hash: String,
+    pub hash: String,
+}
+
+#[derive(Clone, Queryable)]
+pub struct DataKey {
+    pub id: i32,
+    pub master_key_id: String,
+    pub wrapped_key: Vec<u8>,
+    pub inserted_at: PgTimestamp,
+}
+
+#[derive(Clone, Insertable)]
+#[table_name = "data_keys"]
+pub struct NewDataKey<'a> {
+    pub master_key_id: &'a str,
+    pub wrapped_key: &'a [u8],
 }
@@ -1,123 +1,819 @@
 use diesel::pg::PgConnection;
 use diesel::prelude::*;
 use diesel::result::Error;
-use dotenv::dotenv;
-use std::env;
+use diesel::sql_types::{BigInt, Bool, Integer, Nullable, Varchar};
+use std::sync::{Mutex, OnceLock};
 
-use crate::models::{Locker, NewLocker, NewUser, User};
+use super::encryption::{active_data_key_id, next_row_id, open_column, seal_column, Row};
+use crate::config;
+use crate::crypto::KeyPurpose;
+use crate::metrics;
+use crate::models::{
+    AuditEvent, DataKey, Locker, LockerMetadata, LockerMetadataChanges, NewAuditEvent, NewLocker,
+    NewRecoveryCode, NewTotpSecret, NewUser, NewWebAuthnCredential, RecoveryCode, TotpSecret, User,
+    WebAuthnCredential,
+};
 use crate::schema::lockers;
 use crate::schema::users;
 
-pub fn get_active_users() -> Result<Vec<User>, Error> {
-    use crate::schema::users::dsl::*;
-    let connection = establish_connection();
-    let results = users
-        .filter(deleted.eq(false))
-        .limit(99)
-        .load::<User>(&connection)?;
+const AUDIT_CHAIN_LOCK_ID: i64 = 0x6b65_7970_6f73_7401;
+const SERVER_KEY_LOCK_ID: i64 = 0x6b65_7970_6f73_7404;
+
+/// A stored row whose password files or locker contents can't be read back, e.g. they no longer decrypt. Reads return
+/// it boxed in `Error::DeserializationError`, so callers keep matching on diesel errors.
+#[derive(Debug, thiserror::Error)]
+#[error("Corrupt {table} record {id}: {reason}")]
+pub struct CorruptRecord {
+    pub table: &'static str,
+    pub id: i32,
+    pub reason: String,
+}
 
-    println!("Displaying {} users", results.len());
-    for user in &results {
-        println!("{}", user.email);
+impl CorruptRecord {
+    /// The corrupt record behind a persistence error, if that is what it is.
+    pub fn find(err: &Error) -> Option<&CorruptRecord> {
+        match err {
+            Error::DeserializationError(cause) => cause.downcast_ref::<CorruptRecord>(),
+            _ => None,
+        }
     }
+}
+
+// Failing to decode a row's own bytes marks the row, database failures pass through
+pub(super) fn corrupt_record(table: &'static str, id: i32, err: Error) -> Error {
+    match err {
+        Error::DeserializationError(cause) => {
+            Error::DeserializationError(Box::new(CorruptRecord {
+                table,
+                id,
+                reason: cause.to_string(),
+            }))
+        }
+        err => err,
+    }
+}
 
-    Ok(results)
+/// Connects and runs a trivial query, reporting the failure instead of panicking.
+pub fn ping() -> Result<(), String> {
+    let _timer = metrics::db_query_timer("ping");
+    let connection = try_establish_connection()?;
+    diesel::sql_query("SELECT 1")
+        .execute(&connection)
+        .map(|_| ())
+        .map_err(|err| format!("Database query failed: {}", err))
 }
 
 pub fn find_user(email_: &str) -> Result<Option<User>, Error> {
     use crate::schema::users::dsl::*;
+    let _timer = metrics::db_query_timer("find_user");
     let connection = establish_connection();
     let results: Vec<User> = users
         .filter(deleted.eq(false))
         .filter(email.eq(email_))
         .limit(1)
         .load::<User>(&connection)?;
-    let user: Option<User> = results.first().cloned();
+    match results.into_iter().next() {
+        Some(user) => Ok(Some(decrypt_user(&connection, user)?)),
+        None => Ok(None),
+    }
+}
+
+// Reads hand out the OPAQUE records decrypted
+fn decrypt_user(connection: &PgConnection, mut user: User) -> Result<User, Error> {
+    let row = Row::user(user.id, user.columns_bound);
+    user.psswd_file = open_column(
+        connection,
+        user.data_key_id,
+        KeyPurpose::UserPasswordFile,
+        row,
+        &user.psswd_file,
+    )
+    .map_err(|err| corrupt_record("users", user.id, err))?;
+    user.recovery_psswd_file = match &user.recovery_psswd_file {
+        Some(stored) => Some(
+            open_column(
+                connection,
+                user.data_key_id,
+                KeyPurpose::UserRecoveryPasswordFile,
+                row,
+                stored,
+            )
+            .map_err(|err| corrupt_record("users", user.id, err))?,
+        ),
+        None => None,
+    };
     Ok(user)
 }
 
-pub fn add_user<'a>(email: &'a str, psswd_file: &'a str) -> Result<User, Error> {
-    let new_user = NewUser { email, psswd_file };
+pub fn add_user(
+    email: &str,
+    psswd_file: &[u8],
+    recovery_psswd_file: Option<&[u8]>,
+) -> Result<User, Error> {
+    let _timer = metrics::db_query_timer("add_user");
     let connection = establish_connection();
-    diesel::insert_into(users::table)
+    let row = Row::user(next_row_id(&connection, "users")?, true);
+    let psswd_file = seal_column(KeyPurpose::UserPasswordFile, row, psswd_file);
+    let recovery_psswd_file =
+        recovery_psswd_file.map(|r| seal_column(KeyPurpose::UserRecoveryPasswordFile, row, r));
+    let new_user = NewUser {
+        id: row.id,
+        email,
+        psswd_file: &psswd_file,
+        recovery_psswd_file: recovery_psswd_file.as_deref(),
+        data_key_id: Some(active_data_key_id()),
+        columns_bound: true,
+    };
+    let user: User = diesel::insert_into(users::table)
         .values(&new_user)
-        .get_result(&connection)
+        .get_result(&connection)?;
+    decrypt_user(&connection, user)
 }
 
-pub fn store_locker_contents(
+/// Replaces both the password and recovery OPAQUE records, i.e. after account recovery.
+pub fn update_password_files(
+    user_id: i32,
+    psswd_file_arg: &[u8],
+    recovery_psswd_file_arg: &[u8],
+) -> Result<usize, Error> {
+    use crate::schema::users::dsl::*;
+    let _timer = metrics::db_query_timer("update_password_files");
+    let connection = establish_connection();
+    let row = Row::user(user_id, true);
+    diesel::update(users.filter(id.eq(user_id)))
+        .set((
+            psswd_file.eq(seal_column(
+                KeyPurpose::UserPasswordFile,
+                row,
+                psswd_file_arg,
+            )),
+            recovery_psswd_file.eq(Some(seal_column(
+                KeyPurpose::UserRecoveryPasswordFile,
+                row,
+                recovery_psswd_file_arg,
+            ))),
+            data_key_id.eq(Some(active_data_key_id())),
+            columns_bound.eq(true),
+            updated_at.eq(diesel::dsl::now),
+        ))
+        .execute(&connection)
+}
+
+pub fn update_recovery_password_file(
+    user_id: i32,
+    recovery_psswd_file_arg: &[u8],
+) -> Result<usize, Error> {
+    use crate::schema::users::dsl::*;
+    let _timer = metrics::db_query_timer("update_recovery_password_file");
+    let connection = establish_connection();
+    // A row is encrypted under a single data key, so the password file is re-encrypted along with it
+    connection.transaction::<_, Error, _>(|| {
+        let row: Option<(Vec<u8>, Option<i32>, bool)> = users
+            .filter(id.eq(user_id))
+            .select((psswd_file, data_key_id, columns_bound))
+            .for_update()
+            .first(&connection)
+            .optional()?;
+        let (stored_psswd_file, row_data_key_id, bound) = match row {
+            Some(row) => row,
+            None => return Ok(0),
+        };
+        let plain_psswd_file = open_column(
+            &connection,
+            row_data_key_id,
+            KeyPurpose::UserPasswordFile,
+            Row::user(user_id, bound),
+            &stored_psswd_file,
+        )
+        .map_err(|err| corrupt_record("users", user_id, err))?;
+        let row = Row::user(user_id, true);
+        diesel::update(users.filter(id.eq(user_id)))
+            .set((
+                psswd_file.eq(seal_column(
+                    KeyPurpose::UserPasswordFile,
+                    row,
+                    &plain_psswd_file,
+                )),
+                recovery_psswd_file.eq(Some(seal_column(
+                    KeyPurpose::UserRecoveryPasswordFile,
+                    row,
+                    recovery_psswd_file_arg,
+                ))),
+                data_key_id.eq(Some(active_data_key_id())),
+                columns_bound.eq(true),
+                updated_at.eq(diesel::dsl::now),
+            ))
+            .execute(&connection)
+    })
+}
+
+/// Moves the user to a new email along with the OPAQUE records registered for it. Everything else references the
+/// user by id, so this single update is the whole change. A taken email fails with a UniqueViolation.
+pub fn change_email(
+    user_id: i32,
+    email_arg: &str,
+    psswd_file_arg: &[u8],
+    recovery_psswd_file_arg: Option<&[u8]>,
+) -> Result<usize, Error> {
+    use crate::schema::users::dsl::*;
+    let _timer = metrics::db_query_timer("change_email");
+    let connection = establish_connection();
+    let row = Row::user(user_id, true);
+    diesel::update(users.filter(id.eq(user_id)).filter(deleted.eq(false)))
+        .set((
+            email.eq(email_arg),
+            psswd_file.eq(seal_column(
+                KeyPurpose::UserPasswordFile,
+                row,
+                psswd_file_arg,
+            )),
+            recovery_psswd_file.eq(recovery_psswd_file_arg
+                .map(|r| seal_column(KeyPurpose::UserRecoveryPasswordFile, row, r))),
+            data_key_id.eq(Some(active_data_key_id())),
+            columns_bound.eq(true),
+            updated_at.eq(diesel::dsl::now),
+        ))
+        .execute(&connection)
+}
+
+/// Runs `f` in one transaction, committed only if it returns Ok. The functions taking a connection are its steps.
+pub fn transaction<T, E, F>(f: F) -> Result<T, E>
+where
+    F: FnOnce(&PgConnection) -> Result<T, E>,
+    E: From<Error>,
+{
+    let connection = establish_connection();
+    connection.transaction(|| f(&connection))
+}
+
+pub fn insert_locker(
+    connection: &PgConnection,
     email: &str,
     locker_id: &str,
     psswd_file: &[u8],
     ciphertext: &[u8],
 ) -> Result<Locker, Error> {
-    add_locker(
-        email,
-        locker_id,
-        base64::encode(psswd_file).as_str(),
-        base64::encode(ciphertext).as_str(),
-    )
-}
-
-fn add_locker<'a>(
-    email: &'a str,
-    locker_id: &'a str,
-    psswd_file: &'a str,
-    ciphertext: &'a str,
-) -> Result<Locker, Error> {
+    let _timer = metrics::db_query_timer("insert_locker");
+    let row = Row::locker(
+        next_row_id(connection, "lockers")?,
+        active_user_id(connection, email)?,
+        true,
+    );
+    let psswd_file = seal_column(KeyPurpose::LockerPasswordFile, row, psswd_file);
+    let ciphertext = seal_column(KeyPurpose::LockerCiphertext, row, ciphertext);
     let new_locker: NewLocker = NewLocker {
-        email,
+        id: row.id,
+        user_id: row.user_id,
         locker_id,
-        psswd_file,
-        ciphertext,
+        psswd_file: &psswd_file,
+        ciphertext: &ciphertext,
+        data_key_id: Some(active_data_key_id()),
+        columns_bound: true,
     };
-    let connection = establish_connection();
-    diesel::insert_into(lockers::table)
+    let locker: Locker = diesel::insert_into(lockers::table)
         .values(&new_locker)
-        .get_result(&connection)
+        .get_result(connection)?;
+    decrypt_locker(connection, locker)
 }
 
-pub fn fetch_locker_contents(
+// Like users, lockers are handed out with their contents decrypted
+fn decrypt_locker(connection: &PgConnection, mut locker: Locker) -> Result<Locker, Error> {
+    let row = Row::locker(locker.id, locker.user_id, locker.columns_bound);
+    locker.psswd_file = open_column(
+        connection,
+        locker.data_key_id,
+        KeyPurpose::LockerPasswordFile,
+        row,
+        &locker.psswd_file,
+    )
+    .map_err(|err| corrupt_record("lockers", locker.id, err))?;
+    locker.ciphertext = open_column(
+        connection,
+        locker.data_key_id,
+        KeyPurpose::LockerCiphertext,
+        row,
+        &locker.ciphertext,
+    )
+    .map_err(|err| corrupt_record("lockers", locker.id, err))?;
+    Ok(locker)
+}
+
+// Lockers belong to a user row, the email is only how the session identifies it
+fn active_user_id(connection: &PgConnection, email_arg: &str) -> Result<i32, Error> {
+    users::table
+        .filter(users::email.eq(email_arg))
+        .filter(users::deleted.eq(false))
+        .select(users::id)
+        .first(connection)
+}
+
+pub fn find_locker(
+    connection: &PgConnection,
     email_arg: &str,
     locker_id_arg: &str,
-) -> Result<(Vec<u8>, Vec<u8>), Error> {
+) -> Result<Locker, Error> {
     use crate::schema::lockers::dsl::*;
-    let connection = establish_connection();
-    let results: Vec<Locker> = lockers
+    let _timer = metrics::db_query_timer("find_locker");
+    let locker = lockers
         .filter(locker_id.eq(locker_id_arg))
-        .filter(email.eq(email_arg))
-        .limit(1)
-        .load::<Locker>(&connection)?;
-    let locker: Locker = results.first().cloned().ok_or(Error::NotFound)?;
-    Ok((
-        base64::decode(locker.psswd_file).expect("Error decoding locker contents!"),
-        base64::decode(locker.ciphertext).expect("Error decoding locker contents!"),
-    ))
+        .filter(user_id.eq(active_user_id(connection, email_arg)?))
+        .first::<Locker>(connection)?;
+    decrypt_locker(connection, locker)
 }
 
-pub fn fetch_lockers(email_arg: &str) -> Result<Vec<Locker>, Error> {
+/// The metadata of all the user's lockers, without their password files or contents.
+pub fn fetch_locker_metadata(email_arg: &str) -> Result<Vec<LockerMetadata>, Error> {
     use crate::schema::lockers::dsl::*;
+    let _timer = metrics::db_query_timer("fetch_locker_metadata");
     let connection = establish_connection();
-    let results: Vec<Locker> = lockers
-        .filter(email.eq(email_arg))
-        .load::<Locker>(&connection)?;
-    Ok(results)
+    lockers
+        .filter(user_id.eq(active_user_id(&connection, email_arg)?))
+        .order(locker_id.asc())
+        .select((
+            locker_id,
+            version,
+            kind,
+            label,
+            tags,
+            custom_fields,
+            icon_hash,
+        ))
+        .load::<LockerMetadata>(&connection)
 }
 
-pub fn delete_locker_contents(email_arg: &str, locker_id_arg: &str) -> Result<usize, Error> {
+/// Replaces the locker's metadata. Its contents and version stay as they are, so opens in progress are unaffected.
+/// Returns 0 if there is no such locker.
+pub fn update_locker_metadata(
+    email_arg: &str,
+    locker_id_arg: &str,
+    changes: &LockerMetadataChanges,
+) -> Result<usize, Error> {
     use crate::schema::lockers::dsl::*;
+    let _timer = metrics::db_query_timer("update_locker_metadata");
     let connection = establish_connection();
-    diesel::delete(
+    diesel::update(
         lockers
             .filter(locker_id.eq(locker_id_arg))
-            .filter(email.eq(email_arg)),
+            .filter(user_id.eq(active_user_id(&connection, email_arg)?)),
     )
+    .set((changes, updated_at.eq(diesel::dsl::now)))
     .execute(&connection)
 }
 
+/// Replaces the locker's contents if it is still at `version_arg`, bumping the version. Returns 0 if it changed since.
+pub fn rotate_locker(
+    connection: &PgConnection,
+    email_arg: &str,
+    locker_id_arg: &str,
+    version_arg: i32,
+    psswd_file_arg: &[u8],
+    ciphertext_arg: &[u8],
+) -> Result<usize, Error> {
+    use crate::schema::lockers::dsl::*;
+    let _timer = metrics::db_query_timer("rotate_locker");
+    let user_id_arg = active_user_id(connection, email_arg)?;
+    let row_id: Option<i32> = lockers
+        .filter(locker_id.eq(locker_id_arg))
+        .filter(user_id.eq(user_id_arg))
+        .select(id)
+        .first(connection)
+        .optional()?;
+    let row = match row_id {
+        Some(row_id) => Row::locker(row_id, user_id_arg, true),
+        None => return Ok(0),
+    };
+    diesel::update(
+        lockers
+            .filter(id.eq(row.id))
+            .filter(version.eq(version_arg)),
+    )
+    .set((
+        psswd_file.eq(seal_column(
+            KeyPurpose::LockerPasswordFile,
+            row,
+            psswd_file_arg,
+        )),
+        ciphertext.eq(seal_column(
+            KeyPurpose::LockerCiphertext,
+            row,
+            ciphertext_arg,
+        )),
+        data_key_id.eq(Some(active_data_key_id())),
+        columns_bound.eq(true),
+        version.eq(version + 1),
+        updated_at.eq(diesel::dsl::now),
+    ))
+    .execute(connection)
+}
+
+/// Deletes the locker if it is still at `version_arg`. Returns 0 if it changed (or was deleted) since.
+pub fn delete_locker(
+    connection: &PgConnection,
+    email_arg: &str,
+    locker_id_arg: &str,
+    version_arg: i32,
+) -> Result<usize, Error> {
+    use crate::schema::lockers::dsl::*;
+    let _timer = metrics::db_query_timer("delete_locker");
+    diesel::delete(
+        lockers
+            .filter(locker_id.eq(locker_id_arg))
+            .filter(user_id.eq(active_user_id(connection, email_arg)?))
+            .filter(version.eq(version_arg)),
+    )
+    .execute(connection)
+}
+
+pub fn find_totp_secret(user_id_arg: i32) -> Result<Option<TotpSecret>, Error> {
+    use crate::schema::totp_secrets::dsl::*;
+    let _timer = metrics::db_query_timer("find_totp_secret");
+    let connection = establish_connection();
+    totp_secrets
+        .filter(user_id.eq(user_id_arg))
+        .first::<TotpSecret>(&connection)
+        .optional()
+}
+
+/// Stores a new (not yet enabled) TOTP secret, replacing any previous pending one.
+pub fn store_totp_secret(
+    connection: &PgConnection,
+    user_id_arg: i32,
+    secret_arg: &str,
+) -> Result<TotpSecret, Error> {
+    use crate::schema::totp_secrets::dsl::*;
+    let new_totp_secret = NewTotpSecret {
+        user_id: user_id_arg,
+        secret: secret_arg,
+    };
+    let _timer = metrics::db_query_timer("store_totp_secret");
+    diesel::insert_into(totp_secrets)
+        .values(&new_totp_secret)
+        .on_conflict(user_id)
+        .do_update()
+        .set((
+            secret.eq(secret_arg),
+            enabled.eq(false),
+            last_used_step.eq(0),
+            updated_at.eq(diesel::dsl::now),
+        ))
+        .get_result(connection)
+}
+
+pub fn enable_totp_secret(user_id_arg: i32) -> Result<usize, Error> {
+    use crate::schema::totp_secrets::dsl::*;
+    let _timer = metrics::db_query_timer("enable_totp_secret");
+    let connection = establish_connection();
+    diesel::update(totp_secrets.filter(user_id.eq(user_id_arg)))
+        .set((enabled.eq(true), updated_at.eq(diesel::dsl::now)))
+        .execute(&connection)
+}
+
+/// Only moves forward, so a TOTP code (i.e. step) that was already used can't be used again. Returns false on replay.
+pub fn update_totp_last_used_step(user_id_arg: i32, step: i64) -> Result<bool, Error> {
+    use crate::schema::totp_secrets::dsl::*;
+    let _timer = metrics::db_query_timer("update_totp_last_used_step");
+    let connection = establish_connection();
+    let updated = diesel::update(
+        totp_secrets
+            .filter(user_id.eq(user_id_arg))
+            .filter(last_used_step.lt(step)),
+    )
+    .set((last_used_step.eq(step), updated_at.eq(diesel::dsl::now)))
+    .execute(&connection)?;
+    Ok(updated == 1)
+}
+
+/// Whether the user has a TOTP secret, enabled or still waiting for confirmation.
+pub fn has_totp_secret(connection: &PgConnection, user_id_arg: i32) -> Result<bool, Error> {
+    use crate::schema::totp_secrets::dsl::*;
+    let _timer = metrics::db_query_timer("has_totp_secret");
+    diesel::select(diesel::dsl::exists(
+        totp_secrets.filter(user_id.eq(user_id_arg)),
+    ))
+    .get_result(connection)
+}
+
+pub fn delete_totp_secret(connection: &PgConnection, user_id_arg: i32) -> Result<usize, Error> {
+    use crate::schema::totp_secrets::dsl::*;
+    let _timer = metrics::db_query_timer("delete_totp_secret");
+    diesel::delete(totp_secrets.filter(user_id.eq(user_id_arg))).execute(connection)
+}
+
+/// Replaces all of the user's recovery codes (used or not) with the given hashes.
+pub fn replace_recovery_codes(
+    connection: &PgConnection,
+    user_id_arg: i32,
+    code_hashes: &[String],
+) -> Result<usize, Error> {
+    use crate::schema::recovery_codes::dsl::*;
+    let new_recovery_codes: Vec<NewRecoveryCode> = code_hashes
+        .iter()
+        .map(|hash| NewRecoveryCode {
+            user_id: user_id_arg,
+            code_hash: hash,
+        })
+        .collect();
+    let _timer = metrics::db_query_timer("replace_recovery_codes");
+    diesel::delete(recovery_codes.filter(user_id.eq(user_id_arg))).execute(connection)?;
+    diesel::insert_into(recovery_codes)
+        .values(&new_recovery_codes)
+        .execute(connection)
+}
+
+/// Marks the recovery code as used. Returns false if there is no unused code with that hash.
+pub fn use_recovery_code(user_id_arg: i32, code_hash_arg: &str) -> Result<bool, Error> {
+    use crate::schema::recovery_codes::dsl::*;
+    let _timer = metrics::db_query_timer("use_recovery_code");
+    let connection = establish_connection();
+    let updated = diesel::update(
+        recovery_codes
+            .filter(user_id.eq(user_id_arg))
+            .filter(code_hash.eq(code_hash_arg))
+            .filter(used.eq(false)),
+    )
+    .set((used.eq(true), updated_at.eq(diesel::dsl::now)))
+    .execute(&connection)?;
+    Ok(updated == 1)
+}
+
+pub fn delete_recovery_codes(connection: &PgConnection, user_id_arg: i32) -> Result<usize, Error> {
+    use crate::schema::recovery_codes::dsl::*;
+    let _timer = metrics::db_query_timer("delete_recovery_codes");
+    diesel::delete(recovery_codes.filter(user_id.eq(user_id_arg))).execute(connection)
+}
+
+pub fn find_webauthn_credentials(user_id_arg: i32) -> Result<Vec<WebAuthnCredential>, Error> {
+    use crate::schema::webauthn_credentials::dsl::*;
+    let _timer = metrics::db_query_timer("find_webauthn_credentials");
+    let connection = establish_connection();
+    webauthn_credentials
+        .filter(user_id.eq(user_id_arg))
+        .load::<WebAuthnCredential>(&connection)
+}
+
+pub fn add_webauthn_credential(
+    user_id_arg: i32,
+    credential_id_arg: &str,
+    public_key_arg: &str,
+    sign_count_arg: i64,
+) -> Result<WebAuthnCredential, Error> {
+    use crate::schema::webauthn_credentials::dsl::*;
+    let new_credential = NewWebAuthnCredential {
+        user_id: user_id_arg,
+        credential_id: credential_id_arg,
+        public_key: public_key_arg,
+        sign_count: sign_count_arg,
+    };
+    let _timer = metrics::db_query_timer("add_webauthn_credential");
+    let connection = establish_connection();
+    diesel::insert_into(webauthn_credentials)
+        .values(&new_credential)
+        .get_result(&connection)
+}
+
+/// Only moves forward, so a concurrent assertion with the same (or an older) counter is rejected. Returns false in that case.
+pub fn update_webauthn_sign_count(id_arg: i32, sign_count_arg: i64) -> Result<bool, Error> {
+    use crate::schema::webauthn_credentials::dsl::*;
+    let _timer = metrics::db_query_timer("update_webauthn_sign_count");
+    let connection = establish_connection();
+    let updated = diesel::update(
+        webauthn_credentials
+            .filter(id.eq(id_arg))
+            .filter(sign_count.lt(sign_count_arg).or(sign_count.eq(0))),
+    )
+    .set((
+        sign_count.eq(sign_count_arg),
+        updated_at.eq(diesel::dsl::now),
+    ))
+    .execute(&connection)?;
+    Ok(updated == 1)
+}
+
+pub fn has_webauthn_credentials(
+    connection: &PgConnection,
+    user_id_arg: i32,
+) -> Result<bool, Error> {
+    use crate::schema::webauthn_credentials::dsl::*;
+    let _timer = metrics::db_query_timer("has_webauthn_credentials");
+    diesel::select(diesel::dsl::exists(
+        webauthn_credentials.filter(user_id.eq(user_id_arg)),
+    ))
+    .get_result(connection)
+}
+
+pub fn delete_webauthn_credentials(
+    connection: &PgConnection,
+    user_id_arg: i32,
+) -> Result<usize, Error> {
+    use crate::schema::webauthn_credentials::dsl::*;
+    let _timer = metrics::db_query_timer("delete_webauthn_credentials");
+    diesel::delete(webauthn_credentials.filter(user_id.eq(user_id_arg))).execute(connection)
+}
+
+/// Appends to the audit hash chain: `chain_hash` is given the event with `prev_hash` set to the latest entry's hash and
+/// returns its hash. Serialized across app instances by a transaction-scoped advisory lock so the chain never forks.
+pub fn append_audit_event<F>(mut event: NewAuditEvent, chain_hash: F) -> Result<AuditEvent, Error>
+where
+    F: FnOnce(&NewAuditEvent) -> String,
+{
+    use crate::schema::audit_events::dsl::*;
+    let _timer = metrics::db_query_timer("append_audit_event");
+    let connection = establish_connection();
+    connection.transaction(|| {
+        diesel::sql_query(format!(
+            "SELECT pg_advisory_xact_lock({})",
+            AUDIT_CHAIN_LOCK_ID
+        ))
+        .execute(&connection)?;
+        let latest_hash: Option<String> = audit_events
+            .select(hash)
+            .order(id.desc())
+            .first(&connection)
+            .optional()?;
+        event.prev_hash = latest_hash.unwrap_or_default();
+        event.hash = chain_hash(&event);
+        diesel::insert_into(audit_events)
+            .values(&event)
+            .get_result(&connection)
+    })
+}
+
+/// The user's latest events, each with the hash of the entry before it in the chain (None for the very first entry).
+pub fn fetch_audit_events(
+    user_id_arg: i32,
+    limit: i64,
+) -> Result<Vec<(AuditEvent, Option<String>)>, Error> {
+    let _timer = metrics::db_query_timer("fetch_audit_events");
+    let connection = establish_connection();
+    // One round trip: the preceding entry, usually another user's, comes from a subquery on the primary key
+    let events = diesel::sql_query(
+        "SELECT e.*, (SELECT p.hash FROM audit_events p WHERE p.id < e.id ORDER BY p.id DESC LIMIT 1) \
+         AS preceding_hash FROM audit_events e WHERE e.user_id = $1 ORDER BY e.id DESC LIMIT $2",
+    )
+    .bind::<Integer, _>(user_id_arg)
+    .bind::<BigInt, _>(limit)
+    .load::<AuditEventWithPrecedingHash>(&connection)?;
+    Ok(events
+        .into_iter()
+        .map(|row| (row.event, row.preceding_hash))
+        .collect())
+}
+
+#[derive(QueryableByName)]
+struct AuditEventWithPrecedingHash {
+    #[diesel(embed)]
+    event: AuditEvent,
+    #[sql_type = "Nullable<Varchar>"]
+    preceding_hash: Option<String>,
+}
+
+// Administrative operations, used by the CLI subcommands
+
+/// Every user, including soft-deleted ones, ordered by id.
+pub fn list_users() -> Result<Vec<User>, Error> {
+    use crate::schema::users::dsl::*;
+    let _timer = metrics::db_query_timer("list_users");
+    let connection = establish_connection();
+    users.order(id.asc()).load::<User>(&connection)
+}
+
+/// Soft-deletes the user so they can no longer log in. Returns the number of users changed (0 or 1).
+pub fn disable_user(email_arg: &str) -> Result<usize, Error> {
+    use crate::schema::users::dsl::*;
+    let _timer = metrics::db_query_timer("disable_user");
+    let connection = establish_connection();
+    diesel::update(users.filter(email.eq(email_arg)).filter(deleted.eq(false)))
+        .set((deleted.eq(true), updated_at.eq(diesel::dsl::now)))
+        .execute(&connection)
+}
+
+/// Permanently removes soft-deleted users. Their lockers and second factors go with them (ON DELETE CASCADE).
+pub fn purge_deleted_users() -> Result<usize, Error> {
+    use crate::schema::users::dsl::*;
+    let _timer = metrics::db_query_timer("purge_deleted_users");
+    let connection = establish_connection();
+    diesel::delete(users.filter(deleted.eq(true))).execute(&connection)
+}
+
+/// Rewrites every TOTP secret with `reseal` in one transaction, so either all or none move to a new server key.
+pub fn reseal_totp_secrets<F>(reseal: F) -> Result<usize, Error>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    use crate::schema::totp_secrets::dsl::*;
+    let _timer = metrics::db_query_timer("reseal_totp_secrets");
+    let connection = establish_connection();
+    connection.transaction(|| {
+        let all: Vec<TotpSecret> = totp_secrets.load(&connection)?;
+        for totp_secret in &all {
+            let resealed = reseal(&totp_secret.secret).ok_or(Error::RollbackTransaction)?;
+            diesel::update(totp_secrets.filter(id.eq(totp_secret.id)))
+                .set(secret.eq(resealed))
+                .execute(&connection)?;
+        }
+        Ok(all.len())
+    })
+}
+
+/// Held exclusively by `rotate-server-key` until it returns, the lock goes with its connection.
+pub struct ServerKeyLock {
+    _connection: PgConnection,
+}
+
+// The server's shared hold, kept for as long as it runs
+static SERVER_KEY_SHARED: OnceLock<Mutex<PgConnection>> = OnceLock::new();
+
+#[derive(QueryableByName)]
+struct Locked {
+    #[sql_type = "Bool"]
+    locked: bool,
+}
+
+// Session-level, unlike the other advisory locks, so it outlives any transaction
+fn try_lock_server_key(function: &str) -> Result<Option<PgConnection>, String> {
+    let connection = try_establish_connection()?;
+    let locked = diesel::sql_query(format!(
+        "SELECT {}({}) AS locked",
+        function, SERVER_KEY_LOCK_ID
+    ))
+    .get_result::<Locked>(&connection)
+    .map_err(|err| format!("Could not lock the server key: {}", err))?
+    .locked;
+    Ok(match locked {
+        true => Some(connection),
+        false => None,
+    })
+}
+
+/// Marks this server as using the server key, so it can't be rotated underneath it. Every server instance shares the
+/// lock, it is only refused while a rotation runs.
+pub fn share_server_key() -> Result<(), String> {
+    let connection = try_lock_server_key("pg_try_advisory_lock_shared")?.ok_or_else(|| {
+        "The server key is being rotated, start the server once rotate-server-key is done"
+            .to_string()
+    })?;
+    SERVER_KEY_SHARED
+        .set(Mutex::new(connection))
+        .map_err(|_| "The server key is already locked".to_string())
+}
+
+/// Locks the server key for a rotation. Refused while any server is connected to the database.
+pub fn lock_server_key() -> Result<ServerKeyLock, String> {
+    try_lock_server_key("pg_try_advisory_lock")?
+        .map(|connection| ServerKeyLock {
+            _connection: connection,
+        })
+        .ok_or_else(|| {
+            "A server (or another rotation) is running, stop every server before rotating the server key".to_string()
+        })
+}
+
+pub fn load_all_lockers() -> Result<Vec<Locker>, Error> {
+    use crate::schema::lockers::dsl::*;
+    let connection = establish_connection();
+    lockers.order(id.asc()).load::<Locker>(&connection)
+}
+
+pub fn load_all_data_keys() -> Result<Vec<DataKey>, Error> {
+    use crate::schema::data_keys::dsl::*;
+    let connection = establish_connection();
+    data_keys.order(id.asc()).load::<DataKey>(&connection)
+}
+
+pub fn load_all_totp_secrets() -> Result<Vec<TotpSecret>, Error> {
+    use crate::schema::totp_secrets::dsl::*;
+    let connection = establish_connection();
+    totp_secrets.order(id.asc()).load::<TotpSecret>(&connection)
+}
+
+pub fn load_all_recovery_codes() -> Result<Vec<RecoveryCode>, Error> {
+    use crate::schema::recovery_codes::dsl::*;
+    let connection = establish_connection();
+    recovery_codes
+        .order(id.asc())
+        .load::<RecoveryCode>(&connection)
+}
+
+pub fn load_all_webauthn_credentials() -> Result<Vec<WebAuthnCredential>, Error> {
+    use crate::schema::webauthn_credentials::dsl::*;
+    let connection = establish_connection();
+    webauthn_credentials
+        .order(id.asc())
+        .load::<WebAuthnCredential>(&connection)
+}
+
+pub fn load_all_audit_events() -> Result<Vec<AuditEvent>, Error> {
+    use crate::schema::audit_events::dsl::*;
+    let connection = establish_connection();
+    audit_events.order(id.asc()).load::<AuditEvent>(&connection)
+}
+
 fn establish_connection() -> PgConnection {
-    dotenv().ok();
+    try_establish_connection().unwrap_or_else(|err| panic!("{}", err))
+}
 
-    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    PgConnection::establish(&database_url)
-        .unwrap_or_else(|_| panic!("Error connecting to {}", database_url))
+pub(super) fn try_establish_connection() -> Result<PgConnection, String> {
+    // The URL carries the database password, so only the error is reported
+    PgConnection::establish(&config::get().database.url)
+        .map_err(|err| format!("Error connecting to the database: {}", err))
 }
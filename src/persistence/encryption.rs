@@ -0,0 +1,522 @@
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::result::Error;
+use diesel::sql_types::Integer;
+use opaque_ke::rand::rngs::OsRng;
+use opaque_ke::rand::RngCore;
+use std::collections::HashMap;
+use std::fs;
+use std::sync::{Mutex, OnceLock};
+use tracing::{debug, error, info};
+use zeroize::Zeroizing;
+
+use crate::config::{self, EncryptionConfig};
+use crate::crypto::{self, KeyPurpose};
+use crate::models::{DataKey, NewDataKey};
+use crate::persistence::db::{corrupt_record, try_establish_connection};
+use crate::util;
+
+// Serializes data key creation across app instances, so they all agree on the active one
+const DATA_KEYS_LOCK_ID: i64 = 0x6b65_7970_6f73_7403;
+
+static MASTER_KEYS: OnceLock<HashMap<String, Zeroizing<Vec<u8>>>> = OnceLock::new();
+static ACTIVE_DATA_KEY: OnceLock<i32> = OnceLock::new();
+// Unwrapped data keys by id, the active one is always present after init_encryption()
+static DATA_KEYS: OnceLock<Mutex<HashMap<i32, Zeroizing<Vec<u8>>>>> = OnceLock::new();
+
+fn data_keys_cache() -> &'static Mutex<HashMap<i32, Zeroizing<Vec<u8>>>> {
+    DATA_KEYS.get_or_init(Default::default)
+}
+
+/// Loads the master keys and makes sure a data key wrapped by the current one exists. Must run before any password
+/// file or locker is read or written.
+pub fn init_encryption() -> Result<(), String> {
+    let encryption = &config::get().encryption;
+    MASTER_KEYS
+        .set(load_master_keys(encryption)?)
+        .map_err(|_| "Encryption was already initialized".to_string())?;
+    let connection = try_establish_connection()?;
+    let data_key_id = active_data_key(&connection, &encryption.master_key_id)
+        .map_err(|err| format!("Could not set up the data encryption key: {}", err))?;
+    ACTIVE_DATA_KEY
+        .set(data_key_id)
+        .map_err(|_| "Encryption was already initialized".to_string())?;
+    info!(
+        master_key_id = %encryption.master_key_id,
+        data_key_id,
+        "Encryption at rest initialized"
+    );
+    Ok(())
+}
+
+fn load_master_keys(
+    encryption: &EncryptionConfig,
+) -> Result<HashMap<String, Zeroizing<Vec<u8>>>, String> {
+    let mut master_keys = HashMap::new();
+    if let Some(file) = &encryption.master_key_file {
+        util::check_private_permissions(file).map_err(|err| err.to_string())?;
+        let contents = Zeroizing::new(
+            fs::read_to_string(file).map_err(|err| format!("Could not read {}: {}", file, err))?,
+        );
+        let entries: HashMap<String, String> = toml::from_str(&contents)
+            .map_err(|err| format!("Could not parse master key file {}: {}", file, err))?;
+        for (id, key) in entries {
+            master_keys.insert(id.clone(), decode_master_key(&id, &key)?);
+        }
+    }
+    if let Some(key) = &encryption.master_key {
+        let id = &encryption.master_key_id;
+        master_keys.insert(id.clone(), decode_master_key(id, key)?);
+    }
+    if !master_keys.contains_key(&encryption.master_key_id) {
+        return Err(format!(
+            "The current master key `{}` is not configured",
+            encryption.master_key_id
+        ));
+    }
+    Ok(master_keys)
+}
+
+/// A master key is the base64 encoding of 32 random bytes, e.g. from `openssl rand -base64 32`.
+pub fn decode_master_key(id: &str, key: &str) -> Result<Zeroizing<Vec<u8>>, String> {
+    match base64::decode(key.trim()) {
+        Ok(bytes) if bytes.len() == 32 => Ok(Zeroizing::new(bytes)),
+        _ => Err(format!(
+            "Master key `{}` must be the base64 encoding of 32 random bytes",
+            id
+        )),
+    }
+}
+
+// The newest data key wrapped by the current master key, or a new one if the master key was just rotated in
+fn active_data_key(connection: &PgConnection, master_key_id_arg: &str) -> Result<i32, Error> {
+    use crate::schema::data_keys::dsl::*;
+    connection.transaction(|| {
+        diesel::sql_query(format!(
+            "SELECT pg_advisory_xact_lock({})",
+            DATA_KEYS_LOCK_ID
+        ))
+        .execute(connection)?;
+        let existing = data_keys
+            .filter(master_key_id.eq(master_key_id_arg))
+            .order(id.desc())
+            .first::<DataKey>(connection)
+            .optional()?;
+        if let Some(data_key) = existing {
+            unwrap_data_key(&data_key)?;
+            return Ok(data_key.id);
+        }
+        let mut key = Zeroizing::new(vec![0u8; 32]);
+        OsRng.fill_bytes(&mut key);
+        let wrapped = crypto::seal(
+            master_key(master_key_id_arg)?,
+            KeyPurpose::DataKeyWrap,
+            &key,
+        );
+        let data_key: DataKey = diesel::insert_into(data_keys)
+            .values(&NewDataKey {
+                master_key_id: master_key_id_arg,
+                wrapped_key: &wrapped,
+            })
+            .get_result(connection)?;
+        info!(data_key_id = data_key.id, "Created a new data key");
+        data_keys_cache().lock().unwrap().insert(data_key.id, key);
+        Ok(data_key.id)
+    })
+}
+
+fn master_key(id: &str) -> Result<&'static [u8], Error> {
+    MASTER_KEYS
+        .get()
+        .expect("persistence::init_encryption must run first")
+        .get(id)
+        .map(|key| key.as_slice())
+        .ok_or_else(|| corrupt(format!("Master key `{}` is not configured", id)))
+}
+
+fn unwrap_data_key(data_key: &DataKey) -> Result<Zeroizing<Vec<u8>>, Error> {
+    let key = crypto::open(
+        master_key(&data_key.master_key_id)?,
+        KeyPurpose::DataKeyWrap,
+        &data_key.wrapped_key,
+    )
+    .map_err(|_| corrupt(format!("Could not unwrap data key {}", data_key.id)))?;
+    data_keys_cache()
+        .lock()
+        .unwrap()
+        .insert(data_key.id, key.clone());
+    Ok(key)
+}
+
+fn data_key(connection: &PgConnection, data_key_id: i32) -> Result<Zeroizing<Vec<u8>>, Error> {
+    use crate::schema::data_keys::dsl::*;
+    if let Some(key) = data_keys_cache().lock().unwrap().get(&data_key_id) {
+        return Ok(key.clone());
+    }
+    let data_key = data_keys.find(data_key_id).first::<DataKey>(connection)?;
+    unwrap_data_key(&data_key)
+}
+
+pub(super) fn active_data_key_id() -> i32 {
+    *ACTIVE_DATA_KEY
+        .get()
+        .expect("persistence::init_encryption must run first")
+}
+
+/// The row a sealed column value belongs to. Its table, id and user id are the associated data, so a value copied into
+/// another row, e.g. another user's or another locker, no longer decrypts.
+#[derive(Clone, Copy)]
+pub(super) struct Row {
+    pub table: &'static str,
+    pub id: i32,
+    pub user_id: i32,
+    // Rows sealed before columns were bound have no associated data, until re-encryption rewrites them
+    pub bound: bool,
+}
+
+impl Row {
+    pub fn user(id: i32, bound: bool) -> Row {
+        Row {
+            table: "users",
+            id,
+            user_id: id,
+            bound,
+        }
+    }
+
+    pub fn locker(id: i32, user_id: i32, bound: bool) -> Row {
+        Row {
+            table: "lockers",
+            id,
+            user_id,
+            bound,
+        }
+    }
+
+    fn aad(&self) -> Vec<u8> {
+        match self.bound {
+            true => [
+                self.table.as_bytes(),
+                &[0],
+                &self.id.to_be_bytes(),
+                &self.user_id.to_be_bytes(),
+            ]
+            .concat(),
+            false => Vec::new(),
+        }
+    }
+}
+
+#[derive(QueryableByName)]
+struct NextId {
+    #[sql_type = "Integer"]
+    id: i32,
+}
+
+/// Takes the next id of `table` from its sequence, so its columns can be sealed before the row is inserted.
+pub(super) fn next_row_id(connection: &PgConnection, table: &str) -> Result<i32, Error> {
+    diesel::sql_query(format!(
+        "SELECT nextval(pg_get_serial_sequence('{}', 'id'))::INTEGER AS id",
+        table
+    ))
+    .get_result::<NextId>(connection)
+    .map(|next| next.id)
+}
+
+/// Encrypts a column value of `row` under the active data key, returning what to store. Always bound to the row.
+pub(super) fn seal_column(purpose: KeyPurpose, row: Row, plaintext: &[u8]) -> Vec<u8> {
+    let key = data_keys_cache()
+        .lock()
+        .unwrap()
+        .get(&active_data_key_id())
+        .cloned()
+        .expect("The active data key is unwrapped by init_encryption");
+    let row = Row { bound: true, ..row };
+    crypto::seal_with_aad(&key, purpose, plaintext, &row.aad())
+}
+
+/// Decrypts a stored column value of `row`. Rows written before encryption at rest (no data key) hold the plain value.
+pub(super) fn open_column(
+    connection: &PgConnection,
+    data_key_id: Option<i32>,
+    purpose: KeyPurpose,
+    row: Row,
+    stored: &[u8],
+) -> Result<Vec<u8>, Error> {
+    match data_key_id {
+        None => Ok(stored.to_vec()),
+        Some(data_key_id) => {
+            let key = data_key(connection, data_key_id)?;
+            crypto::open_with_aad(&key, purpose, stored, &row.aad())
+                .map(|plaintext| plaintext.to_vec())
+                .map_err(|_| {
+                    corrupt(format!(
+                        "Could not decrypt a value under data key {}",
+                        data_key_id
+                    ))
+                })
+        }
+    }
+}
+
+/// Runs re-encryption in the background, e.g. after a master key rotation. Requests keep working meanwhile since rows
+/// are readable under any configured master key.
+pub fn spawn_reencryption() {
+    let spawned = std::thread::Builder::new()
+        .name("reencryption".to_string())
+        .spawn(|| match reencrypt_all() {
+            Ok(Reencrypted { rows: 0, errors: 0 }) => debug!("All rows use the active data key"),
+            Ok(Reencrypted { rows, errors: 0 }) => {
+                info!(count = rows, "Re-encrypted rows under the active data key")
+            }
+            Ok(Reencrypted { rows, errors }) => error!(
+                count = rows,
+                errors, "Re-encrypted rows under the active data key, some could not be read"
+            ),
+            Err(err) => error!(error = %err, "Re-encryption stopped"),
+        });
+    if let Err(err) = spawned {
+        error!(error = ?err, "Could not start re-encryption");
+    }
+}
+
+const REENCRYPT_BATCH_SIZE: i64 = 100;
+
+/// What a re-encryption run did: rows rewritten, and rows skipped because they could not be decrypted.
+#[derive(Debug, Default, PartialEq)]
+pub struct Reencrypted {
+    pub rows: usize,
+    pub errors: usize,
+}
+
+/// Re-encrypts every user and locker row not yet under the active data key or not yet bound to its row (including rows
+/// from before encryption at rest), a batch per transaction. A row that doesn't decrypt is logged and skipped, so it
+/// doesn't hold up the others.
+pub fn reencrypt_all() -> Result<Reencrypted, String> {
+    let connection = try_establish_connection()?;
+    let mut done = Reencrypted::default();
+    // Rows are visited in id order, so skipped rows are never selected again
+    let (mut after_user, mut after_locker) = (0, 0);
+    loop {
+        let (users_batch, lockers_batch) = connection
+            .transaction::<_, Error, _>(|| {
+                Ok((
+                    reencrypt_users(&connection, after_user)?,
+                    reencrypt_lockers(&connection, after_locker)?,
+                ))
+            })
+            .map_err(|err| format!("Could not re-encrypt, {} row(s) done: {}", done.rows, err))?;
+        for batch in [&users_batch, &lockers_batch] {
+            done.rows += batch.rows;
+            done.errors += batch.errors;
+        }
+        if users_batch.last_id.is_none() && lockers_batch.last_id.is_none() {
+            break;
+        }
+        after_user = users_batch.last_id.unwrap_or(after_user);
+        after_locker = lockers_batch.last_id.unwrap_or(after_locker);
+    }
+    log_retired_master_keys(&connection)
+        .map_err(|err| format!("Could not check master key usage: {}", err))?;
+    Ok(done)
+}
+
+struct Batch {
+    rows: usize,
+    errors: usize,
+    // Highest id looked at, None once there was nothing left
+    last_id: Option<i32>,
+}
+
+// A corrupt row is counted and left as it is, anything else (e.g. the database) fails the batch
+fn skip_corrupt(table: &'static str, row_id: i32, err: Error) -> Result<(), Error> {
+    match corrupt_record(table, row_id, err) {
+        err @ Error::DeserializationError(_) => {
+            error!(table, id = row_id, error = %err, "Could not re-encrypt a row, skipping it");
+            Ok(())
+        }
+        err => Err(err),
+    }
+}
+
+fn reencrypt_users(connection: &PgConnection, after: i32) -> Result<Batch, Error> {
+    use crate::schema::users::dsl::*;
+    let active = active_data_key_id();
+    let rows: Vec<(i32, Vec<u8>, Option<Vec<u8>>, Option<i32>, bool)> = users
+        .filter(id.gt(after))
+        .filter(
+            data_key_id
+                .is_null()
+                .or(data_key_id.ne(active))
+                .or(columns_bound.eq(false)),
+        )
+        .select((
+            id,
+            psswd_file,
+            recovery_psswd_file,
+            data_key_id,
+            columns_bound,
+        ))
+        .order(id.asc())
+        .limit(REENCRYPT_BATCH_SIZE)
+        .for_update()
+        .load(connection)?;
+    let mut batch = Batch {
+        rows: 0,
+        errors: 0,
+        last_id: rows.last().map(|row| row.0),
+    };
+    for (row_id, stored_psswd_file, stored_recovery_psswd_file, row_data_key_id, bound) in &rows {
+        let row = Row::user(*row_id, *bound);
+        let opened = open_column(
+            connection,
+            *row_data_key_id,
+            KeyPurpose::UserPasswordFile,
+            row,
+            stored_psswd_file,
+        )
+        .and_then(|plain_psswd_file| {
+            let plain_recovery_psswd_file = match stored_recovery_psswd_file {
+                Some(stored) => Some(open_column(
+                    connection,
+                    *row_data_key_id,
+                    KeyPurpose::UserRecoveryPasswordFile,
+                    row,
+                    stored,
+                )?),
+                None => None,
+            };
+            Ok((plain_psswd_file, plain_recovery_psswd_file))
+        });
+        let (plain_psswd_file, plain_recovery_psswd_file) = match opened {
+            Ok(opened) => opened,
+            Err(err) => {
+                skip_corrupt("users", *row_id, err)?;
+                batch.errors += 1;
+                continue;
+            }
+        };
+        diesel::update(users.filter(id.eq(row_id)))
+            .set((
+                psswd_file.eq(seal_column(
+                    KeyPurpose::UserPasswordFile,
+                    row,
+                    &plain_psswd_file,
+                )),
+                recovery_psswd_file.eq(plain_recovery_psswd_file
+                    .map(|r| seal_column(KeyPurpose::UserRecoveryPasswordFile, row, &r))),
+                data_key_id.eq(Some(active)),
+                columns_bound.eq(true),
+            ))
+            .execute(connection)?;
+        batch.rows += 1;
+    }
+    Ok(batch)
+}
+
+fn reencrypt_lockers(connection: &PgConnection, after: i32) -> Result<Batch, Error> {
+    use crate::schema::lockers::dsl::*;
+    let active = active_data_key_id();
+    let rows: Vec<(i32, i32, Vec<u8>, Vec<u8>, Option<i32>, bool)> = lockers
+        .filter(id.gt(after))
+        .filter(
+            data_key_id
+                .is_null()
+                .or(data_key_id.ne(active))
+                .or(columns_bound.eq(false)),
+        )
+        .select((
+            id,
+            user_id,
+            psswd_file,
+            ciphertext,
+            data_key_id,
+            columns_bound,
+        ))
+        .order(id.asc())
+        .limit(REENCRYPT_BATCH_SIZE)
+        .for_update()
+        .load(connection)?;
+    let mut batch = Batch {
+        rows: 0,
+        errors: 0,
+        last_id: rows.last().map(|row| row.0),
+    };
+    for (row_id, row_user_id, stored_psswd_file, stored_ciphertext, row_data_key_id, bound) in &rows
+    {
+        let row = Row::locker(*row_id, *row_user_id, *bound);
+        let opened = open_column(
+            connection,
+            *row_data_key_id,
+            KeyPurpose::LockerPasswordFile,
+            row,
+            stored_psswd_file,
+        )
+        .and_then(|plain_psswd_file| {
+            let plain_ciphertext = open_column(
+                connection,
+                *row_data_key_id,
+                KeyPurpose::LockerCiphertext,
+                row,
+                stored_ciphertext,
+            )?;
+            Ok((plain_psswd_file, plain_ciphertext))
+        });
+        let (plain_psswd_file, plain_ciphertext) = match opened {
+            Ok(opened) => opened,
+            Err(err) => {
+                skip_corrupt("lockers", *row_id, err)?;
+                batch.errors += 1;
+                continue;
+            }
+        };
+        // The locker's contents don't change, so neither does its version
+        diesel::update(lockers.filter(id.eq(row_id)))
+            .set((
+                psswd_file.eq(seal_column(
+                    KeyPurpose::LockerPasswordFile,
+                    row,
+                    &plain_psswd_file,
+                )),
+                ciphertext.eq(seal_column(
+                    KeyPurpose::LockerCiphertext,
+                    row,
+                    &plain_ciphertext,
+                )),
+                data_key_id.eq(Some(active)),
+                columns_bound.eq(true),
+            ))
+            .execute(connection)?;
+        batch.rows += 1;
+    }
+    Ok(batch)
+}
+
+#[derive(QueryableByName)]
+struct MasterKeyInUse {
+    #[sql_type = "diesel::sql_types::Varchar"]
+    master_key_id: String,
+}
+
+// Once nothing references a retired master key's data keys, the operator can remove it from the key file
+fn log_retired_master_keys(connection: &PgConnection) -> Result<(), Error> {
+    let in_use: Vec<MasterKeyInUse> = diesel::sql_query(
+        "SELECT DISTINCT master_key_id FROM data_keys WHERE id = $1 \
+         OR id IN (SELECT data_key_id FROM users) OR id IN (SELECT data_key_id FROM lockers)",
+    )
+    .bind::<Integer, _>(active_data_key_id())
+    .load(connection)?;
+    let configured = MASTER_KEYS
+        .get()
+        .expect("persistence::init_encryption must run first");
+    for master_key_id in configured.keys() {
+        if !in_use.iter().any(|k| &k.master_key_id == master_key_id) {
+            info!(%master_key_id, "No data is encrypted under this master key anymore, it can be removed");
+        }
+    }
+    Ok(())
+}
+
+fn corrupt(message: String) -> Error {
+    Error::DeserializationError(message.into())
+}
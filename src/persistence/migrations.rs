@@ -0,0 +1,110 @@
+use diesel::prelude::*;
+use diesel_migrations::{MigrationConnection, RunMigrationsError};
+use std::collections::HashSet;
+use tracing::info;
+
+use crate::persistence::db::try_establish_connection;
+
+// Serializes migrations across app instances, so replicas starting together don't race each other
+const MIGRATIONS_LOCK_ID: i64 = 0x6b65_7970_6f73_7402;
+
+// Compiled into the binary, so deployments don't need diesel_cli or the migrations directory
+embed_migrations!("migrations");
+// Written by build.rs from the same directory
+const EMBEDDED_VERSIONS: &[&str] = include!(concat!(env!("OUT_DIR"), "/migration_versions.rs"));
+
+/// Applies all pending migrations in one transaction.
+pub fn run_migrations() -> Result<(), String> {
+    let applied = migrate()?;
+    info!(count = applied.len(), versions = ?applied, "Database migrations applied");
+    Ok(())
+}
+
+/// Errors unless the database has applied exactly the embedded migrations, without changing the database.
+pub fn check_migrations() -> Result<(), String> {
+    let connection = try_establish_connection()?;
+    let applied = connection
+        .previously_run_migration_versions()
+        .map_err(|err| format!("Could not read the applied migrations: {}", err))?;
+    let (pending, unknown) = compare_versions(EMBEDDED_VERSIONS, &applied);
+    if !pending.is_empty() {
+        return Err(format!(
+            "Database schema is behind the app, pending migrations: {}",
+            pending.join(", ")
+        ));
+    }
+    if !unknown.is_empty() {
+        return Err(format!(
+            "Database schema is ahead of the app, unknown migrations: {}",
+            unknown.join(", ")
+        ));
+    }
+    info!("Database schema is up to date");
+    Ok(())
+}
+
+// Embedded versions the database lacks, and applied versions this binary doesn't know, both sorted
+fn compare_versions(embedded: &[&str], applied: &HashSet<String>) -> (Vec<String>, Vec<String>) {
+    let pending = embedded
+        .iter()
+        .filter(|version| !applied.contains(**version))
+        .map(|version| version.to_string())
+        .collect();
+    let mut unknown: Vec<String> = applied
+        .iter()
+        .filter(|version| !embedded.contains(&version.as_str()))
+        .cloned()
+        .collect();
+    unknown.sort();
+    (pending, unknown)
+}
+
+// Returns the versions that were applied
+fn migrate() -> Result<Vec<String>, String> {
+    let connection = try_establish_connection()?;
+    let mut output = Vec::new();
+    connection
+        .transaction::<_, RunMigrationsError, _>(|| {
+            diesel::sql_query(format!(
+                "SELECT pg_advisory_xact_lock({})",
+                MIGRATIONS_LOCK_ID
+            ))
+            .execute(&connection)?;
+            embedded_migrations::run_with_output(&connection, &mut output)
+        })
+        .map_err(|err| format!("Could not run migrations: {}", err))?;
+    Ok(migration_versions(&output))
+}
+
+fn migration_versions(output: &[u8]) -> Vec<String> {
+    String::from_utf8_lossy(output)
+        .lines()
+        .filter_map(|line| line.strip_prefix("Running migration "))
+        .map(|version| version.trim().to_string())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn embedded_versions_are_sorted_digits() {
+        assert_eq!(EMBEDDED_VERSIONS.first(), Some(&"00000000000000"));
+        assert!(EMBEDDED_VERSIONS.contains(&"20221127235419"));
+        assert!(EMBEDDED_VERSIONS.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(EMBEDDED_VERSIONS
+            .iter()
+            .all(|version| version.chars().all(|c| c.is_ascii_digit())));
+    }
+
+    #[test]
+    fn compare_versions_finds_pending_and_unknown() {
+        let applied: HashSet<String> = ["1", "2", "9"].iter().map(|v| v.to_string()).collect();
+        let (pending, unknown) = compare_versions(&["1", "2", "3"], &applied);
+        assert_eq!(pending, vec!["3"]);
+        assert_eq!(unknown, vec!["9"]);
+        let (pending, unknown) = compare_versions(&["1", "2", "9"], &applied);
+        assert!(pending.is_empty() && unknown.is_empty());
+    }
+}
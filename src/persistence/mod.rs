@@ -1,3 +1,7 @@
 mod db;
+mod encryption;
+mod migrations;
 
 pub use db::*;
+pub use encryption::*;
+pub use migrations::*;
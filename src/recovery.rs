@@ -0,0 +1,106 @@
+use tracing::{error, info};
+use zeroize::Zeroizing;
+
+use crate::api::ApiError;
+use crate::cache;
+use crate::crypto;
+use crate::persistence;
+use crate::user;
+
+/// The OPAQUE credential identifier of the recovery record, so it can never be confused with the password record.
+pub fn recovery_identifier(email: &str) -> String {
+    format!("recovery:{}", email)
+}
+
+/// OPAQUE login start against the user's recovery record, i.e. with the recovery code as the password.
+pub fn start(email: &str, credential_request_base64: &str) -> Result<(u32, String), ApiError> {
+    let user = user::get_user(email).map_err(ApiError::RecoveryError)?;
+    let recovery_password_file = user
+        .recovery_psswd_file
+        .ok_or_else(|| ApiError::RecoveryError("Recovery is not set up".to_string()))?;
+    let recovery_password_file_bytes = Zeroizing::new(recovery_password_file);
+    let server_login_start_result = crypto::login_start(
+        &recovery_identifier(email),
+        &recovery_password_file_bytes,
+        credential_request_base64,
+    )?;
+    let nonce = crypto::create_nonce();
+    cache::insert(
+        nonce,
+        Zeroizing::new(server_login_start_result.state.serialize().to_vec()),
+    );
+    cache::insert_login_email(nonce.to_be_bytes().to_vec(), email);
+    let response = base64::encode(server_login_start_result.message.serialize());
+    Ok((nonce, response))
+}
+
+/// Finishes the recovery login and, since the recovery code is proven, starts re-registration of both the new password
+/// and a new (i.e. rotated) recovery code. Returns the reset token along with both registration responses.
+pub fn finish(
+    nonce: u32,
+    credential_finalization_base64: &str,
+    password_registration_request_base64: &str,
+    recovery_registration_request_base64: &str,
+) -> Result<(Vec<u8>, String, String), ApiError> {
+    // ServerLogin state is single use
+    let server_login_bytes = cache::take(&nonce).ok_or(ApiError::BadRequest)?;
+    let email = cache::take_login_email(&nonce.to_be_bytes()).ok_or(ApiError::BadRequest)?;
+    crypto::login_finish(&server_login_bytes, credential_finalization_base64).map_err(|err| {
+        error!(error = ?err, "Error during recovery");
+        err
+    })?;
+
+    let password_registration =
+        crypto::server_side_registration_start(password_registration_request_base64, &email)?;
+    let recovery_registration = crypto::server_side_registration_start(
+        recovery_registration_request_base64,
+        &recovery_identifier(&email),
+    )?;
+    let reset_token = crypto::create_session_id();
+    cache::insert_login_email(reset_cache_key(&reset_token), &email);
+    Ok((
+        reset_token,
+        base64::encode(password_registration.message.serialize()),
+        base64::encode(recovery_registration.message.serialize()),
+    ))
+}
+
+/// Stores the new password and recovery records and signs the user out everywhere. Returns the recovered user's email.
+pub fn reset(
+    reset_token: &[u8],
+    password_upload_base64: &str,
+    recovery_upload_base64: &str,
+) -> Result<String, ApiError> {
+    // Malformed uploads are rejected before the reset token is used up, so the client can retry them
+    let password_file = crypto::server_side_registration_finish(password_upload_base64)?;
+    let recovery_password_file = crypto::server_side_registration_finish(recovery_upload_base64)?;
+    let email =
+        cache::take_login_email(&reset_cache_key(reset_token)).ok_or(ApiError::BadRequest)?;
+    let user = user::get_user(&email).map_err(ApiError::RecoveryError)?;
+    persistence::update_password_files(user.id, &password_file, &recovery_password_file).map_err(
+        |err| {
+            error!(error = ?err, "Error storing recovered password files");
+            ApiError::ServerError
+        },
+    )?;
+    info!(user_id = user.id, "Account recovery used");
+    cache::revoke_user_sessions(&email);
+    Ok(email)
+}
+
+/// Sets up (or rotates) the recovery record of an authenticated user.
+pub fn register_finish(email: &str, recovery_upload_base64: &str) -> Result<(), ApiError> {
+    let user = user::get_user(email).map_err(ApiError::RecoveryError)?;
+    let recovery_password_file = crypto::server_side_registration_finish(recovery_upload_base64)?;
+    persistence::update_recovery_password_file(user.id, &recovery_password_file).map_err(
+        |err| {
+            error!(error = ?err, "Error storing recovery password file");
+            ApiError::ServerError
+        },
+    )?;
+    Ok(())
+}
+
+fn reset_cache_key(reset_token: &[u8]) -> Vec<u8> {
+    [b"recovery:".as_ref(), reset_token].concat()
+}
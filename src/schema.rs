@@ -1,10 +1,66 @@
+table! {
+    audit_events (id) {
+        id -> Int4,
+        user_id -> Nullable<Int4>,
+        event_type -> Varchar,
+        locker_id -> Nullable<Varchar>,
+        ip -> Nullable<Varchar>,
+        user_agent -> Nullable<Varchar>,
+        outcome -> Varchar,
+        occurred_at -> Int8,
+        prev_hash -> Varchar,
+        hash -> Varchar,
+        inserted_at -> Timestamp,
+    }
+}
+
+table! {
+    data_keys (id) {
+        id -> Int4,
+        master_key_id -> Varchar,
+        wrapped_key -> Bytea,
+        inserted_at -> Timestamp,
+    }
+}
+
 table! {
     lockers (id) {
         id -> Int4,
-        email -> Varchar,
         locker_id -> Varchar,
-        psswd_file -> Text,
-        ciphertext -> Text,
+        psswd_file -> Bytea,
+        ciphertext -> Bytea,
+        inserted_at -> Timestamp,
+        updated_at -> Timestamp,
+        user_id -> Int4,
+        version -> Int4,
+        data_key_id -> Nullable<Int4>,
+        kind -> Varchar,
+        label -> Nullable<Bytea>,
+        tags -> Nullable<Bytea>,
+        custom_fields -> Nullable<Bytea>,
+        icon_hash -> Nullable<Varchar>,
+        columns_bound -> Bool,
+    }
+}
+
+table! {
+    recovery_codes (id) {
+        id -> Int4,
+        user_id -> Int4,
+        code_hash -> Text,
+        used -> Bool,
+        inserted_at -> Timestamp,
+        updated_at -> Timestamp,
+    }
+}
+
+table! {
+    totp_secrets (id) {
+        id -> Int4,
+        user_id -> Int4,
+        secret -> Text,
+        enabled -> Bool,
+        last_used_step -> Int8,
         inserted_at -> Timestamp,
         updated_at -> Timestamp,
     }
@@ -14,11 +70,41 @@ table! {
     users (id) {
         id -> Int4,
         email -> Varchar,
-        psswd_file -> Text,
+        psswd_file -> Bytea,
         deleted -> Bool,
         inserted_at -> Timestamp,
         updated_at -> Timestamp,
+        recovery_psswd_file -> Nullable<Bytea>,
+        data_key_id -> Nullable<Int4>,
+        columns_bound -> Bool,
     }
 }
 
-allow_tables_to_appear_in_same_query!(lockers, users,);
+table! {
+    webauthn_credentials (id) {
+        id -> Int4,
+        user_id -> Int4,
+        credential_id -> Varchar,
+        public_key -> Text,
+        sign_count -> Int8,
+        inserted_at -> Timestamp,
+        updated_at -> Timestamp,
+    }
+}
+
+joinable!(lockers -> data_keys (data_key_id));
+joinable!(lockers -> users (user_id));
+joinable!(recovery_codes -> users (user_id));
+joinable!(totp_secrets -> users (user_id));
+joinable!(users -> data_keys (data_key_id));
+joinable!(webauthn_credentials -> users (user_id));
+
+allow_tables_to_appear_in_same_query!(
+    audit_events,
+    data_keys,
+    lockers,
+    recovery_codes,
+    totp_secrets,
+    users,
+    webauthn_credentials,
+);
@@ -0,0 +1,501 @@
+use rustls::server::{AllowAnyAnonymousOrAuthenticatedClient, NoClientAuth};
+use rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig, ServerConnection, StreamOwned};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::{self, BufReader, ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{debug, error, info, warn};
+
+use crate::config::{self, TlsConfig};
+
+/// Set on requests whose client presented a certificate signed by `server.tls.client_ca`: the certificate's SHA-256.
+pub const CLIENT_CERT_HEADER: &str = "X-Keypost-Client-Cert";
+
+const MAX_HEAD_BYTES: usize = 16 * 1024;
+const MAX_HEADERS: usize = 64;
+// Each connection holds two threads, beyond this new connections are closed right away
+const MAX_CONNECTIONS: usize = 256;
+// The whole head has to arrive within this, however slowly its bytes trickle in
+const HEAD_TIMEOUT: Duration = Duration::from_secs(10);
+// Longest a socket may sit idle once the request is being forwarded
+const IO_TIMEOUT: Duration = Duration::from_secs(60);
+const RELOAD_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+// Connections pick up the current config when accepted, so swapping it never affects requests in flight
+static SERVER_CONFIG: OnceLock<RwLock<Arc<ServerConfig>>> = OnceLock::new();
+static CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// Loads the certificates and starts terminating TLS on `server.address:server.port`, forwarding plain HTTP to Rocket
+/// on the loopback `server.internal_port`. Certificates are reloaded on SIGHUP or when one of the files changes.
+pub fn init() -> Result<(), String> {
+    let server = &config::get().server;
+    let tls = server.tls.as_ref().ok_or("TLS is not configured")?;
+    SERVER_CONFIG
+        .set(RwLock::new(Arc::new(load(tls)?)))
+        .map_err(|_| "TLS was already initialized".to_string())?;
+    let listener = TcpListener::bind((server.address.as_str(), server.port)).map_err(|err| {
+        format!(
+            "Could not listen on {}:{}: {}",
+            server.address, server.port, err
+        )
+    })?;
+    let upstream = SocketAddr::from(([127, 0, 0, 1], server.internal_port));
+    spawn("tls-accept", move || accept(listener, upstream))?;
+    spawn("tls-sighup", watch_sighup)?;
+    spawn("tls-files", watch_files)?;
+    info!(
+        address = %server.address,
+        port = server.port,
+        client_certs = tls.client_ca.is_some(),
+        "Terminating TLS"
+    );
+    Ok(())
+}
+
+fn spawn<F: FnOnce() + Send + 'static>(name: &str, f: F) -> Result<(), String> {
+    thread::Builder::new()
+        .name(name.to_string())
+        .spawn(f)
+        .map(|_| ())
+        .map_err(|err| format!("Could not start {}: {}", name, err))
+}
+
+fn load(tls: &TlsConfig) -> Result<ServerConfig, String> {
+    let certs = read_pem(&tls.certs, rustls_pemfile::certs)?
+        .into_iter()
+        .map(Certificate)
+        .collect::<Vec<_>>();
+    if certs.is_empty() {
+        return Err(format!("No certificate in {}", tls.certs));
+    }
+    let key = read_pem(&tls.key, |reader| loop {
+        match rustls_pemfile::read_one(reader)? {
+            Some(rustls_pemfile::Item::PKCS8Key(key))
+            | Some(rustls_pemfile::Item::RSAKey(key))
+            | Some(rustls_pemfile::Item::ECKey(key)) => return Ok(Some(key)),
+            Some(_) => continue,
+            None => return Ok(None),
+        }
+    })?
+    .ok_or_else(|| format!("No private key in {}", tls.key))?;
+    let client_verifier = match &tls.client_ca {
+        // Client certificates are optional for the connection, admin routes check for one
+        Some(client_ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in read_pem(client_ca, rustls_pemfile::certs)? {
+                roots
+                    .add(&Certificate(cert))
+                    .map_err(|err| format!("Invalid CA certificate in {}: {}", client_ca, err))?;
+            }
+            AllowAnyAnonymousOrAuthenticatedClient::new(roots)
+        }
+        None => NoClientAuth::new(),
+    };
+    ServerConfig::builder()
+        .with_safe_defaults()
+        .with_client_cert_verifier(client_verifier)
+        .with_single_cert(certs, PrivateKey(key))
+        .map_err(|err| format!("Invalid certificate or key: {}", err))
+}
+
+fn read_pem<T, F>(path: &str, parse: F) -> Result<T, String>
+where
+    F: FnOnce(&mut dyn io::BufRead) -> io::Result<T>,
+{
+    let file = File::open(path).map_err(|err| format!("Could not open {}: {}", path, err))?;
+    parse(&mut BufReader::new(file)).map_err(|err| format!("Could not parse {}: {}", path, err))
+}
+
+/// Swaps in the certificates from the configured files. On failure the current ones stay in use.
+pub fn reload() {
+    let tls = match &config::get().server.tls {
+        Some(tls) => tls,
+        None => return,
+    };
+    match load(tls) {
+        Ok(server_config) => {
+            *current_lock().write().unwrap() = Arc::new(server_config);
+            info!("Reloaded TLS certificates");
+        }
+        Err(err) => {
+            error!(error = %err, "Could not reload TLS certificates, keeping the current ones")
+        }
+    }
+}
+
+fn current_lock() -> &'static RwLock<Arc<ServerConfig>> {
+    SERVER_CONFIG.get().expect("tls::init must run first")
+}
+
+fn watch_sighup() {
+    let mut signals = match signal_hook::iterator::Signals::new(&[signal_hook::consts::SIGHUP]) {
+        Ok(signals) => signals,
+        Err(err) => {
+            error!(error = ?err, "Could not listen for SIGHUP, certificates reload on file changes only");
+            return;
+        }
+    };
+    for _ in signals.forever() {
+        info!("SIGHUP received");
+        reload();
+    }
+}
+
+// Polling keeps this free of platform specific file watchers, certificates rarely change more than daily
+fn watch_files() {
+    let tls = match &config::get().server.tls {
+        Some(tls) => tls,
+        None => return,
+    };
+    let files: Vec<&String> = [Some(&tls.certs), Some(&tls.key), tls.client_ca.as_ref()]
+        .iter()
+        .flatten()
+        .copied()
+        .collect();
+    let modified = || -> Vec<Option<SystemTime>> {
+        files
+            .iter()
+            .map(|file| fs::metadata(file).and_then(|m| m.modified()).ok())
+            .collect()
+    };
+    let mut last = modified();
+    loop {
+        thread::sleep(RELOAD_POLL_INTERVAL);
+        let current = modified();
+        if current != last {
+            info!("TLS files changed");
+            reload();
+            last = current;
+        }
+    }
+}
+
+// Counts a connection for as long as it is being served
+struct ConnectionSlot;
+
+impl ConnectionSlot {
+    fn acquire() -> Option<ConnectionSlot> {
+        if CONNECTIONS.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+            CONNECTIONS.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        Some(ConnectionSlot)
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        CONNECTIONS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn accept(listener: TcpListener, upstream: SocketAddr) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let slot = match ConnectionSlot::acquire() {
+                    Some(slot) => slot,
+                    None => {
+                        warn!(
+                            max = MAX_CONNECTIONS,
+                            "Too many TLS connections, dropping one"
+                        );
+                        continue;
+                    }
+                };
+                let spawned = spawn("tls-conn", move || {
+                    let _slot = slot;
+                    if let Err(err) = serve(stream, upstream) {
+                        debug!(error = ?err, "TLS connection ended with an error");
+                    }
+                });
+                if let Err(err) = spawned {
+                    warn!(error = %err, "Dropping TLS connection");
+                }
+            }
+            Err(err) => warn!(error = ?err, "Could not accept TLS connection"),
+        }
+    }
+}
+
+// One request per connection: its head is rewritten with what only the terminator knows (the client's address and
+// certificate), and `Connection: close` makes sure Rocket never reads a second, unrewritten request from it
+fn serve(client: TcpStream, upstream: SocketAddr) -> io::Result<()> {
+    let peer = client.peer_addr()?;
+    client.set_write_timeout(Some(IO_TIMEOUT))?;
+    let server_config = Arc::clone(&current_lock().read().unwrap());
+    let connection = ServerConnection::new(server_config)
+        .map_err(|err| io::Error::new(ErrorKind::Other, err))?;
+    let mut tls = StreamOwned::new(connection, client);
+
+    let deadline = Instant::now() + HEAD_TIMEOUT;
+    let mut received = Vec::new();
+    let head_len = loop {
+        match find_head_end(&received) {
+            Ok(Some(head_len)) => break head_len,
+            Ok(None) => {}
+            Err(err) => return reject(tls, "400 Bad Request", err),
+        }
+        if received.len() > MAX_HEAD_BYTES {
+            return reject(
+                tls,
+                "431 Request Header Fields Too Large",
+                "Request head too large",
+            );
+        }
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return reject(tls, "408 Request Timeout", "Request head timed out");
+        }
+        tls.sock.set_read_timeout(Some(remaining))?;
+        let mut buf = [0u8; 4096];
+        match tls.read(&mut buf)? {
+            0 => return Ok(()),
+            n => received.extend_from_slice(&buf[..n]),
+        }
+    };
+    tls.sock.set_read_timeout(Some(IO_TIMEOUT))?;
+    let client_cert = tls
+        .conn
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+        .map(|cert| hex(&Sha256::digest(&cert.0)));
+    let head = match rewrite_head(&received[..head_len], &peer, client_cert.as_deref()) {
+        Ok(head) => head,
+        Err(err) => return reject(tls, "400 Bad Request", err),
+    };
+
+    let mut rocket = match TcpStream::connect(upstream) {
+        Ok(rocket) => rocket,
+        Err(err) => {
+            warn!(error = ?err, "Could not reach the server behind TLS");
+            return reject(tls, "502 Bad Gateway", err);
+        }
+    };
+    rocket.write_all(&head)?;
+    rocket.write_all(&received[head_len..])?;
+
+    let StreamOwned { mut conn, sock } = tls;
+    // Body bytes decrypted along with the head but not read yet
+    let mut chunk = [0u8; 16 * 1024];
+    while let Ok(n) = conn.reader().read(&mut chunk) {
+        if n == 0 {
+            break;
+        }
+        rocket.write_all(&chunk[..n])?;
+    }
+    let conn = Arc::new(Mutex::new(conn));
+    let request_body = {
+        let conn = Arc::clone(&conn);
+        let sock = sock.try_clone()?;
+        let rocket = rocket.try_clone()?;
+        thread::Builder::new()
+            .name("tls-conn-body".to_string())
+            .spawn(move || forward_request(conn, sock, rocket))?
+    };
+    let result = forward_response(&conn, &sock, &mut rocket);
+    let _ = sock.shutdown(Shutdown::Both);
+    let _ = request_body.join();
+    result
+}
+
+// Answers the client directly and ends the connection, the error is what gets logged
+fn reject<E: Into<Box<dyn std::error::Error + Send + Sync>>>(
+    mut tls: StreamOwned<ServerConnection, TcpStream>,
+    status: &str,
+    err: E,
+) -> io::Result<()> {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        status
+    );
+    tls.write_all(response.as_bytes())?;
+    tls.conn.send_close_notify();
+    tls.flush()?;
+    Err(io::Error::new(ErrorKind::InvalidData, err))
+}
+
+// Client to Rocket: decrypts whatever the client sends after the head
+fn forward_request(conn: Arc<Mutex<ServerConnection>>, mut sock: TcpStream, mut rocket: TcpStream) {
+    let mut buf = [0u8; 16 * 1024];
+    loop {
+        let n = match sock.read(&mut buf) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => n,
+        };
+        let mut plaintext = Vec::new();
+        {
+            let mut conn = conn.lock().unwrap();
+            let mut received = &buf[..n];
+            while !received.is_empty() {
+                if conn.read_tls(&mut received).is_err() {
+                    return;
+                }
+                if conn.process_new_packets().is_err() {
+                    let _ = conn.write_tls(&mut sock);
+                    return;
+                }
+            }
+            let mut chunk = [0u8; 16 * 1024];
+            loop {
+                match conn.reader().read(&mut chunk) {
+                    Ok(0) => break,
+                    Ok(n) => plaintext.extend_from_slice(&chunk[..n]),
+                    Err(_) => break,
+                }
+            }
+            // e.g. key updates
+            while conn.wants_write() {
+                if conn.write_tls(&mut sock).is_err() {
+                    return;
+                }
+            }
+        }
+        if rocket.write_all(&plaintext).is_err() {
+            break;
+        }
+    }
+    let _ = rocket.shutdown(Shutdown::Write);
+}
+
+// Rocket to client: encrypts the response until Rocket closes the connection
+fn forward_response(
+    conn: &Mutex<ServerConnection>,
+    sock: &TcpStream,
+    rocket: &mut TcpStream,
+) -> io::Result<()> {
+    let mut sock = sock.try_clone()?;
+    let mut buf = [0u8; 16 * 1024];
+    loop {
+        let n = rocket.read(&mut buf)?;
+        let mut conn = conn.lock().unwrap();
+        if n == 0 {
+            conn.send_close_notify();
+        } else {
+            conn.writer().write_all(&buf[..n])?;
+        }
+        while conn.wants_write() {
+            conn.write_tls(&mut sock)?;
+        }
+        if n == 0 {
+            return Ok(());
+        }
+    }
+}
+
+// Parsed the way Rocket's parser (httparse) will, so both agree on where the head ends
+fn find_head_end(received: &[u8]) -> Result<Option<usize>, httparse::Error> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    match httparse::Request::new(&mut headers).parse(received)? {
+        httparse::Status::Complete(head_len) => Ok(Some(head_len)),
+        httparse::Status::Partial => Ok(None),
+    }
+}
+
+// Headers only the terminator may set are dropped from what the client sent. The head is rebuilt from the parsed
+// request rather than edited line by line, so a header behind a bare LF can't slip past the filter
+fn rewrite_head(
+    head: &[u8],
+    peer: &SocketAddr,
+    client_cert: Option<&str>,
+) -> Result<Vec<u8>, httparse::Error> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut request = httparse::Request::new(&mut headers);
+    if request.parse(head)?.is_partial() {
+        return Err(httparse::Error::Token);
+    }
+    let mut rewritten = Vec::with_capacity(head.len() + 128);
+    // A complete parse always has the request line
+    rewritten.extend_from_slice(
+        format!(
+            "{} {} HTTP/1.{}\r\n",
+            request.method.unwrap_or_default(),
+            request.path.unwrap_or_default(),
+            request.version.unwrap_or(1)
+        )
+        .as_bytes(),
+    );
+    for header in request.headers.iter() {
+        let reserved = ["X-Real-IP", "Connection", "Keep-Alive", CLIENT_CERT_HEADER]
+            .iter()
+            .any(|reserved| header.name.eq_ignore_ascii_case(reserved));
+        if !reserved {
+            rewritten.extend_from_slice(header.name.as_bytes());
+            rewritten.extend_from_slice(b": ");
+            rewritten.extend_from_slice(header.value);
+            rewritten.extend_from_slice(b"\r\n");
+        }
+    }
+    rewritten
+        .extend_from_slice(format!("X-Real-IP: {}\r\nConnection: close\r\n", peer.ip()).as_bytes());
+    if let Some(client_cert) = client_cert {
+        rewritten
+            .extend_from_slice(format!("{}: {}\r\n", CLIENT_CERT_HEADER, client_cert).as_bytes());
+    }
+    rewritten.extend_from_slice(b"\r\n");
+    Ok(rewritten)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rewrite_head_replaces_reserved_headers() {
+        let head = b"GET /metrics HTTP/1.1\r\nHost: keypost\r\nx-real-ip: 10.0.0.1\r\nConnection: keep-alive\r\n\
+                     X-Keypost-Client-Cert: forged\r\n\r\n";
+        let peer: SocketAddr = "192.0.2.7:50000".parse().unwrap();
+        assert_eq!(
+            rewrite_head(head, &peer, Some("abcd")).unwrap(),
+            b"GET /metrics HTTP/1.1\r\nHost: keypost\r\nX-Real-IP: 192.0.2.7\r\nConnection: close\r\n\
+              X-Keypost-Client-Cert: abcd\r\n\r\n"
+        );
+        let rewritten = rewrite_head(head, &peer, None).unwrap();
+        assert!(!String::from_utf8(rewritten)
+            .unwrap()
+            .contains(CLIENT_CERT_HEADER));
+    }
+
+    #[test]
+    fn rewrite_head_drops_reserved_headers_behind_bare_line_feeds() {
+        let head = b"GET /metrics HTTP/1.1\r\nFoo: x\nX-Keypost-Client-Cert: forged\nX-Real-IP: 10.0.0.1\r\n\r\n";
+        let peer: SocketAddr = "192.0.2.7:50000".parse().unwrap();
+        let rewritten = String::from_utf8(rewrite_head(head, &peer, None).unwrap()).unwrap();
+        assert_eq!(
+            rewritten,
+            "GET /metrics HTTP/1.1\r\nFoo: x\r\nX-Real-IP: 192.0.2.7\r\nConnection: close\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn rewrite_head_rejects_bare_carriage_returns() {
+        let head = b"GET / HTTP/1.1\r\nFoo: x\rX-Real-IP: 10.0.0.1\r\n\r\n";
+        let peer: SocketAddr = "192.0.2.7:50000".parse().unwrap();
+        assert!(rewrite_head(head, &peer, None).is_err());
+    }
+
+    #[test]
+    fn find_head_end_includes_the_blank_line() {
+        assert_eq!(find_head_end(b"GET / HTTP/1.1\r\n\r\nbody"), Ok(Some(18)));
+        assert_eq!(find_head_end(b"GET / HTTP/1.1\r\n"), Ok(None));
+        assert!(find_head_end(b"GET / HTTP/1.1\r\nFoo\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn connection_slots_are_bounded() {
+        let slots: Vec<_> = (0..MAX_CONNECTIONS)
+            .map_while(|_| ConnectionSlot::acquire())
+            .collect();
+        assert!(ConnectionSlot::acquire().is_none());
+        drop(slots);
+        assert!(ConnectionSlot::acquire().is_some());
+    }
+}
@@ -0,0 +1,315 @@
+use diesel::pg::PgConnection;
+use diesel::result::DatabaseErrorKind;
+use sha2::{Digest, Sha256};
+use tracing::{error, info, warn};
+use zeroize::Zeroizing;
+
+use crate::api::{ApiError, WebAuthnAssertion};
+use crate::cache;
+use crate::config;
+use crate::crypto;
+use crate::models::{TotpSecret, WebAuthnCredential};
+use crate::persistence;
+use crate::user;
+
+const RECOVERY_CODE_COUNT: usize = 10;
+
+/// Returned once when TOTP is enabled; the server keeps neither in plaintext after this.
+pub struct TotpEnrollment {
+    pub provisioning_uri: String,
+    pub recovery_codes: Vec<String>,
+}
+
+pub fn enable_totp(email: &str) -> Result<TotpEnrollment, ApiError> {
+    let user = user::get_user(email).map_err(ApiError::TwoFactorError)?;
+    if let Some(TotpSecret { enabled: true, .. }) = find_totp_secret(user.id)? {
+        return Err(ApiError::TwoFactorError(
+            "TOTP is already enabled".to_string(),
+        ));
+    }
+    let secret = crypto::create_totp_secret();
+    let sealed_secret = crypto::seal(
+        crypto::server_key(),
+        crypto::KeyPurpose::TotpSecret,
+        &secret,
+    );
+    // The secret is useless without its recovery codes and vice versa, so both or neither are stored
+    let recovery_codes = persistence::transaction(|connection| {
+        persistence::store_totp_secret(connection, user.id, &base64::encode(sealed_secret))?;
+        create_recovery_codes(connection, user.id)
+    })?;
+    Ok(TotpEnrollment {
+        provisioning_uri: crypto::totp_provisioning_uri(email, &secret),
+        recovery_codes,
+    })
+}
+
+/// TOTP only becomes required at login once the user proves their authenticator app produces valid codes.
+pub fn confirm_totp(email: &str, code: &str) -> Result<(), ApiError> {
+    let user = user::get_user(email).map_err(ApiError::TwoFactorError)?;
+    let totp_secret = find_totp_secret(user.id)?
+        .ok_or_else(|| ApiError::TwoFactorError("TOTP is not enrolled".to_string()))?;
+    verify_totp_code(&totp_secret, code)?;
+    persistence::enable_totp_secret(user.id).map_err(|err| {
+        error!(error = ?err, "Error enabling totp secret");
+        ApiError::ServerError
+    })?;
+    Ok(())
+}
+
+/// Recovery codes stand in for any second factor, so they stay while WebAuthn credentials remain.
+pub fn disable_totp(email: &str) -> Result<(), ApiError> {
+    let user = user::get_user(email).map_err(ApiError::TwoFactorError)?;
+    persistence::transaction(|connection| {
+        persistence::delete_totp_secret(connection, user.id)?;
+        if !persistence::has_webauthn_credentials(connection, user.id)? {
+            persistence::delete_recovery_codes(connection, user.id)?;
+        }
+        Ok::<_, diesel::result::Error>(())
+    })?;
+    Ok(())
+}
+
+/// Challenge and parameters for navigator.credentials.create() or .get()
+#[derive(Debug, Serialize)]
+pub struct WebAuthnOptions {
+    pub challenge: String,
+    pub rp_id: String,
+    pub credential_ids: Vec<String>,
+}
+
+/// Only required if the user has a second factor, in which case any one of: a WebAuthn assertion, a current TOTP code
+/// or an unused recovery code (which is then used up).
+pub fn verify_login(
+    email: &str,
+    client_hash: &[u8],
+    code: Option<&str>,
+    assertion: Option<&WebAuthnAssertion>,
+) -> Result<(), ApiError> {
+    let user = user::get_user(email).map_err(ApiError::LoginError)?;
+    let totp_secret = find_totp_secret(user.id)?.filter(|totp_secret| totp_secret.enabled);
+    let credentials = find_webauthn_credentials(user.id)?;
+    if totp_secret.is_none() && credentials.is_empty() {
+        return Ok(());
+    }
+    if let Some(assertion) = assertion {
+        return verify_webauthn_assertion(client_hash, &credentials, assertion);
+    }
+    let code = code.ok_or(ApiError::SecondFactorRequired)?;
+    if let Some(totp_secret) = totp_secret {
+        if verify_totp_code(&totp_secret, code).is_ok() {
+            return Ok(());
+        }
+    }
+    match persistence::use_recovery_code(user.id, &hash_recovery_code(code)) {
+        Ok(true) => {
+            info!(user_id = user.id, "Recovery code used for login");
+            Ok(())
+        }
+        Ok(false) => Err(ApiError::InvalidSecondFactor),
+        Err(err) => {
+            error!(error = ?err, "Error using recovery code");
+            Err(ApiError::ServerError)
+        }
+    }
+}
+
+pub fn start_webauthn_registration(email: &str, nonce: u32) -> Result<WebAuthnOptions, ApiError> {
+    let user = user::get_user(email).map_err(ApiError::TwoFactorError)?;
+    let challenge = crypto::create_webauthn_challenge();
+    cache::insert(nonce, Zeroizing::new(challenge.clone()));
+    cache::insert_login_email(nonce.to_be_bytes().to_vec(), email);
+    Ok(WebAuthnOptions {
+        challenge: crypto::base64url_encode(&challenge),
+        rp_id: webauthn_rp_id(),
+        // i.e. excludeCredentials, so the same authenticator isn't registered twice
+        credential_ids: credential_ids(&find_webauthn_credentials(user.id)?),
+    })
+}
+
+pub fn finish_webauthn_registration(
+    email: &str,
+    nonce: u32,
+    attestation_object: &str,
+    client_data_json: &str,
+) -> Result<(), ApiError> {
+    // A challenge may only be attempted once
+    let challenge = cache::take(&nonce).ok_or(ApiError::BadRequest)?;
+    match cache::take_login_email(&nonce.to_be_bytes()) {
+        Some(challenge_email) if challenge_email == email => {}
+        _ => return Err(ApiError::BadRequest),
+    }
+    let credential = crypto::verify_webauthn_registration(
+        &webauthn_rp_id(),
+        &webauthn_origin(),
+        &challenge,
+        &crypto::base64url_decode(attestation_object)?,
+        &crypto::base64url_decode(client_data_json)?,
+    )?;
+    let user = user::get_user(email).map_err(ApiError::TwoFactorError)?;
+    match persistence::add_webauthn_credential(
+        user.id,
+        &crypto::base64url_encode(&credential.credential_id),
+        &base64::encode(&credential.public_key),
+        credential.sign_count as i64,
+    ) {
+        Ok(_) => Ok(()),
+        Err(diesel::result::Error::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => Err(
+            ApiError::WebAuthnError("Credential is already registered".to_string()),
+        ),
+        Err(err) => {
+            error!(error = ?err, "Error storing webauthn credential");
+            Err(ApiError::ServerError)
+        }
+    }
+}
+
+/// Like disable_totp, the recovery codes only go once no TOTP secret remains either.
+pub fn disable_webauthn(email: &str) -> Result<(), ApiError> {
+    let user = user::get_user(email).map_err(ApiError::TwoFactorError)?;
+    persistence::transaction(|connection| {
+        persistence::delete_webauthn_credentials(connection, user.id)?;
+        if !persistence::has_totp_secret(connection, user.id)? {
+            persistence::delete_recovery_codes(connection, user.id)?;
+        }
+        Ok(())
+    })
+    .map_err(|err: diesel::result::Error| {
+        error!(error = ?err, "Error deleting webauthn credentials");
+        ApiError::ServerError
+    })?;
+    Ok(())
+}
+
+/// Issues the assertion challenge for a login that has completed /login/finish (i.e. is identified by its client_hash).
+pub fn start_webauthn_login(email: &str, client_hash: &[u8]) -> Result<WebAuthnOptions, ApiError> {
+    let user = user::get_user(email).map_err(ApiError::LoginError)?;
+    let credentials = find_webauthn_credentials(user.id)?;
+    if credentials.is_empty() {
+        return Err(ApiError::WebAuthnError(
+            "No credentials registered".to_string(),
+        ));
+    }
+    let challenge = crypto::create_webauthn_challenge();
+    cache::insert_bin(
+        webauthn_challenge_key(client_hash),
+        Zeroizing::new(challenge.clone()),
+    );
+    Ok(WebAuthnOptions {
+        challenge: crypto::base64url_encode(&challenge),
+        rp_id: webauthn_rp_id(),
+        credential_ids: credential_ids(&credentials),
+    })
+}
+
+fn verify_webauthn_assertion(
+    client_hash: &[u8],
+    credentials: &[WebAuthnCredential],
+    assertion: &WebAuthnAssertion,
+) -> Result<(), ApiError> {
+    let challenge_key = webauthn_challenge_key(client_hash);
+    let challenge = cache::take_bin(&challenge_key).ok_or(ApiError::InvalidSecondFactor)?;
+    let credential = credentials
+        .iter()
+        .find(|credential| credential.credential_id == assertion.id)
+        .ok_or(ApiError::InvalidSecondFactor)?;
+    let public_key = base64::decode(&credential.public_key).map_err(|err| {
+        error!(error = ?err, "Error decoding webauthn public key");
+        ApiError::ServerError
+    })?;
+    let sign_count = crypto::verify_webauthn_assertion(
+        &webauthn_rp_id(),
+        &webauthn_origin(),
+        &challenge,
+        &public_key,
+        credential.sign_count as u32,
+        &crypto::base64url_decode(&assertion.a)?,
+        &crypto::base64url_decode(&assertion.d)?,
+        &crypto::base64url_decode(&assertion.s)?,
+    )
+    .map_err(|err| {
+        warn!(error = ?err, "WebAuthn assertion failed");
+        ApiError::InvalidSecondFactor
+    })?;
+    match persistence::update_webauthn_sign_count(credential.id, sign_count as i64) {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(ApiError::InvalidSecondFactor),
+        Err(err) => {
+            error!(error = ?err, "Error updating webauthn sign count");
+            Err(ApiError::ServerError)
+        }
+    }
+}
+
+fn find_webauthn_credentials(user_id: i32) -> Result<Vec<WebAuthnCredential>, ApiError> {
+    persistence::find_webauthn_credentials(user_id).map_err(|err| {
+        error!(error = ?err, "Error finding webauthn credentials");
+        ApiError::ServerError
+    })
+}
+
+fn credential_ids(credentials: &[WebAuthnCredential]) -> Vec<String> {
+    credentials
+        .iter()
+        .map(|credential| credential.credential_id.clone())
+        .collect()
+}
+
+fn webauthn_challenge_key(client_hash: &[u8]) -> Vec<u8> {
+    [b"webauthn:".as_ref(), client_hash].concat()
+}
+
+fn webauthn_rp_id() -> String {
+    config::get().webauthn.rp_id.clone()
+}
+
+fn webauthn_origin() -> String {
+    config::get().webauthn.origin.clone()
+}
+
+fn verify_totp_code(totp_secret: &TotpSecret, code: &str) -> Result<(), ApiError> {
+    let sealed_secret = base64::decode(&totp_secret.secret).map_err(|err| {
+        error!(error = ?err, "Error decoding totp secret");
+        ApiError::ServerError
+    })?;
+    let secret = crypto::open(
+        crypto::server_key(),
+        crypto::KeyPurpose::TotpSecret,
+        &sealed_secret,
+    )
+    .map_err(|_| ApiError::ServerError)?;
+    let step = crypto::verify_totp(&secret, code.trim(), totp_secret.last_used_step)
+        .ok_or(ApiError::InvalidSecondFactor)?;
+    match persistence::update_totp_last_used_step(totp_secret.user_id, step) {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(ApiError::InvalidSecondFactor),
+        Err(err) => {
+            error!(error = ?err, "Error updating totp last used step");
+            Err(ApiError::ServerError)
+        }
+    }
+}
+
+fn find_totp_secret(user_id: i32) -> Result<Option<TotpSecret>, ApiError> {
+    persistence::find_totp_secret(user_id).map_err(|err| {
+        error!(error = ?err, "Error finding totp secret");
+        ApiError::ServerError
+    })
+}
+
+fn create_recovery_codes(connection: &PgConnection, user_id: i32) -> Result<Vec<String>, ApiError> {
+    let recovery_codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+        .map(|_| crypto::create_recovery_code())
+        .collect();
+    let code_hashes: Vec<String> = recovery_codes
+        .iter()
+        .map(|code| hash_recovery_code(code))
+        .collect();
+    persistence::replace_recovery_codes(connection, user_id, &code_hashes)?;
+    Ok(recovery_codes)
+}
+
+// Recovery codes carry 80 bits of entropy, so a plain hash is enough to keep them out of the database
+fn hash_recovery_code(code: &str) -> String {
+    base64::encode(Sha256::digest(code.trim().to_uppercase().as_bytes()))
+}
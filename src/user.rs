@@ -1,5 +1,7 @@
-use crate::models::User;
+use tracing::{error, info};
 
+use crate::logging;
+use crate::models::User;
 use crate::persistence;
 
 pub fn get_user(email: &str) -> Result<User, String> {
@@ -7,12 +9,12 @@ pub fn get_user(email: &str) -> Result<User, String> {
         Ok(result) => match result {
             Some(user) => Ok(user),
             None => {
-                println!("User not found: {}", email);
+                info!(user = %logging::email(email), "User not found");
                 Err(String::from("User not found!"))
             }
         },
         Err(err) => {
-            println!("Error finding user: {:?}", err);
+            error!(error = ?err, "Error finding user");
             Err(String::from("User not found!"))
         }
     }
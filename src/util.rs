@@ -1,37 +1,65 @@
-use std::env;
-use std::fs;
-use std::io::Error;
+use std::fs::{self, DirBuilder, OpenOptions};
+use std::io::{Error, ErrorKind, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+use tracing::{debug, info};
+
+use crate::config;
 
 pub fn default_dir() -> String {
-    String::from(env!("HOME")) + "/.keypost-app"
+    config::get().data_dir.clone()
 }
 
-#[allow(dead_code)]
-pub fn get_env_var(var: &str, default: &str) -> String {
-    match env::var(var) {
-        Ok(value) => value,
-        Err(_e) => String::from(default),
+/// Creates the directory (and its parents) readable by the owner only, or checks an existing one is.
+pub fn create_directory(dir: &str) -> Result<(), Error> {
+    match fs::metadata(dir) {
+        Ok(_) => check_private_permissions(dir),
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            info!(dir, "Creating directory");
+            DirBuilder::new().recursive(true).mode(0o700).create(dir)
+        }
+        Err(err) => Err(err),
     }
 }
 
-pub fn create_directory(dir: &str) -> Result<(), Error> {
-    match fs::read_dir(dir) {
-        Ok(_) => Ok(()),
-        Err(err) => {
-            println!("DEBUG: Looked for directory {}. But error {:?}", dir, err);
-            println!("INFO: Will attempt to create directory {}", dir);
-            fs::create_dir(dir)
-        }
+/// Errors unless the file or directory is inaccessible to group and others, since it holds server secrets.
+pub fn check_private_permissions(path: &str) -> Result<(), Error> {
+    let mode = fs::metadata(path)?.permissions().mode();
+    if mode & 0o077 != 0 {
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!(
+                "{} has permissions {:o}, it must not be accessible to group or others (chmod go-rwx)",
+                path,
+                mode & 0o777
+            ),
+        ));
     }
+    Ok(())
 }
 
+/// Writes a 0600 file atomically: a crash leaves either the old file or the complete new one, never a partial write.
 pub fn write_to_file(file_path: &str, bytes: &[u8]) -> Result<(), Error> {
-    fs::write(file_path, bytes)
+    let tmp_path = format!("{}.tmp", file_path);
+    let _ = fs::remove_file(&tmp_path); // Leftover of an interrupted write
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&tmp_path)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, file_path)?;
+    // Make the rename itself durable
+    if let Some(parent) = Path::new(file_path).parent() {
+        fs::File::open(parent)?.sync_all()?;
+    }
+    Ok(())
 }
 
 pub fn read_file(file_path: &str) -> Result<Vec<u8>, Error> {
     fs::read(file_path).map_err(|err| {
-        println!("DEBUG: Could not read file {}. Error: {:?}", file_path, err);
+        debug!(file_path, error = ?err, "Could not read file");
         err
     })
 }
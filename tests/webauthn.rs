@@ -0,0 +1,241 @@
+// Runs the WebAuthn ceremonies against a software authenticator: a fixed P-256 key, "none" attestation and
+// authenticator data built by hand. The app is a binary crate, so the module is compiled in here on its own.
+#[macro_use]
+extern crate serde_derive;
+
+mod api {
+    #[derive(Debug)]
+    #[allow(dead_code)]
+    pub enum ApiError {
+        BadRequestDecode(base64::DecodeError),
+        WebAuthnError(String),
+    }
+}
+
+#[path = "../src/crypto/webauthn.rs"]
+#[allow(dead_code)]
+mod webauthn;
+
+use api::ApiError;
+use p256::ecdsa::signature::Signer;
+use p256::ecdsa::{Signature, SigningKey};
+use serde_cbor::Value;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use webauthn::*;
+
+const RP_ID: &str = "keypost.example";
+const ORIGIN: &str = "https://keypost.example";
+const CREDENTIAL_ID: &[u8] = b"software-authenticator";
+
+struct Authenticator {
+    key: SigningKey,
+    sign_count: u32,
+}
+
+impl Authenticator {
+    fn new() -> Authenticator {
+        Authenticator {
+            key: SigningKey::from_bytes(&[7u8; 32]).unwrap(),
+            sign_count: 0,
+        }
+    }
+
+    fn cose_key(&self) -> Vec<u8> {
+        let point = self.key.verifying_key().to_encoded_point(false);
+        let mut cose_key = BTreeMap::new();
+        cose_key.insert(Value::Integer(1), Value::Integer(2));
+        cose_key.insert(Value::Integer(3), Value::Integer(-7));
+        cose_key.insert(Value::Integer(-1), Value::Integer(1));
+        cose_key.insert(
+            Value::Integer(-2),
+            Value::Bytes(point.x().unwrap().to_vec()),
+        );
+        cose_key.insert(
+            Value::Integer(-3),
+            Value::Bytes(point.y().unwrap().to_vec()),
+        );
+        serde_cbor::to_vec(&Value::Map(cose_key)).unwrap()
+    }
+
+    // rp id hash | flags | sign count | (aaguid | credential id length | credential id | COSE key)
+    fn authenticator_data(&self, rp_id: &str, attested: bool) -> Vec<u8> {
+        let mut data = Sha256::digest(rp_id.as_bytes()).to_vec();
+        data.push(if attested { 0x41 } else { 0x01 });
+        data.extend_from_slice(&self.sign_count.to_be_bytes());
+        if attested {
+            data.extend_from_slice(&[0u8; 16]);
+            data.extend_from_slice(&(CREDENTIAL_ID.len() as u16).to_be_bytes());
+            data.extend_from_slice(CREDENTIAL_ID);
+            data.extend_from_slice(&self.cose_key());
+        }
+        data
+    }
+
+    fn attestation_object(&self, rp_id: &str) -> Vec<u8> {
+        let mut attestation = BTreeMap::new();
+        attestation.insert(
+            Value::Text("fmt".to_string()),
+            Value::Text("none".to_string()),
+        );
+        attestation.insert(
+            Value::Text("attStmt".to_string()),
+            Value::Map(BTreeMap::new()),
+        );
+        attestation.insert(
+            Value::Text("authData".to_string()),
+            Value::Bytes(self.authenticator_data(rp_id, true)),
+        );
+        serde_cbor::to_vec(&Value::Map(attestation)).unwrap()
+    }
+
+    // Authenticator data, client data and DER signature of an assertion
+    fn assert(
+        &mut self,
+        rp_id: &str,
+        origin: &str,
+        challenge: &[u8],
+    ) -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+        self.sign_count += 1;
+        let authenticator_data = self.authenticator_data(rp_id, false);
+        let client_data = client_data("webauthn.get", origin, challenge);
+        let signed_data = [&authenticator_data[..], &Sha256::digest(&client_data)[..]].concat();
+        let signature: Signature = self.key.sign(&signed_data);
+        (
+            authenticator_data,
+            client_data,
+            signature.to_der().as_bytes().to_vec(),
+        )
+    }
+}
+
+fn client_data(type_: &str, origin: &str, challenge: &[u8]) -> Vec<u8> {
+    serde_json::to_vec(&serde_json::json!({
+        "type": type_,
+        "challenge": base64url_encode(challenge),
+        "origin": origin,
+    }))
+    .unwrap()
+}
+
+fn register(authenticator: &Authenticator) -> VerifiedCredential {
+    let challenge = create_webauthn_challenge();
+    verify_webauthn_registration(
+        RP_ID,
+        ORIGIN,
+        &challenge,
+        &authenticator.attestation_object(RP_ID),
+        &client_data("webauthn.create", ORIGIN, &challenge),
+    )
+    .unwrap()
+}
+
+fn assert_webauthn_error(result: Result<impl std::fmt::Debug, ApiError>, expected: &str) {
+    match result {
+        Err(ApiError::WebAuthnError(msg)) => assert_eq!(msg, expected),
+        other => panic!("expected WebAuthnError({}), got {:?}", expected, other),
+    }
+}
+
+#[test]
+fn registration_and_assertion_succeed() {
+    let mut authenticator = Authenticator::new();
+    let credential = register(&authenticator);
+    assert_eq!(credential.credential_id, CREDENTIAL_ID);
+    assert_eq!(credential.sign_count, 0);
+
+    let challenge = create_webauthn_challenge();
+    let (authenticator_data, client_data, signature) =
+        authenticator.assert(RP_ID, ORIGIN, &challenge);
+    let sign_count = verify_webauthn_assertion(
+        RP_ID,
+        ORIGIN,
+        &challenge,
+        &credential.public_key,
+        credential.sign_count,
+        &authenticator_data,
+        &client_data,
+        &signature,
+    )
+    .unwrap();
+    assert_eq!(sign_count, 1);
+}
+
+#[test]
+fn assertion_with_regressed_sign_count_is_rejected() {
+    let mut authenticator = Authenticator::new();
+    let credential = register(&authenticator);
+    let challenge = create_webauthn_challenge();
+    let (authenticator_data, client_data, signature) =
+        authenticator.assert(RP_ID, ORIGIN, &challenge);
+    // The server has already seen a later assertion, e.g. from a clone of this authenticator
+    let result = verify_webauthn_assertion(
+        RP_ID,
+        ORIGIN,
+        &challenge,
+        &credential.public_key,
+        5,
+        &authenticator_data,
+        &client_data,
+        &signature,
+    );
+    assert_webauthn_error(result, "Sign count did not increase");
+}
+
+#[test]
+fn wrong_rp_id_hash_is_rejected() {
+    let mut authenticator = Authenticator::new();
+    let challenge = create_webauthn_challenge();
+    let registration = verify_webauthn_registration(
+        RP_ID,
+        ORIGIN,
+        &challenge,
+        &authenticator.attestation_object("evil.example"),
+        &client_data("webauthn.create", ORIGIN, &challenge),
+    );
+    assert_webauthn_error(registration.map(|_| ()), "RP ID mismatch");
+
+    let credential = register(&authenticator);
+    let (authenticator_data, client_data, signature) =
+        authenticator.assert("evil.example", ORIGIN, &challenge);
+    let assertion = verify_webauthn_assertion(
+        RP_ID,
+        ORIGIN,
+        &challenge,
+        &credential.public_key,
+        credential.sign_count,
+        &authenticator_data,
+        &client_data,
+        &signature,
+    );
+    assert_webauthn_error(assertion, "RP ID mismatch");
+}
+
+#[test]
+fn wrong_origin_is_rejected() {
+    let mut authenticator = Authenticator::new();
+    let challenge = create_webauthn_challenge();
+    let registration = verify_webauthn_registration(
+        RP_ID,
+        ORIGIN,
+        &challenge,
+        &authenticator.attestation_object(RP_ID),
+        &client_data("webauthn.create", "https://evil.example", &challenge),
+    );
+    assert_webauthn_error(registration.map(|_| ()), "Origin mismatch");
+
+    let credential = register(&authenticator);
+    let (authenticator_data, client_data, signature) =
+        authenticator.assert(RP_ID, "https://evil.example", &challenge);
+    let assertion = verify_webauthn_assertion(
+        RP_ID,
+        ORIGIN,
+        &challenge,
+        &credential.public_key,
+        credential.sign_count,
+        &authenticator_data,
+        &client_data,
+        &signature,
+    );
+    assert_webauthn_error(assertion, "Origin mismatch");
+}